@@ -1,4 +1,12 @@
 let g:virtualsnip#events = get(g:, 'virtualsnip#events', ['CompleteDone'])
+let g:virtualsnip#use_daemon = get(g:, 'virtualsnip#use_daemon', v:false)
+let g:virtualsnip#use_rpc = get(g:, 'virtualsnip#use_rpc', v:false)
+let g:virtualsnip#usage_file = get(g:, 'virtualsnip#usage_file', '')
+let g:virtualsnip#anchors_file = get(g:, 'virtualsnip#anchors_file', '')
+
+" Protocol spoken with the daemon and the remote plugin, see core/src/protocol.rs
+let g:virtualsnip#protocol_version = 1
+let g:virtualsnip#capabilities = ['multiLinePreviews', 'perNodeHighlights']
 
 let s:is_enabled = v:false
 
@@ -8,6 +16,7 @@ function! virtualsnip#enable() abort
     autocmd InsertEnter * call s:on_event('InsertEnter')
     autocmd CursorMovedI * call s:on_event('CursorMovedI')
     autocmd InsertLeave * call s:clear()
+    autocmd CompleteDone * call s:on_complete_done()
   augroup END
   for event in g:virtualsnip#events
     if exists('##' . event)
@@ -22,6 +31,12 @@ function! virtualsnip#disable() abort
   augroup virtualsnip
     autocmd!
   augroup END
+  if g:virtualsnip#use_daemon
+    call virtualsnip#daemon#stop()
+  endif
+  if g:virtualsnip#use_rpc
+    call virtualsnip#rpc#stop()
+  endif
   let s:is_enabled = v:false
 endfunction
 
@@ -29,14 +44,33 @@ function! virtualsnip#is_enabled() abort
   return s:is_enabled
 endfunction
 
+" Recalculates even if the buffer is not changed, e.g. after snippets are edited
+function! virtualsnip#update() abort
+  if !s:is_enabled || mode() !~# '^i'
+    return
+  endif
+  let s:last_world = {}
+  call s:on_event('Update')
+endfunction
+
 function! s:clear() abort
   let s:last_world = {}
+  if g:virtualsnip#use_rpc
+    call virtualsnip#rpc#clear(bufnr('%'))
+    return
+  endif
   call virtualsnip#view#refresh({'texts': []})
 endfunction
 
 function! s:calc(world) abort
   let sh = virtualsnip#path#core()
   let json = system(sh, json_encode(a:world))
+  if v:shell_error
+    echohl ErrorMsg
+    echomsg trim(json)
+    echohl None
+    return v:null
+  endif
   return json_decode(json)
 endfunction
 
@@ -45,10 +79,51 @@ function! s:on_event(event) abort
   if type(world) != type({}) || !s:world_is_changed(world)
     return
   endif
+  if g:virtualsnip#use_rpc
+    call virtualsnip#rpc#show(bufnr('%'), world)
+    return
+  endif
+  if g:virtualsnip#use_daemon
+    call virtualsnip#daemon#calc(bufnr('%'), world,
+          \ function('virtualsnip#view#refresh'))
+    return
+  endif
   let value = s:calc(world)
   call virtualsnip#view#refresh(value)
 endfunction
 
+" Counts completed snippets so that they win ties, see core/src/usage.rs, and
+" learns the text typed before them, see core/src/anchors.rs
+function! s:on_complete_done() abort
+  let item = v:completed_item
+  if empty(get(item, 'word', '')) || get(item, 'kind', '') !~? 'snip'
+    return
+  endif
+  let before = strpart(getline('.'), 0, col('.') - 1)
+  let learnable = before[len(before) - len(item.word):] ==# item.word
+  let before = strpart(before, 0, len(before) - len(item.word))
+  for filetype in split(&filetype, '\.')
+    if !empty(g:virtualsnip#usage_file)
+      call s:send('record', [expand(g:virtualsnip#usage_file), filetype, item.word])
+    endif
+    if !empty(g:virtualsnip#anchors_file) && learnable && before =~# '\S'
+      call s:send('learn',
+            \ [expand(g:virtualsnip#anchors_file), filetype, item.word, before])
+    endif
+  endfor
+endfunction
+
+" Runs a `record` or `learn` command of the core in whichever mode is used
+function! s:send(command, args) abort
+  if g:virtualsnip#use_rpc
+    call call('virtualsnip#rpc#' . a:command, a:args)
+  elseif g:virtualsnip#use_daemon
+    call call('virtualsnip#daemon#' . a:command, a:args)
+  else
+    call jobstart([virtualsnip#path#core(), a:command] + a:args)
+  endif
+endfunction
+
 let s:last_world = {}
 function! s:world_is_changed(world) abort
   if type(a:world) != type({})
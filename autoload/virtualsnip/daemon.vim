@@ -0,0 +1,118 @@
+let g:virtualsnip#daemon#debounce = get(g:, 'virtualsnip#daemon#debounce', 30)
+
+let s:incompatible_version = -32001
+
+let s:job = -1
+let s:next_id = 1
+let s:callbacks = {}
+let s:buffer = ''
+
+function! s:start() abort
+  if s:job > 0
+    return s:job
+  endif
+  let cmd = [virtualsnip#path#core('daemon'),
+        \ '--debounce', string(g:virtualsnip#daemon#debounce)]
+  if !empty(g:virtualsnip#snippet_dirs)
+    call add(cmd, '--watch')
+  endif
+  let s:job = jobstart(cmd, {
+        \ 'on_stdout': function('s:on_stdout'),
+        \ 'on_exit': function('s:on_exit'),
+        \ })
+  if s:job > 0
+    call s:send({'method': 'initialize', 'params': {
+          \ 'version': g:virtualsnip#protocol_version,
+          \ 'capabilities': g:virtualsnip#capabilities,
+          \ }}, v:null)
+  endif
+  return s:job
+endfunction
+
+function! virtualsnip#daemon#stop() abort
+  if s:job > 0
+    call jobstop(s:job)
+  endif
+  let s:job = -1
+  let s:callbacks = {}
+  let s:buffer = ''
+endfunction
+
+" Sends world to the daemon. Older requests for the same buffer are answered
+" with an error by the daemon, so only the newest callback gets a result.
+function! virtualsnip#daemon#calc(bufnr, world, callback) abort
+  if s:start() <= 0
+    return
+  endif
+  call s:send({'method': 'calc', 'bufnr': a:bufnr, 'params': a:world},
+        \ a:callback)
+endfunction
+
+function! virtualsnip#daemon#record(usage_file, filetype, prefix) abort
+  if s:start() <= 0
+    return
+  endif
+  call chansend(s:job, json_encode({'method': 'recordUsage', 'params': {
+        \ 'usage_file': a:usage_file,
+        \ 'filetype': a:filetype,
+        \ 'snippet': a:prefix,
+        \ }}) . "\n")
+endfunction
+
+function! virtualsnip#daemon#learn(anchors_file, filetype, prefix, before) abort
+  if s:start() <= 0
+    return
+  endif
+  call chansend(s:job, json_encode({'method': 'learnAnchor', 'params': {
+        \ 'anchors_file': a:anchors_file,
+        \ 'filetype': a:filetype,
+        \ 'snippet': a:prefix,
+        \ 'before': a:before,
+        \ }}) . "\n")
+endfunction
+
+function! s:send(msg, callback) abort
+  let id = s:next_id
+  let s:next_id += 1
+  if a:callback isnot v:null
+    let s:callbacks[id] = a:callback
+  endif
+  call chansend(s:job, json_encode(extend({'id': id}, a:msg)) . "\n")
+endfunction
+
+function! s:on_stdout(job, data, event) abort
+  " data is split on newlines; the last item is an incomplete line
+  let s:buffer .= a:data[0]
+  let lines = [s:buffer] + a:data[1:]
+  let s:buffer = remove(lines, -1)
+  for line in lines
+    if empty(line)
+      continue
+    endif
+    let msg = json_decode(line)
+    if get(msg, 'method', '') ==# 'snippetsChanged'
+      call virtualsnip#update()
+      continue
+    endif
+    if get(get(msg, 'error', {}), 'code', 0) == s:incompatible_version
+      echohl ErrorMsg
+      echomsg 'virtualsnip: ' . msg.error.message
+      echohl None
+      continue
+    endif
+    let Callback = get(s:callbacks, msg.id, v:null)
+    if Callback is v:null
+      continue
+    endif
+    call remove(s:callbacks, msg.id)
+    if has_key(msg, 'result')
+      call Callback(msg.result)
+    endif
+  endfor
+endfunction
+
+function! s:on_exit(job, code, event) abort
+  let s:job = -1
+  let s:callbacks = {}
+  let s:buffer = ''
+endfunction
@@ -6,13 +6,15 @@ function! s:sep() abort
   endif
 endfunction
 
-function! virtualsnip#path#core() abort
+" The core binary, or with a name like 'daemon' the virtualsnip-{name} one
+function! virtualsnip#path#core(...) abort
   let s = s:sep()
   let target = g:virtualsnip#root_dir . s . 'core' . s . 'target'
   let dir = target . s . 'release'
+  let name = a:0 ? 'virtualsnip-' . a:1 : 'virtualsnip'
   if has('win32')
-    return dir . s . 'virtualsnip.exe'
+    return dir . s . name . '.exe'
   else
-    return dir . s . 'virtualsnip'
+    return dir . s . name
   endif
 endfunction
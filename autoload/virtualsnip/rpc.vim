@@ -0,0 +1,63 @@
+let s:chan = 0
+
+function! s:start() abort
+  if s:chan > 0
+    return s:chan
+  endif
+  let s:chan = jobstart([virtualsnip#path#core('nvim')], {
+        \ 'rpc': v:true,
+        \ 'on_exit': function('s:on_exit'),
+        \ })
+  if s:chan <= 0
+    return s:chan
+  endif
+  try
+    call rpcrequest(s:chan, 'initialize', {
+          \ 'version': g:virtualsnip#protocol_version,
+          \ 'capabilities': g:virtualsnip#capabilities,
+          \ })
+  catch
+    echohl ErrorMsg
+    echomsg 'virtualsnip: ' . v:exception
+    echohl None
+    call virtualsnip#rpc#stop()
+  endtry
+  return s:chan
+endfunction
+
+function! virtualsnip#rpc#stop() abort
+  if s:chan > 0
+    call jobstop(s:chan)
+  endif
+  let s:chan = 0
+endfunction
+
+" The core replaces the extmarks of the buffer by itself
+function! virtualsnip#rpc#show(bufnr, world) abort
+  if s:start() <= 0
+    return
+  endif
+  call rpcrequest(s:chan, 'show', a:bufnr, virtualsnip#view#namespace(), a:world)
+endfunction
+
+function! virtualsnip#rpc#record(usage_file, filetype, prefix) abort
+  if s:start() <= 0
+    return
+  endif
+  call rpcrequest(s:chan, 'record', a:usage_file, a:filetype, a:prefix)
+endfunction
+
+function! virtualsnip#rpc#learn(anchors_file, filetype, prefix, before) abort
+  if s:start() <= 0
+    return
+  endif
+  call rpcrequest(s:chan, 'learn', a:anchors_file, a:filetype, a:prefix, a:before)
+endfunction
+
+function! virtualsnip#rpc#clear(bufnr) abort
+  call nvim_buf_clear_namespace(a:bufnr, virtualsnip#view#namespace(), 0, -1)
+endfunction
+
+function! s:on_exit(job, code, event) abort
+  let s:chan = 0
+endfunction
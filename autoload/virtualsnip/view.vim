@@ -4,7 +4,9 @@ if exists('*nvim_create_namespace')
 endif
 
 let g:virtualsnip#highlight_base = get(g:, 'virtualsnip#highlight_base', 'Comment')
+let g:virtualsnip#highlight_placeholder = get(g:, 'virtualsnip#highlight_placeholder', '')
 let g:virtualsnip#sign = get(g:, 'virtualsnip#sign', ' ')
+let g:virtualsnip#snippet_dirs = get(g:, 'virtualsnip#snippet_dirs', [])
 
 function! virtualsnip#view#get_current_buffer_info() abort
   let bufnr = bufnr('%')
@@ -14,22 +16,40 @@ function! virtualsnip#view#get_current_buffer_info() abort
   let lines = getline(start_line_no, cursor_line_no)
   let start_line = start_line_no - 1
   let cursor_line = cursor_line_no - 1
-  try
-    let sources = vsnip#source#find(bufnr('%'))
-  catch /E117/
-      return
-  endtry
+  if empty(g:virtualsnip#snippet_dirs)
+    try
+      let sources = vsnip#source#find(bufnr('%'))
+    catch /E117/
+        return
+    endtry
+  else
+    " The core reads the snippet files by itself
+    let sources = []
+  endif
   " NOTE: start_line <= cursor_line < start_line + len(lines)
   return {
-        \ 'highlight': {'base': g:virtualsnip#highlight_base},
+        \ 'version': g:virtualsnip#protocol_version,
+        \ 'highlight': {'base': g:virtualsnip#highlight_base,
+        \   'placeholder': empty(g:virtualsnip#highlight_placeholder)
+        \     ? v:null : g:virtualsnip#highlight_placeholder},
         \ 'sign': g:virtualsnip#sign,
         \ 'lines': lines,
         \ 'start_line': start_line,
         \ 'cursor_line': cursor_line,
-        \ 'sources': sources
+        \ 'sources': sources,
+        \ 'snippet_dirs': map(copy(g:virtualsnip#snippet_dirs), 'expand(v:val)'),
+        \ 'filetypes': split(&filetype, '\.'),
+        \ 'usage_file': empty(g:virtualsnip#usage_file)
+        \   ? v:null : expand(g:virtualsnip#usage_file),
+        \ 'anchors_file': empty(g:virtualsnip#anchors_file)
+        \   ? v:null : expand(g:virtualsnip#anchors_file),
         \}
 endfunction
 
+function! virtualsnip#view#namespace() abort
+  return s:virtualsnip_id
+endfunction
+
 let s:shown = {}
 " Refreshes virtualtexts if needed
 function! virtualsnip#view#refresh(value) abort
@@ -0,0 +1,141 @@
+//! Phrases learned from the user's buffers that start a snippet.
+//!
+//! When a snippet is completed after some text on the same line, like `if(`
+//! or a macro, that text is recorded for the snippet's filetype and prefix.
+//! Once it has been seen [`MIN_COUNT`] times it is tried in matching as
+//! another first text of the snippet, so code written in the user's own style
+//! still finds its snippet.
+//!
+//! The file has one tab-separated line per phrase, escaped as [`crate::tsv`]
+//! does: filetype, prefix, count and the phrase with its whitespace collapsed.
+use crate::tsv;
+use std::{collections::HashMap, io, path::Path};
+
+/// Phrases seen fewer times are not used yet
+pub const MIN_COUNT: u64 = 2;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Anchors {
+    /// (filetype, prefix, phrase)
+    counts: HashMap<(String, String, String), u64>
+}
+
+impl Anchors {
+    /// A missing file has no anchors and malformed lines are skipped
+    pub fn load(path: &Path) -> io::Result<Self> { Ok(Self::from_rows(tsv::load(path)?)) }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> { tsv::save(path, &self.rows()) }
+
+    /// Records that the snippet `prefix` was completed after `before` on its
+    /// line. Blank text teaches nothing.
+    pub fn learn(&mut self, filetype: &str, prefix: &str, before: &str) {
+        let phrase = normalize(before);
+        if phrase.is_empty() {
+            return;
+        }
+        *self
+            .counts
+            .entry((filetype.to_owned(), prefix.to_owned(), phrase))
+            .or_default() += 1;
+    }
+
+    /// Phrases seen often enough for a snippet in the filetypes of the buffer
+    pub fn get<P: AsRef<str>>(&self, filetypes: &[String], prefixes: &[P]) -> Vec<String> {
+        if self.counts.is_empty() {
+            return Vec::new();
+        }
+        let mut found: Vec<String> = self
+            .counts
+            .iter()
+            .filter(|((ft, prefix, _), count)| {
+                **count >= MIN_COUNT
+                    && filetypes.contains(ft)
+                    && prefixes.iter().any(|p| p.as_ref() == prefix)
+            })
+            .map(|((_, _, phrase), _)| phrase.clone())
+            .collect();
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    fn from_rows(rows: Vec<Vec<String>>) -> Self {
+        let counts = rows
+            .into_iter()
+            .filter_map(|row| match <[String; 4]>::try_from(row).ok()? {
+                [ft, prefix, count, phrase] => Some(((ft, prefix, phrase), count.parse().ok()?))
+            })
+            .collect();
+        Self { counts }
+    }
+
+    /// Sorted so that the file diffs well
+    fn rows(&self) -> Vec<Vec<String>> {
+        let mut keys: Vec<_> = self.counts.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|k| {
+                vec![
+                    k.0.clone(),
+                    k.1.clone(),
+                    self.counts[k].to_string(),
+                    k.2.clone(),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Anchors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&tsv::format(&self.rows()))
+    }
+}
+
+/// Adds what was typed before the snippet `prefix` to the file at `path`
+pub fn learn(path: &Path, filetype: &str, prefix: &str, before: &str) -> io::Result<()> {
+    tsv::update(path, |rows| {
+        let mut anchors = Anchors::from_rows(rows);
+        anchors.learn(filetype, prefix, before);
+        anchors.rows()
+    })
+}
+
+/// Trims and collapses whitespace
+fn normalize(s: &str) -> String { s.split_whitespace().collect::<Vec<_>>().join(" ") }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+    use std::fs;
+
+    #[test]
+    fn can_learn() {
+        let mut anchors = Anchors::default();
+        let lua = ["lua".to_owned()];
+        anchors.learn("lua", "if", "  if(");
+        assert!(anchors.get(&lua, &["if"]).is_empty());
+        anchors.learn("lua", "if", "if(\t");
+        anchors.learn("lua", "if", " ");
+        anchors.learn("c", "if", "if(");
+        anchors.learn("c", "if", "if(");
+        assert_eq!(anchors.get(&lua, &["if"]), vec!["if("]);
+        assert!(anchors.get(&lua, &["for"]).is_empty());
+        assert_eq!(
+            anchors,
+            Anchors::from_rows(tsv::parse(&anchors.to_string()))
+        );
+    }
+
+    #[test]
+    fn can_save() {
+        let tmp = TempDir::new("anchors");
+        let dir = tmp.path();
+        let path = dir.join("anchors.tsv");
+        learn(&path, "rust", "test", "#[my_macro] fn").unwrap();
+        learn(&path, "rust", "test", "#[my_macro]   fn").unwrap();
+        let s = fs::read_to_string(&path).unwrap();
+        assert_eq!(s, "rust\ttest\t2\t#[my_macro] fn\n");
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    env,
+    io::{stdin, stdout, BufReader},
+    process,
+    time::Duration
+};
+use virtualsnip::daemon;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    daemon::run(BufReader::new(stdin()), stdout(), options(&args))
+}
+
+fn options(args: &[String]) -> daemon::Options {
+    let mut opts = daemon::Options::default();
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "--coalesce" => opts.coalesce = true,
+            "--watch" => opts.watch = true,
+            "--encoding" => {
+                opts.encoding = it
+                    .next()
+                    .map(|s| s.parse().unwrap_or_else(|e: String| usage(&e)))
+                    .unwrap_or_else(|| usage("--encoding takes json, msgpack or cbor"))
+            }
+            "--debounce" => {
+                let ms = it
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| usage("--debounce takes milliseconds"));
+                opts.coalesce = true;
+                opts.debounce = Duration::from_millis(ms);
+            }
+            x => usage(&format!("unknown option {}", x))
+        }
+    }
+    opts
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("virtualsnip-daemon: {}", msg);
+    eprintln!(
+        "usage: virtualsnip-daemon [--coalesce] [--debounce <ms>] [--watch] [--encoding <encoding>]"
+    );
+    eprintln!("encodings: json, msgpack, cbor");
+    process::exit(2)
+}
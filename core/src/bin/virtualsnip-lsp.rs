@@ -0,0 +1,13 @@
+use std::{
+    io::{stdin, stdout},
+    process
+};
+use virtualsnip::lsp;
+
+fn main() {
+    let code = lsp::run(stdin().lock(), stdout().lock()).unwrap_or_else(|e| {
+        eprintln!("virtualsnip-lsp: {}", e);
+        1
+    });
+    process::exit(code)
+}
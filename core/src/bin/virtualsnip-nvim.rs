@@ -0,0 +1,12 @@
+use std::{
+    io::{stdin, stdout},
+    process
+};
+use virtualsnip::nvim;
+
+fn main() {
+    if let Err(e) = nvim::run(stdin().lock(), stdout().lock()) {
+        eprintln!("virtualsnip-nvim: {}", e);
+        process::exit(1)
+    }
+}
@@ -0,0 +1,1079 @@
+//! Converts snippet files between formats.
+//!
+//! Bodies are read by the parser of their syntax into the same [`Node`] tree
+//! the previews are made of, as written with mirrors still empty, and that
+//! tree is written in the syntax of the target format.
+//! Transformations are translated between the Python regexes of UltiSnips and
+//! the JavaScript ones of VS Code where the constructs map to each other.
+//! Whatever the target cannot express, like Python interpolation in VS Code or
+//! a transformation in SnipMate, is dropped and reported as a [`Warning`].
+use crate::{
+    parse_nodes, snipmate, source, source::Syntax, textmate, ultisnips, Node, NodePlaceholder,
+    NodeTransform, NodeVariable
+};
+use serde_json::{json, Map, Value};
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    VsCode,
+    UltiSnips,
+    SnipMate,
+    /// Can only be read
+    TextMate
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct File {
+    pub extends: Vec<String>,
+    pub snippets: Vec<Snippet>,
+    /// Of what could not be read
+    pub warnings: Vec<Warning>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snippet {
+    pub name: String,
+    pub prefix: Vec<String>,
+    pub description: String,
+    /// Filetypes; empty means every filetype
+    pub scope: Vec<String>,
+    /// UltiSnips options like `bA`
+    pub options: String,
+    pub priority: i64,
+    pub body: Vec<Node>,
+    /// How the transformations in `body` are written
+    pub syntax: Syntax
+}
+
+/// Something that could not be read or written
+#[derive(Debug, PartialEq, Eq)]
+pub struct Warning {
+    /// Name of the snippet; empty for the whole file
+    pub snippet: String,
+    pub message: String
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.snippet.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.snippet, self.message)
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vscode" => Ok(Format::VsCode),
+            "ultisnips" => Ok(Format::UltiSnips),
+            "snipmate" => Ok(Format::SnipMate),
+            "textmate" => Ok(Format::TextMate),
+            _ => Err(format!("unknown format {}", s))
+        }
+    }
+}
+
+impl Format {
+    fn syntax(self) -> Syntax {
+        match self {
+            Format::VsCode | Format::TextMate => Syntax::Lsp,
+            Format::UltiSnips => Syntax::UltiSnips,
+            Format::SnipMate => Syntax::SnipMate
+        }
+    }
+}
+
+pub fn read(s: &str, from: Format) -> Result<File, Box<dyn std::error::Error + Send + Sync>> {
+    let syntax = from.syntax();
+    let body = |lines: &[String]| parse_body(&lines.join("\n"), syntax);
+    let file = match from {
+        Format::VsCode => File {
+            extends: Vec::new(),
+            snippets: source::parse_json(s)?
+                .into_iter()
+                .map(|d| Snippet {
+                    body: body(&d.body),
+                    name: d.name,
+                    prefix: d.prefix,
+                    description: d.description,
+                    scope: d.scope,
+                    options: String::new(),
+                    priority: d.priority,
+                    syntax
+                })
+                .collect(),
+            warnings: Vec::new()
+        },
+        Format::UltiSnips => {
+            let file = ultisnips::parse(s)?;
+            File {
+                extends: file.extends,
+                snippets: file
+                    .snippets
+                    .into_iter()
+                    .map(|s| Snippet {
+                        body: body(&s.body),
+                        name: s.trigger.clone(),
+                        prefix: vec![s.trigger],
+                        description: s.description,
+                        scope: Vec::new(),
+                        options: s.options,
+                        priority: s.priority,
+                        syntax
+                    })
+                    .collect(),
+                warnings: file
+                    .warnings
+                    .iter()
+                    .map(|w| Warning {
+                        snippet: String::new(),
+                        message: w.to_string()
+                    })
+                    .collect()
+            }
+        }
+        Format::SnipMate => {
+            let file = snipmate::parse(s)?;
+            File {
+                extends: file.extends,
+                snippets: file
+                    .snippets
+                    .into_iter()
+                    .map(|s| Snippet {
+                        body: body(&s.body),
+                        name: s.trigger.clone(),
+                        prefix: vec![s.trigger],
+                        description: s.description,
+                        scope: Vec::new(),
+                        options: String::new(),
+                        priority: s.priority,
+                        syntax
+                    })
+                    .collect(),
+                warnings: Vec::new()
+            }
+        }
+        Format::TextMate => {
+            let snip = textmate::parse(s)?;
+            File {
+                extends: Vec::new(),
+                snippets: vec![Snippet {
+                    body: parse_body(&snip.content, syntax),
+                    scope: snip.filetypes(),
+                    name: if snip.name.is_empty() {
+                        snip.tab_trigger.clone()
+                    } else {
+                        snip.name
+                    },
+                    prefix: vec![snip.tab_trigger],
+                    description: String::new(),
+                    options: String::new(),
+                    priority: 0,
+                    syntax
+                }],
+                warnings: Vec::new()
+            }
+        }
+    };
+    Ok(file)
+}
+
+/// Like [`read`], scoping the snippets by the file name as [`source::read_file`] does
+pub fn read_file(path: &Path, from: Format) -> io::Result<File> {
+    let s = fs::read_to_string(path)?;
+    let mut file = read(&s, from).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if let Some(lang) = source::implied_scope(path) {
+        for snip in file.snippets.iter_mut().filter(|s| s.scope.is_empty()) {
+            snip.scope.push(lang.to_owned());
+        }
+    }
+    Ok(file)
+}
+
+/// Writes `file` in `to`; TextMate is not writable since it has one snippet per file.
+pub fn write(file: &File, to: Format) -> Result<(String, Vec<Warning>), String> {
+    let mut warnings = Vec::new();
+    let mut warn = |snippet: &str, message: String| {
+        warnings.push(Warning {
+            snippet: snippet.to_owned(),
+            message
+        })
+    };
+    let out = match to {
+        Format::VsCode => write_vscode(file, &mut warn),
+        Format::UltiSnips | Format::SnipMate => write_vim(file, to, &mut warn),
+        Format::TextMate => return Err("textmate can not be written".into())
+    };
+    Ok((out, warnings))
+}
+
+fn write_vscode(file: &File, warn: &mut dyn FnMut(&str, String)) -> String {
+    if !file.extends.is_empty() {
+        warn(
+            "",
+            format!("extends {} is dropped", file.extends.join(", "))
+        );
+    }
+    let mut map = Map::new();
+    for snip in &file.snippets {
+        if !snip.options.is_empty() {
+            warn(&snip.name, format!("options {} are dropped", snip.options));
+        }
+        if snip.priority != 0 {
+            warn(&snip.name, format!("priority {} is dropped", snip.priority));
+        }
+        let body = print(&snip.body, snip.syntax, Syntax::Lsp, &mut |m| {
+            warn(&snip.name, m)
+        });
+        let mut v = json!({
+            "prefix": match snip.prefix.as_slice() {
+                [p] => json!(p),
+                ps => json!(ps)
+            },
+            "body": body.split('\n').collect::<Vec<_>>()
+        });
+        if !snip.description.is_empty() {
+            v["description"] = json!(snip.description);
+        }
+        if !snip.scope.is_empty() {
+            v["scope"] = json!(snip.scope.join(","));
+        }
+        let mut name = snip.name.clone();
+        for i in 2.. {
+            if !map.contains_key(&name) {
+                break;
+            }
+            name = format!("{} ({})", snip.name, i);
+        }
+        map.insert(name, v);
+    }
+    let mut out = serde_json::to_string_pretty(&Value::Object(map)).expect("a map of strings");
+    out.push('\n');
+    out
+}
+
+fn write_vim(file: &File, to: Format, warn: &mut dyn FnMut(&str, String)) -> String {
+    let syntax = to.syntax();
+    let mut out = String::new();
+    if !file.extends.is_empty() {
+        out.push_str(&format!("extends {}\n\n", file.extends.join(", ")));
+    }
+    let mut scopes: Vec<_> = file.snippets.iter().map(|s| &s.scope).collect();
+    scopes.dedup();
+    if scopes.len() > 1 {
+        warn("", "scopes differ; split the output by filetype".into());
+    }
+    let mut priority = 0;
+    for snip in &file.snippets {
+        let mut warn = |m: String| warn(&snip.name, m);
+        if snip.priority != priority {
+            priority = snip.priority;
+            out.push_str(&format!("priority {}\n\n", priority));
+        }
+        if syntax == Syntax::SnipMate && !snip.options.is_empty() {
+            warn(format!("options {} are dropped", snip.options));
+        }
+        let body = print(&snip.body, snip.syntax, syntax, &mut warn);
+        for prefix in &snip.prefix {
+            if syntax == Syntax::UltiSnips {
+                let trigger = if prefix.contains(char::is_whitespace) || prefix.contains('"') {
+                    match ['!', '|', '\'', '#']
+                        .into_iter()
+                        .find(|q| !prefix.contains(*q))
+                    {
+                        Some(q) => format!("{}{}{}", q, prefix, q),
+                        None => {
+                            warn(format!("trigger {} can not be quoted", prefix));
+                            continue;
+                        }
+                    }
+                } else {
+                    prefix.clone()
+                };
+                let mut header = format!("snippet {}", trigger);
+                if !snip.description.is_empty() || !snip.options.is_empty() {
+                    header.push_str(&format!(" \"{}\"", snip.description.replace('\n', " ")));
+                }
+                if !snip.options.is_empty() {
+                    header.push_str(&format!(" {}", snip.options));
+                }
+                out.push_str(&format!("{}\n{}\nendsnippet\n\n", header, body));
+            } else {
+                if prefix.contains(char::is_whitespace) || prefix.is_empty() {
+                    warn(format!("trigger {:?} is not a word", prefix));
+                    continue;
+                }
+                out.push_str(&format!("snippet {}", prefix));
+                if !snip.description.is_empty() {
+                    out.push_str(&format!(" {}", snip.description.replace('\n', " ")));
+                }
+                out.push('\n');
+                for line in body.split('\n') {
+                    out.push_str(&format!("\t{}\n", line));
+                }
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+/// `s` as written in `syntax`, mirrors still empty
+pub fn parse_body(s: &str, syntax: Syntax) -> Vec<Node> {
+    match syntax {
+        Syntax::Lsp => parse_nodes(s),
+        Syntax::UltiSnips => ultisnips::parse_nodes(s),
+        Syntax::SnipMate => snipmate::parse_nodes(s)
+    }
+}
+
+/// VS Code variables that a Vim expression can compute
+const VIM_EXPRS: &[(&str, &str)] = &[
+    ("TM_FILENAME", "expand('%:t')"),
+    ("TM_FILENAME_BASE", "expand('%:t:r')"),
+    ("TM_DIRECTORY", "expand('%:p:h')"),
+    ("TM_FILEPATH", "expand('%:p')"),
+    ("TM_LINE_NUMBER", "line('.')"),
+    ("TM_CURRENT_LINE", "getline('.')"),
+    ("CLIPBOARD", "getreg('+')"),
+    ("CURRENT_YEAR", "strftime('%Y')"),
+    ("CURRENT_MONTH", "strftime('%m')"),
+    ("CURRENT_DATE", "strftime('%d')"),
+    ("CURRENT_HOUR", "strftime('%H')"),
+    ("CURRENT_MINUTE", "strftime('%M')"),
+    ("CURRENT_SECOND", "strftime('%S')")
+];
+
+/// `nodes` written in `from` as they are written in `syntax`
+pub fn print(nodes: &[Node], from: Syntax, syntax: Syntax, warn: &mut dyn FnMut(String)) -> String {
+    let mut out = String::new();
+    if is_javascript(from) == is_javascript(syntax) || syntax == Syntax::SnipMate {
+        print_nodes(nodes, syntax, false, &mut out, warn);
+    } else {
+        let nodes = translate_transforms(nodes, from, syntax, warn);
+        print_nodes(&nodes, syntax, false, &mut out, warn);
+    }
+    out
+}
+
+fn print_nodes(
+    nodes: &[Node],
+    syntax: Syntax,
+    in_braces: bool,
+    out: &mut String,
+    warn: &mut dyn FnMut(String)
+) {
+    for (i, node) in nodes.iter().enumerate() {
+        // `$1` followed by `2` has to be `${1}2`
+        let glued = |word: fn(char) -> bool| match nodes.get(i + 1) {
+            Some(Node::Text(t)) => t.value.chars().next().is_some_and(word),
+            _ => false
+        };
+        match node {
+            Node::Text(t) => escape(&t.value, syntax, in_braces, out),
+            Node::Placeholder(p) => print_placeholder(p, syntax, glued, out, warn),
+            Node::Variable(v) => print_variable(v, syntax, glued, out, warn),
+            Node::Interpolation(n) => print_interpolation(&n.language, &n.code, syntax, out, warn)
+        }
+    }
+}
+
+fn print_placeholder(
+    p: &NodePlaceholder,
+    syntax: Syntax,
+    glued: impl Fn(fn(char) -> bool) -> bool,
+    out: &mut String,
+    warn: &mut dyn FnMut(String)
+) {
+    let n = match p.index {
+        Some(n) => n,
+        None => return print_nodes(&p.children, syntax, false, out, warn)
+    };
+    if let Some(t) = &p.transform {
+        if syntax != Syntax::SnipMate {
+            out.push_str(&format!("${{{}{}}}", n, substitution(t)));
+            return;
+        }
+        warn(format!("transformation of ${} is dropped", n));
+        out.push_str(&format!("${{{}}}", n));
+    } else if let (Syntax::Lsp, false) = (syntax, p.choices.is_empty()) {
+        let xs: Vec<_> = p
+            .choices
+            .iter()
+            .map(|x| {
+                x.replace('\\', "\\\\")
+                    .replace(',', "\\,")
+                    .replace('|', "\\|")
+            })
+            .collect();
+        out.push_str(&format!("${{{}|{}|}}", n, xs.join(",")));
+    } else if let Some(x) = p.choices.first() {
+        warn(format!("choices of ${} are reduced to the first one", n));
+        out.push_str(&format!("${{{}:", n));
+        escape(x, syntax, true, out);
+        out.push('}');
+    } else if !p.children.is_empty() {
+        out.push_str(&format!("${{{}:", n));
+        print_nodes(&p.children, syntax, true, out, warn);
+        out.push('}');
+    } else if glued(|c| c.is_ascii_digit()) {
+        out.push_str(&format!("${{{}}}", n))
+    } else {
+        out.push_str(&format!("${}", n))
+    }
+}
+
+/// UltiSnips' `${VISUAL}` is VS Code's `TM_SELECTED_TEXT`
+fn print_variable(
+    v: &NodeVariable,
+    syntax: Syntax,
+    glued: impl Fn(fn(char) -> bool) -> bool,
+    out: &mut String,
+    warn: &mut dyn FnMut(String)
+) {
+    let selection = matches!(v.name.as_str(), "TM_SELECTED_TEXT" | "VISUAL");
+    if syntax == Syntax::Lsp || selection {
+        let name = match syntax {
+            Syntax::Lsp if selection => "TM_SELECTED_TEXT",
+            Syntax::Lsp => &v.name,
+            _ => "VISUAL"
+        };
+        match &v.transform {
+            Some(t) if syntax != Syntax::SnipMate => {
+                out.push_str(&format!("${{{}{}}}", name, substitution(t)));
+                return;
+            }
+            Some(_) => warn(format!("transformation of {} is dropped", name)),
+            None => ()
+        }
+        if !v.children.is_empty() {
+            out.push_str(&format!("${{{}:", name));
+            print_nodes(&v.children, syntax, true, out, warn);
+            out.push('}');
+        } else if syntax == Syntax::Lsp && !glued(|c| c == '_' || c.is_ascii_alphanumeric()) {
+            out.push_str(&format!("${}", name))
+        } else {
+            out.push_str(&format!("${{{}}}", name))
+        }
+        return;
+    }
+    if v.transform.is_some() {
+        warn(format!("transformation of {} is dropped", v.name));
+    }
+    match VIM_EXPRS.iter().find(|(name, _)| *name == v.name) {
+        Some((_, expr)) => print_interpolation("vim", expr, syntax, out, warn),
+        None => {
+            warn(format!("variable {} is dropped", v.name));
+            print_nodes(&v.children, syntax, false, out, warn);
+        }
+    }
+}
+
+fn substitution(t: &NodeTransform) -> String { format!("/{}/{}/{}", t.regex, t.format, t.options) }
+
+/// Whether transformations in `syntax` are JavaScript's like in VS Code rather
+/// than Python's like in UltiSnips
+fn is_javascript(syntax: Syntax) -> bool { syntax == Syntax::Lsp }
+
+/// `nodes` with their transformations written for `to`, or dropped with a
+/// warning when they do not translate
+fn translate_transforms(
+    nodes: &[Node],
+    from: Syntax,
+    to: Syntax,
+    warn: &mut dyn FnMut(String)
+) -> Vec<Node> {
+    let mut nodes = nodes.to_vec();
+    for node in &mut nodes {
+        let (transform, name, children) = match node {
+            Node::Placeholder(p) => (
+                &mut p.transform,
+                format!("${}", p.index.unwrap_or_default()),
+                &mut p.children
+            ),
+            Node::Variable(v) => (&mut v.transform, v.name.clone(), &mut v.children),
+            Node::Text(_) | Node::Interpolation(_) => continue
+        };
+        if let Some(t) = transform {
+            match translate_transform(t, from, to) {
+                Ok(x) => *t = x,
+                Err(e) => {
+                    warn(format!("transformation of {} is dropped: {}", name, e));
+                    *transform = None;
+                }
+            }
+        }
+        *children = translate_transforms(children, from, to, warn);
+    }
+    nodes
+}
+
+fn translate_transform(
+    t: &NodeTransform,
+    from: Syntax,
+    to: Syntax
+) -> Result<NodeTransform, String> {
+    let options: String = t.options.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(o) = options.chars().find(|o| !"gim".contains(*o)) {
+        return Err(format!("option {} has no equivalent", o));
+    }
+    let (regex, parts) = if is_javascript(from) {
+        (python_regex(&t.regex)?, vscode_parts(&t.format)?)
+    } else {
+        (javascript_regex(&t.regex)?, ultisnips_parts(&t.format)?)
+    };
+    let format = match to {
+        Syntax::Lsp => vscode_format(&parts),
+        _ => ultisnips_format(&parts)?
+    };
+    Ok(NodeTransform {
+        regex,
+        format,
+        options
+    })
+}
+
+/// A Python regex of UltiSnips in JavaScript
+fn javascript_regex(r: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(r.len());
+    let mut rest = r;
+    while let Some(c) = rest.chars().next() {
+        if let Some(tail) = rest.strip_prefix("(?P<") {
+            out.push_str("(?<");
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("(?P=") {
+            let (name, tail) = tail.split_once(')').ok_or("`(?P=` is not closed")?;
+            out.push_str(&format!("\\k<{}>", name));
+            rest = tail;
+        } else if rest.starts_with("(?") && rest[2..].starts_with(|c| "aiLmsux#-".contains(c)) {
+            return Err("inline flags and comments have no equivalent".into());
+        } else if c == '\\' {
+            let e = rest[1..].chars().next();
+            if let Some('A' | 'Z') = e {
+                return Err(format!("`\\{}` has no equivalent", e.unwrap_or_default()));
+            }
+            let len = 1 + e.map_or(0, char::len_utf8);
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(out)
+}
+
+/// A JavaScript regex of VS Code in Python
+fn python_regex(r: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(r.len());
+    let mut rest = r;
+    while let Some(c) = rest.chars().next() {
+        if let Some(tail) = rest
+            .strip_prefix("(?<")
+            .filter(|t| !t.starts_with(['=', '!']))
+        {
+            out.push_str("(?P<");
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("\\k<") {
+            let (name, tail) = tail.split_once('>').ok_or("`\\k<` is not closed")?;
+            out.push_str(&format!("(?P={})", name));
+            rest = tail;
+        } else if c == '\\' {
+            let e = rest[1..].chars().next();
+            if let Some('p' | 'P' | 'c') = e {
+                return Err(format!("`\\{}` has no equivalent", e.unwrap_or_default()));
+            }
+            if rest.starts_with("\\u{") {
+                return Err("`\\u{` has no equivalent".into());
+            }
+            let len = 1 + e.map_or(0, char::len_utf8);
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(out)
+}
+
+/// A piece of the format of a transformation, whatever its syntax
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Group(usize, Case),
+    /// The text if the group matched something, else the other one. Without
+    /// text the group itself.
+    Cond(usize, Option<String>, String)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Case {
+    Keep,
+    Upcase,
+    Downcase,
+    Capitalize
+}
+
+fn vscode_parts(format: &str) -> Result<Vec<Part>, String> {
+    use crate::vs_snippet::{parse_formats, unescape, Format};
+    let formats = parse_formats(format).ok_or("the format can not be read")?;
+    Ok(formats
+        .iter()
+        .map(|f| match f {
+            Format::Matched(n) => Part::Group(*n, Case::Keep),
+            Format::Upcase(n) => Part::Group(*n, Case::Upcase),
+            Format::Downcase(n) => Part::Group(*n, Case::Downcase),
+            Format::Capitalize(n) => Part::Group(*n, Case::Capitalize),
+            Format::If(n, i) => Part::Cond(*n, Some(unescape(i)), String::new()),
+            Format::IfElse(n, i, e) => Part::Cond(*n, Some(unescape(i)), unescape(e)),
+            Format::Else(n, e) => Part::Cond(*n, None, unescape(e)),
+            Format::Text(t) => Part::Text(unescape(t))
+        })
+        .collect())
+}
+
+/// The replacement of UltiSnips: `$1`, `\u` and `\l` for the next character,
+/// `\U` and `\L` up to `\E`, `(?1:then:else)` and backslash escapes
+fn ultisnips_parts(format: &str) -> Result<Vec<Part>, String> {
+    fn number(s: &str) -> Option<(usize, &str)> {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        Some((s[..end].parse().ok()?, &s[end..]))
+    }
+    fn group(s: &str) -> Option<(usize, &str)> { number(s.strip_prefix('$')?) }
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut rest = format;
+    let push = |parts: &mut Vec<Part>, text: &mut String, part| {
+        if !text.is_empty() {
+            parts.push(Part::Text(std::mem::take(text)));
+        }
+        parts.push(part);
+    };
+    while let Some(c) = rest.chars().next() {
+        if let Some((n, tail)) = group(rest) {
+            push(&mut parts, &mut text, Part::Group(n, Case::Keep));
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("\\u").or(rest.strip_prefix("\\l")) {
+            let upper = rest.starts_with("\\u");
+            if let Some((n, tail)) = group(tail) {
+                if !upper {
+                    return Err(format!("`\\l${}` has no equivalent", n));
+                }
+                push(&mut parts, &mut text, Part::Group(n, Case::Capitalize));
+                rest = tail;
+            } else if let Some(x) = tail.chars().next() {
+                match upper {
+                    true => text.extend(x.to_uppercase()),
+                    false => text.extend(x.to_lowercase())
+                }
+                rest = &tail[x.len_utf8()..];
+            } else {
+                rest = tail;
+            }
+        } else if let Some((upper, (inner, tail))) = rest
+            .strip_prefix("\\U")
+            .map(|t| (true, t))
+            .or(rest.strip_prefix("\\L").map(|t| (false, t)))
+            .and_then(|(u, t)| Some((u, t.split_once("\\E")?)))
+        {
+            match (group(inner), upper) {
+                (Some((n, "")), true) => push(&mut parts, &mut text, Part::Group(n, Case::Upcase)),
+                (Some((n, "")), false) => {
+                    push(&mut parts, &mut text, Part::Group(n, Case::Downcase))
+                }
+                _ if inner.contains(['$', '\\']) => {
+                    return Err("case changes of more than one group have no equivalent".into())
+                }
+                (_, true) => text.push_str(&inner.to_uppercase()),
+                (_, false) => text.push_str(&inner.to_lowercase())
+            }
+            rest = tail;
+        } else if let Some((n, tail)) = rest
+            .strip_prefix("(?")
+            .and_then(number)
+            .and_then(|(n, t)| Some((n, t.strip_prefix(':')?)))
+        {
+            let (then, tail) = condition_text(tail, ":)")?;
+            let (otherwise, tail) = match tail.strip_prefix(':') {
+                Some(t) => condition_text(t, ")")?,
+                None => (String::new(), tail)
+            };
+            push(&mut parts, &mut text, Part::Cond(n, Some(then), otherwise));
+            rest = &tail[1..];
+        } else if let Some(tail) = rest.strip_prefix('\\') {
+            match tail.chars().next() {
+                Some(e) => {
+                    text.push(match e {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        e => e
+                    });
+                    rest = &tail[e.len_utf8()..];
+                }
+                None => {
+                    text.push('\\');
+                    rest = tail;
+                }
+            }
+        } else {
+            text.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+/// Text of a condition up to one of `stop`, which starts what is left
+fn condition_text<'a>(s: &'a str, stop: &str) -> Result<(String, &'a str), String> {
+    let mut text = String::new();
+    let mut cs = s.char_indices();
+    while let Some((i, c)) = cs.next() {
+        match c {
+            '\\' => text.extend(cs.next().map(|(_, e)| e)),
+            '$' => return Err("groups in conditions have no equivalent".into()),
+            c if stop.contains(c) => return Ok((text, &s[i..])),
+            c => text.push(c)
+        }
+    }
+    Err("a condition is not closed".into())
+}
+
+fn vscode_format(parts: &[Part]) -> String {
+    let escaped = |s: &str, special: &str| {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            if special.contains(c) {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        out
+    };
+    let mut out = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        match part {
+            Part::Text(t) => out.push_str(&escaped(t, "\\$/")),
+            Part::Group(n, Case::Keep) => match parts.get(i + 1) {
+                Some(Part::Text(t)) if t.starts_with(|c: char| c.is_ascii_digit()) => {
+                    out.push_str(&format!("${{{}}}", n))
+                }
+                _ => out.push_str(&format!("${}", n))
+            },
+            Part::Group(n, case) => {
+                let case = match case {
+                    Case::Upcase => "upcase",
+                    Case::Downcase => "downcase",
+                    _ => "capitalize"
+                };
+                out.push_str(&format!("${{{}:/{}}}", n, case))
+            }
+            Part::Cond(n, None, e) => out.push_str(&format!("${{{}:-{}}}", n, escaped(e, "\\$/}"))),
+            Part::Cond(n, Some(t), e) if e.is_empty() => {
+                out.push_str(&format!("${{{}:+{}}}", n, escaped(t, "\\$/}")))
+            }
+            Part::Cond(n, Some(t), e) => out.push_str(&format!(
+                "${{{}:?{}:{}}}",
+                n,
+                escaped(t, "\\$/}:"),
+                escaped(e, "\\$/}")
+            ))
+        }
+    }
+    out
+}
+
+fn ultisnips_format(parts: &[Part]) -> Result<String, String> {
+    let escaped = |s: &str, special: &str| {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                c if special.contains(c) => {
+                    out.push('\\');
+                    out.push(c)
+                }
+                c => out.push(c)
+            }
+        }
+        out
+    };
+    let mut out = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        match part {
+            Part::Text(t) => out.push_str(&escaped(t, "\\$()/")),
+            Part::Group(n, Case::Keep) => match parts.get(i + 1) {
+                Some(Part::Text(t)) if t.starts_with(|c: char| c.is_ascii_digit()) => {
+                    return Err(format!("${} followed by a digit has no equivalent", n))
+                }
+                _ => out.push_str(&format!("${}", n))
+            },
+            Part::Group(n, Case::Upcase) => out.push_str(&format!("\\U${}\\E", n)),
+            Part::Group(n, Case::Downcase) => out.push_str(&format!("\\L${}\\E", n)),
+            Part::Group(n, Case::Capitalize) => out.push_str(&format!("\\u${}", n)),
+            Part::Cond(n, t, e) => {
+                let t = match t {
+                    Some(t) => escaped(t, "\\$()/:"),
+                    None => format!("${}", n)
+                };
+                out.push_str(&format!("(?{}:{}:{})", n, t, escaped(e, "\\$()/:")))
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn print_interpolation(
+    language: &str,
+    code: &str,
+    syntax: Syntax,
+    out: &mut String,
+    warn: &mut dyn FnMut(String)
+) {
+    let code_escaped = code.replace('`', "\\`");
+    match (syntax, language) {
+        (Syntax::Lsp, "vim") => match VIM_EXPRS.iter().find(|(_, e)| *e == code.trim()) {
+            Some((name, _)) => out.push_str(&format!("${}", name)),
+            None => warn(format!("vim interpolation `{}` is dropped", code))
+        },
+        (Syntax::UltiSnips, "python") => out.push_str(&format!("`!p {}`", code_escaped)),
+        (Syntax::UltiSnips, "vim") => out.push_str(&format!("`!v {}`", code_escaped)),
+        (Syntax::UltiSnips, "shell") | (Syntax::SnipMate, "vim") => {
+            out.push_str(&format!("`{}`", code_escaped))
+        }
+        _ => warn(format!("{} interpolation `{}` is dropped", language, code))
+    }
+}
+
+fn escape(s: &str, syntax: Syntax, in_braces: bool, out: &mut String) {
+    for c in s.chars() {
+        let special = match c {
+            '\\' | '$' => true,
+            '}' => in_braces,
+            '`' => syntax != Syntax::Lsp,
+            _ => false
+        };
+        if special {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NodeText;
+
+    fn convert(body: &str, from: Syntax, to: Syntax) -> (String, Vec<String>) {
+        let mut warnings = Vec::new();
+        let out = print(&parse_body(body, from), from, to, &mut |m| warnings.push(m));
+        (out, warnings)
+    }
+
+    #[test]
+    fn can_parse_body() {
+        let placeholder = |index, children| {
+            Node::Placeholder(NodePlaceholder {
+                index: Some(index),
+                children,
+                ..Default::default()
+            })
+        };
+        let text = |value: &str| {
+            Node::Text(NodeText {
+                value: value.into()
+            })
+        };
+        assert_eq!(
+            parse_body("${1:a ${2|x,y\\,z|}} $1", Syntax::Lsp),
+            vec![
+                placeholder(
+                    1,
+                    vec![
+                        text("a "),
+                        Node::Placeholder(NodePlaceholder {
+                            index: Some(2),
+                            choices: vec!["x".into(), "y,z".into()],
+                            ..Default::default()
+                        })
+                    ]
+                ),
+                text(" "),
+                placeholder(1, vec![])
+            ]
+        );
+        assert_eq!(
+            parse_body("${1:a} ${1/(.*)/\\u$1/g}", Syntax::UltiSnips),
+            vec![
+                placeholder(1, vec![text("a")]),
+                text(" "),
+                Node::Placeholder(NodePlaceholder {
+                    index: Some(1),
+                    transform: Some(NodeTransform {
+                        regex: "(.*)".into(),
+                        format: "\\u$1".into(),
+                        options: "g".into()
+                    }),
+                    ..Default::default()
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn can_convert_body() {
+        assert_eq!(
+            convert(
+                "def ${1:f}(`!p snip.rv = 1`): `!v expand('%:t')`${VISUAL}",
+                Syntax::UltiSnips,
+                Syntax::Lsp
+            ),
+            (
+                "def ${1:f}(): $TM_FILENAME$TM_SELECTED_TEXT".into(),
+                vec!["python interpolation `snip.rv = 1` is dropped".into()]
+            )
+        );
+        assert_eq!(
+            convert(
+                "${1|a,b|} ${2/x/y/} $CURRENT_YEAR\\$1",
+                Syntax::Lsp,
+                Syntax::SnipMate
+            ),
+            (
+                "${1:a} ${2} `strftime('%Y')`\\$1".into(),
+                vec![
+                    "choices of $1 are reduced to the first one".into(),
+                    "transformation of $2 is dropped".into()
+                ]
+            )
+        );
+        assert_eq!(
+            convert(
+                "${1:x\\}}$1${2}3 `date`",
+                Syntax::UltiSnips,
+                Syntax::UltiSnips
+            ),
+            ("${1:x\\}}$1${2}3 `date`".into(), vec![])
+        );
+        assert_eq!(
+            convert(
+                "${1:name} ${1/(\\w+)/\\u$1/g} ${VISUAL/a/b/}",
+                Syntax::UltiSnips,
+                Syntax::UltiSnips
+            ),
+            (
+                "${1:name} ${1/(\\w+)/\\u$1/g} ${VISUAL/a/b/}".into(),
+                vec![]
+            )
+        );
+        assert_eq!(
+            convert(
+                "${1:x} ${1/(.*)/${1:/upcase}/} ${TM_SELECTED_TEXT/a/b/g}",
+                Syntax::Lsp,
+                Syntax::UltiSnips
+            ),
+            ("${1:x} ${1/(.*)/\\U$1\\E/} ${VISUAL/a/b/g}".into(), vec![])
+        );
+        assert_eq!(
+            convert("`!p\nsnip.rv = 1`", Syntax::UltiSnips, Syntax::Lsp),
+            (
+                "".into(),
+                vec!["python interpolation `snip.rv = 1` is dropped".into()]
+            )
+        );
+    }
+
+    #[test]
+    fn can_translate_transforms() {
+        assert_eq!(
+            convert(
+                "${1:a_b} ${1/(?P<head>\\w)(\\w*)/\\u$1$2 (?2:rest:none) \\U$2\\E\\n/g}",
+                Syntax::UltiSnips,
+                Syntax::Lsp
+            ),
+            (
+                "${1:a_b} ${1/(?<head>\\w)(\\w*)/${1:/capitalize}$2 ${2:?rest:none} ${2:/upcase}\n/g}"
+                    .into(),
+                vec![]
+            )
+        );
+        assert_eq!(
+            convert(
+                "${1:x} ${1/(\\w)\\k<a>/${1:+yes} ${2:-no} ${1:?a\\:b:c} \\$(\\/)${1:/downcase}/gi}",
+                Syntax::Lsp,
+                Syntax::UltiSnips
+            ),
+            (
+                "${1:x} ${1/(\\w)(?P=a)/(?1:yes:) (?2:$2:no) (?1:a\\:b:c) \\$\\(\\/\\)\\L$1\\E/gi}"
+                    .into(),
+                vec![]
+            )
+        );
+        assert_eq!(
+            convert(
+                "${1:x} ${1/(?i)x/y/} ${1/x/\\l$1/} ${1/x/y/a} ${VISUAL/\\Ax/y/}",
+                Syntax::UltiSnips,
+                Syntax::Lsp
+            ),
+            (
+                "${1:x} $1 $1 $1 $TM_SELECTED_TEXT".into(),
+                vec![
+                    "transformation of $1 is dropped: inline flags and comments have no equivalent"
+                        .into(),
+                    "transformation of $1 is dropped: `\\l$1` has no equivalent".into(),
+                    "transformation of $1 is dropped: option a has no equivalent".into(),
+                    "transformation of VISUAL is dropped: `\\A` has no equivalent".into()
+                ]
+            )
+        );
+        assert_eq!(
+            convert(
+                "${1:x} ${1/(.)/$1${2:+2}/} ${1/\\p{L}/y/}",
+                Syntax::Lsp,
+                Syntax::UltiSnips
+            ),
+            (
+                "${1:x} ${1/(.)/$1(?2:2:)/} $1".into(),
+                vec!["transformation of $1 is dropped: `\\p` has no equivalent".into()]
+            )
+        );
+    }
+
+    #[test]
+    fn can_write() {
+        let file = read(
+            "extends c\n\nsnippet \"for loop\" \"For\" b\nfor(${1:i})\n\t$0\nendsnippet\n",
+            Format::UltiSnips
+        )
+        .unwrap();
+        let (json, warnings) = write(&file, Format::VsCode).unwrap();
+        assert_eq!(
+            warnings.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec!["extends c is dropped", "for loop: options b are dropped"]
+        );
+        let back = read(&json, Format::VsCode).unwrap();
+        assert_eq!(back.snippets[0].prefix, vec!["for loop"]);
+        assert_eq!(back.snippets[0].body, file.snippets[0].body);
+        let (ultisnips, _) = write(&back, Format::UltiSnips).unwrap();
+        assert_eq!(
+            ultisnips,
+            "snippet !for loop! \"For\"\nfor(${1:i})\n\t$0\nendsnippet\n\n"
+        );
+        let (snipmate, warnings) = write(&file, Format::SnipMate).unwrap();
+        assert_eq!(snipmate, "extends c\n\n");
+        assert_eq!(warnings[1].message, "trigger \"for loop\" is not a word");
+        assert!(write(&file, Format::TextMate).is_err());
+    }
+}
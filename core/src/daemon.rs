@@ -0,0 +1,866 @@
+//! Long-running mode speaking newline-delimited JSON over stdio.
+//!
+//! ```text
+//! -> {"id":0,"method":"initialize","params":{"version":1,"capabilities":[...]}}
+//! <- {"id":0,"result":{"version":1,"capabilities":[...]}}
+//! -> {"id":1,"method":"calc","bufnr":3,"params":{...Request}}
+//! -> {"method":"$/cancelRequest","params":{"id":1}}
+//! -> {"method":"recordUsage","params":{"usage_file":"/path/to/usage.tsv","snippet":"if","filetype":"lua"}}
+//! -> {"method":"learnAnchor","params":{"anchors_file":"/path/to/anchors.tsv","snippet":"if","filetype":"lua","before":"if("}}
+//! -> {"id":3,"method":"explain","params":{...Request}}
+//! <- {"id":1,"error":{"code":-32800,"message":"Request cancelled"}}
+//! <- {"id":2,"result":{...Response}}
+//! <- {"method":"snippetsChanged","params":{"paths":["/path/to/lua.json"]}}
+//! ```
+//!
+//! Every request is answered exactly once. With coalescing, a newer `calc` for
+//! the same `bufnr` cancels the older ones whether they are queued or running.
+//! With watching, `snippetsChanged` is sent after files in `snippet_dirs` change.
+//! `initialize` is optional; see [`crate::protocol`] for what it negotiates.
+//! Once it has agreed on MessagePack or CBOR, or from the start with
+//! [`Options::encoding`], messages are values of that encoding one after
+//! another instead of lines. Such a value that can not be decoded ends the
+//! session, since the next one can not be found.
+//! `explain` answers with the [`crate::explain`] of the cursor line. It is
+//! computed after the requests before it, as are `recordUsage` and
+//! `learnAnchor`, so that reading never waits for them. A `calc` held back by
+//! [`Options::debounce`] lets what comes after it go first, so that one buffer
+//! does not hold up the others. The daemon stops when its output is closed.
+use crate::{
+    anchors, calc_cancellable, explain,
+    protocol::{Hello, Session, INCOMPATIBLE_VERSION},
+    source::{self, Cache},
+    usage::{self, Event},
+    watch::Watcher,
+    Encoding, Request, Response
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex
+    },
+    thread,
+    time::{Duration, Instant}
+};
+
+pub const REQUEST_CANCELLED: i64 = -32800;
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Answer only the newest `calc` per buffer
+    pub coalesce: bool,
+    /// Hold each `calc` this long so that a burst for its buffer can be
+    /// coalesced
+    pub debounce: Duration,
+    /// Watch `snippet_dirs` instead of checking modification times every time
+    pub watch: bool,
+    /// Of the messages until `initialize` asks for another
+    pub encoding: Encoding
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    id: Option<u64>,
+    method: String,
+    bufnr: Option<usize>,
+    #[serde(default)]
+    params: serde_json::Value
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelParams {
+    id: u64
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordParams {
+    usage_file: PathBuf,
+    snippet: String,
+    filetype: String,
+    /// Now if not given
+    timestamp: Option<u64>
+}
+
+#[derive(Debug, Deserialize)]
+struct LearnParams {
+    anchors_file: PathBuf,
+    snippet: String,
+    filetype: String,
+    /// Text on the line before the prefix of the snippet
+    before: String
+}
+
+#[derive(Debug, Serialize)]
+struct Output<T> {
+    id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Error>
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    method: &'a str,
+    params: serde_json::Value
+}
+
+#[derive(Debug, Serialize)]
+struct Error {
+    code: i64,
+    message: String
+}
+
+struct Job {
+    id: u64,
+    req: Request,
+    session: Session,
+    cancel: Arc<AtomicBool>,
+    /// When the debounce is over
+    due: Instant
+}
+
+enum Task {
+    Calc(Box<Job>),
+    Explain(u64, Box<Request>),
+    Record(Option<u64>, RecordParams),
+    Learn(Option<u64>, LearnParams),
+    Reply(Option<u64>, Error),
+    Result(u64, serde_json::Value),
+    /// Answers after this one are in the new encoding
+    Switch(Encoding),
+    Invalidate(Vec<PathBuf>)
+}
+
+impl Task {
+    /// Only a `calc` waits, until it is due or cancelled
+    fn waits_until(&self, now: Instant) -> Option<Instant> {
+        match self {
+            Task::Calc(job) if job.due > now && !job.cancel.load(Ordering::Relaxed) => {
+                Some(job.due)
+            }
+            _ => None
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Task>,
+    /// queued or running calc requests
+    pending: HashMap<u64, (Option<usize>, Arc<AtomicBool>)>,
+    closed: bool
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar
+}
+
+/// Serves requests read from `r` until it reaches EOF or receives `exit`.
+pub fn run<R, W>(r: R, w: W, opts: Options)
+where
+    R: BufRead + Send + 'static,
+    W: Write
+{
+    let shared = Arc::new(Shared::default());
+    let reader = {
+        let shared = Arc::clone(&shared);
+        let opts = opts.clone();
+        thread::spawn(move || read_loop(r, &shared, &opts))
+    };
+    let mut watcher = if opts.watch {
+        let shared = Arc::clone(&shared);
+        Watcher::new(move |paths| push(&shared, Task::Invalidate(paths)))
+            .map_err(|e| eprintln!("{}", e))
+            .ok()
+    } else {
+        None
+    };
+    let mut cache = if watcher.is_some() {
+        Cache::watched()
+    } else {
+        Cache::default()
+    };
+    let w = &mut Writer {
+        w,
+        encoding: opts.encoding
+    };
+    while let Some(task) = next_task(&shared) {
+        let written = match task {
+            Task::Reply(id, error) => write_output(
+                w,
+                &Output::<()> {
+                    id,
+                    result: None,
+                    error: Some(error)
+                }
+            ),
+            Task::Result(id, result) => write_output(w, &result_output(id, result)),
+            Task::Switch(e) => {
+                w.encoding = e;
+                Ok(())
+            }
+            Task::Calc(job) => {
+                if let Some(watcher) = &mut watcher {
+                    for dir in &job.req.snippet_dirs {
+                        let dir = source::snippet_dir(dir);
+                        if watcher.watch(&dir).is_err() {
+                            cache.invalidate(std::slice::from_ref(&dir));
+                        }
+                    }
+                }
+                let resp = work(&job, &mut cache);
+                let written = write_output(w, &output(job.id, resp));
+                shared.state.lock().unwrap().pending.remove(&job.id);
+                written
+            }
+            Task::Explain(id, req) => {
+                match explain::explain_cached(&req, req.cursor_line, &mut cache) {
+                    Ok(e) => write_output(w, &result_output(id, e)),
+                    Err(e) => write_output(
+                        w,
+                        &error_output::<()>(Some(id), INVALID_PARAMS, e.to_string())
+                    )
+                }
+            }
+            Task::Record(id, p) => {
+                let mut event = Event::now(p.snippet, p.filetype);
+                event.timestamp = p.timestamp.unwrap_or(event.timestamp);
+                let done = usage::record(&p.usage_file, &event);
+                write_done(w, id, done)
+            }
+            Task::Learn(id, p) => {
+                let done = anchors::learn(&p.anchors_file, &p.filetype, &p.snippet, &p.before);
+                write_done(w, id, done)
+            }
+            Task::Invalidate(mut paths) => {
+                // one save usually fires several events
+                {
+                    let mut state = shared.state.lock().unwrap();
+                    while let Some(Task::Invalidate(_)) = state.queue.front() {
+                        if let Some(Task::Invalidate(ps)) = state.queue.pop_front() {
+                            paths.extend(ps);
+                        }
+                    }
+                }
+                paths.sort();
+                paths.dedup();
+                cache.invalidate(&paths);
+                write_output(
+                    w,
+                    &Notification {
+                        method: "snippetsChanged",
+                        params: serde_json::json!({ "paths": paths })
+                    }
+                )
+            }
+        };
+        // Nobody reads the answers anymore; the reader may be blocked on its
+        // input, so it is left behind
+        if let Err(e) = written {
+            eprintln!("{}", e);
+            close(&shared);
+            return;
+        }
+    }
+    let _ = reader.join();
+}
+
+fn work<'a>(job: &'a Job, cache: &mut Cache) -> Option<Response<'a>> {
+    if job.cancel.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut resp = calc_cancellable(&job.req, cache, &job.cancel)?;
+    job.session.adapt(&job.req, &mut resp);
+    Some(resp)
+}
+
+fn output(id: u64, resp: Option<Response<'_>>) -> Output<Response<'_>> {
+    match resp {
+        Some(resp) => result_output(id, resp),
+        None => error_output(Some(id), REQUEST_CANCELLED, "Request cancelled".into())
+    }
+}
+
+fn result_output<T>(id: u64, result: T) -> Output<T> {
+    Output {
+        id: Some(id),
+        result: Some(result),
+        error: None
+    }
+}
+
+fn error_output<T>(id: Option<u64>, code: i64, message: String) -> Output<T> {
+    Output {
+        id,
+        result: None,
+        error: Some(Error { code, message })
+    }
+}
+
+/// Answers a request with `null`, and a notification only if it failed
+fn write_done<W: Write>(
+    w: &mut Writer<W>,
+    id: Option<u64>,
+    done: io::Result<()>
+) -> io::Result<()> {
+    match (done, id) {
+        (Ok(()), Some(id)) => write_output(w, &result_output(id, serde_json::Value::Null)),
+        (Ok(()), None) => Ok(()),
+        (Err(e), id) => write_output(w, &error_output::<()>(id, INTERNAL_ERROR, e.to_string()))
+    }
+}
+
+/// The output and the encoding of what is written to it
+struct Writer<W> {
+    w: W,
+    encoding: Encoding
+}
+
+fn write_output<W: Write, T: Serialize>(w: &mut Writer<W>, out: &T) -> io::Result<()> {
+    if w.encoding == Encoding::Json {
+        serde_json::to_writer(&mut w.w, out)?;
+        w.w.write_all(b"\n")?;
+    } else {
+        w.encoding.write(&mut w.w, out).map_err(|e| match e {
+            crate::Error::Io(e) => e,
+            e => io::Error::other(e)
+        })?
+    }
+    w.w.flush()
+}
+
+/// The first task that does not wait; once the input is closed nothing can be
+/// coalesced anymore, so none of them waits
+fn next_task(shared: &Shared) -> Option<Task> {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let closed = state.closed;
+        let ready = state
+            .queue
+            .iter()
+            .position(|t| closed || t.waits_until(now).is_none());
+        if let Some(i) = ready {
+            return state.queue.remove(i);
+        }
+        if closed {
+            return None;
+        }
+        state = match state.queue.iter().filter_map(|t| t.waits_until(now)).min() {
+            Some(due) => shared.cond.wait_timeout(state, due - now).unwrap().0,
+            None => shared.cond.wait(state).unwrap()
+        };
+    }
+}
+
+fn read_loop<R: BufRead>(mut r: R, shared: &Shared, opts: &Options) {
+    let mut session = Session::default();
+    let mut encoding = opts.encoding;
+    while let Some(msg) = read_message(&mut r, encoding) {
+        let msg = match msg {
+            Ok(m) => m,
+            Err(e) => {
+                reply(shared, None, PARSE_ERROR, e);
+                if encoding == Encoding::Json {
+                    continue;
+                }
+                break;
+            }
+        };
+        if !dispatch(msg, shared, opts, &mut session, &mut encoding) {
+            break;
+        }
+    }
+    close(shared);
+}
+
+/// `None` at the end of the input
+fn read_message<R: BufRead>(r: &mut R, encoding: Encoding) -> Option<Result<Message, String>> {
+    if encoding != Encoding::Json {
+        return match encoding.read(r) {
+            Ok(m) => Some(Ok(m)),
+            Err(crate::Error::Io(_)) => None,
+            Err(e) => Some(Err(e.to_string()))
+        };
+    }
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match r.read_line(&mut line) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) if line.trim().is_empty() => continue,
+            Ok(_) => return Some(serde_json::from_str(&line).map_err(|e| e.to_string()))
+        }
+    }
+}
+
+fn close(shared: &Shared) {
+    shared.state.lock().unwrap().closed = true;
+    shared.cond.notify_all();
+}
+
+/// Returns false when the client asked to exit.
+fn dispatch(
+    msg: Message,
+    shared: &Shared,
+    opts: &Options,
+    session: &mut Session,
+    encoding: &mut Encoding
+) -> bool {
+    match (msg.method.as_str(), msg.id) {
+        ("exit", _) => return false,
+        ("$/cancelRequest", _) => match serde_json::from_value::<CancelParams>(msg.params) {
+            Ok(p) => {
+                let state = shared.state.lock().unwrap();
+                if let Some((_, cancel)) = state.pending.get(&p.id) {
+                    cancel.store(true, Ordering::Relaxed);
+                    // it may be waiting for its debounce
+                    shared.cond.notify_one();
+                }
+            }
+            Err(e) => reply(shared, None, INVALID_PARAMS, e.to_string())
+        },
+        ("initialize", Some(id)) => match serde_json::from_value::<Hello>(msg.params) {
+            Ok(hello) => match (hello.negotiate(), hello.encoding.as_deref().map(str::parse)) {
+                (Err(e), _) => reply(shared, Some(id), INCOMPATIBLE_VERSION, e),
+                (_, Some(Err(e))) => reply(shared, Some(id), INVALID_PARAMS, e),
+                (Ok((mut answer, s)), new) => {
+                    *session = s;
+                    answer.encoding = hello.encoding;
+                    let answer = serde_json::to_value(answer).unwrap();
+                    push(shared, Task::Result(id, answer));
+                    if let Some(Ok(new)) = new {
+                        *encoding = new;
+                        push(shared, Task::Switch(new));
+                    }
+                }
+            },
+            Err(e) => reply(shared, Some(id), INVALID_PARAMS, e.to_string())
+        },
+        ("recordUsage", id) => match serde_json::from_value::<RecordParams>(msg.params) {
+            Ok(p) => push(shared, Task::Record(id, p)),
+            Err(e) => reply(shared, id, INVALID_PARAMS, e.to_string())
+        },
+        ("learnAnchor", id) => match serde_json::from_value::<LearnParams>(msg.params) {
+            Ok(p) => push(shared, Task::Learn(id, p)),
+            Err(e) => reply(shared, id, INVALID_PARAMS, e.to_string())
+        },
+        ("calc", Some(id)) => match serde_json::from_value::<Request>(msg.params)
+            .map_err(crate::Error::from)
+            .and_then(|req| req.validate().map(|_| req))
+        {
+            Ok(req) => enqueue(shared, opts, id, msg.bufnr, req, *session),
+            Err(e) => reply(shared, Some(id), INVALID_PARAMS, e.to_string())
+        },
+        ("explain", Some(id)) => match serde_json::from_value::<Request>(msg.params)
+            .map_err(crate::Error::from)
+            .and_then(|req| req.validate().map(|_| req))
+        {
+            Ok(req) => push(shared, Task::Explain(id, Box::new(req))),
+            Err(e) => reply(shared, Some(id), INVALID_PARAMS, e.to_string())
+        },
+        (method @ ("calc" | "explain"), None) => reply(
+            shared,
+            None,
+            INVALID_REQUEST,
+            format!("{} needs an id", method)
+        ),
+        (method, id) => reply(
+            shared,
+            id,
+            METHOD_NOT_FOUND,
+            format!("Unknown method {}", method)
+        )
+    }
+    true
+}
+
+fn enqueue(
+    shared: &Shared,
+    opts: &Options,
+    id: u64,
+    bufnr: Option<usize>,
+    req: Request,
+    session: Session
+) {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut state = shared.state.lock().unwrap();
+    if opts.coalesce && bufnr.is_some() {
+        for (b, c) in state.pending.values() {
+            if *b == bufnr {
+                c.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+    state.pending.insert(id, (bufnr, Arc::clone(&cancel)));
+    state.queue.push_back(Task::Calc(Box::new(Job {
+        id,
+        req,
+        session,
+        cancel,
+        due: Instant::now() + opts.debounce
+    })));
+    shared.cond.notify_one();
+}
+
+fn reply(shared: &Shared, id: Option<u64>, code: i64, message: String) {
+    push(shared, Task::Reply(id, Error { code, message }));
+}
+
+fn push(shared: &Shared, task: Task) {
+    shared.state.lock().unwrap().queue.push_back(task);
+    shared.cond.notify_one();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+    use std::io::Cursor;
+
+    const WORLD: &str = r#"{"highlight":{"base":"Comment"},"sign":" ","lines":["if a then"],"start_line":0,"cursor_line":0,"sources":[[{"body":["if ${1:true} then","\t$0","end"]}]]}"#;
+
+    fn serve(input: String, opts: Options) -> Vec<serde_json::Value> {
+        let mut out = Vec::new();
+        run(Cursor::new(input.into_bytes()), &mut out, opts);
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    fn calc_line(id: u64, bufnr: usize) -> String {
+        format!(
+            r#"{{"id":{},"method":"calc","bufnr":{},"params":{}}}"#,
+            id, bufnr, WORLD
+        )
+    }
+
+    #[test]
+    fn answers_each_request() {
+        let input = format!("{}\n{}\n", calc_line(1, 1), calc_line(2, 1));
+        let outs = serve(input, Options::default());
+        assert_eq!(outs.len(), 2);
+        for (out, id) in outs.iter().zip(1..) {
+            assert_eq!(out["id"], id);
+            assert_eq!(out["result"]["texts"][0]["line"], 0);
+        }
+    }
+
+    #[test]
+    fn coalesces_per_buffer() {
+        let input = format!(
+            "{}\n{}\n{}\n",
+            calc_line(1, 1),
+            calc_line(2, 2),
+            calc_line(3, 1)
+        );
+        let opts = Options {
+            coalesce: true,
+            // held until the input ends
+            debounce: Duration::from_secs(3600),
+            ..Options::default()
+        };
+        let outs = serve(input, opts);
+        assert_eq!(outs.len(), 3);
+        assert_eq!(outs[0]["id"], 1);
+        assert_eq!(outs[0]["error"]["code"], REQUEST_CANCELLED);
+        assert_eq!(outs[1]["id"], 2);
+        assert!(outs[1]["result"].is_object());
+        assert_eq!(outs[2]["id"], 3);
+        assert!(outs[2]["result"].is_object());
+    }
+
+    #[test]
+    fn cancels_request() {
+        let input = format!(
+            "{}\n{}\n",
+            calc_line(1, 1),
+            r#"{"method":"$/cancelRequest","params":{"id":1}}"#
+        );
+        let opts = Options {
+            // held until the input ends
+            debounce: Duration::from_secs(3600),
+            ..Options::default()
+        };
+        let outs = serve(input, opts);
+        assert_eq!(outs.len(), 1);
+        assert_eq!(outs[0]["error"]["code"], REQUEST_CANCELLED);
+    }
+
+    #[test]
+    fn answers_when_due() {
+        use std::io::BufReader;
+        let (input, mut client) = std::io::pipe().unwrap();
+        let (output, server) = std::io::pipe().unwrap();
+        let opts = Options {
+            debounce: Duration::from_millis(10),
+            ..Options::default()
+        };
+        let daemon = thread::spawn(move || run(BufReader::new(input), server, opts));
+        let mut lines = BufReader::new(output).lines();
+        writeln!(client, "{}", calc_line(1, 1)).unwrap();
+        // the input stays open
+        let out: serde_json::Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(out["id"], 1);
+        assert!(out["result"].is_object());
+        drop(client);
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn goes_on_while_debouncing() {
+        use std::io::BufReader;
+        let (input, mut client) = std::io::pipe().unwrap();
+        let (output, server) = std::io::pipe().unwrap();
+        let opts = Options {
+            coalesce: true,
+            debounce: Duration::from_secs(3600),
+            ..Options::default()
+        };
+        let daemon = thread::spawn(move || run(BufReader::new(input), server, opts));
+        let mut lines = BufReader::new(output).lines();
+        let mut next = || -> serde_json::Value {
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap()
+        };
+        writeln!(client, "{}", calc_line(1, 1)).unwrap();
+        writeln!(client, r#"{{"id":2,"method":"foo"}}"#).unwrap();
+        assert_eq!(next()["id"], 2);
+        // cancelled ones do not wait for their debounce
+        writeln!(client, "{}", calc_line(3, 1)).unwrap();
+        let out = next();
+        assert_eq!(out["id"], 1);
+        assert_eq!(out["error"]["code"], REQUEST_CANCELLED);
+        drop(client);
+        let out = next();
+        assert_eq!(out["id"], 3);
+        assert!(out["result"].is_object());
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn notifies_snippet_changes() {
+        use std::{fs, io::BufReader};
+        let tmp = TempDir::new("daemon");
+        let dir = tmp.path();
+        fs::write(
+            dir.join("lua.json"),
+            r#"{"if": {"body": ["if ${1:true} then", "\t$0", "end"]}}"#
+        )
+        .unwrap();
+        let (input, mut client) = std::io::pipe().unwrap();
+        let (output, server) = std::io::pipe().unwrap();
+        let opts = Options {
+            watch: true,
+            ..Options::default()
+        };
+        let daemon = thread::spawn(move || run(BufReader::new(input), server, opts));
+        let mut lines = BufReader::new(output).lines();
+        let world = serde_json::json!({
+            "highlight": {"base": "Comment"}, "sign": " ", "lines": ["if a then"],
+            "start_line": 0, "cursor_line": 0, "sources": [],
+            "snippet_dirs": [dir], "filetypes": ["lua"]
+        });
+        let calc = serde_json::json!({"id": 1, "method": "calc", "params": world});
+        writeln!(client, "{}", calc).unwrap();
+        let out: serde_json::Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(out["result"]["texts"][0]["chunks"][0][0], " \nend");
+        fs::write(dir.join("lua.json"), "{}").unwrap();
+        let out: serde_json::Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(out["method"], "snippetsChanged");
+        writeln!(client, r#"{{"method":"exit"}}"#).unwrap();
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn negotiates_capabilities() {
+        let hello = |id, version, caps: &str| {
+            format!(
+                r#"{{"id":{},"method":"initialize","params":{{"version":{},"capabilities":{}}}}}"#,
+                id, version, caps
+            )
+        };
+        let input = format!(
+            "{}\n{}\n{}\n",
+            hello(1, 2, "[]"),
+            hello(2, 1, r#"["diffResponses"]"#),
+            calc_line(3, 1)
+        );
+        let outs = serve(input, Options::default());
+        assert_eq!(outs[0]["error"]["code"], INCOMPATIBLE_VERSION);
+        assert_eq!(
+            outs[1]["result"],
+            serde_json::json!({"version": 1, "capabilities": []})
+        );
+        // without multi-line previews the lines are joined
+        assert_eq!(outs[2]["result"]["texts"][0]["chunks"][0][0], "  end");
+    }
+
+    #[test]
+    fn merges_chunks_unless_negotiated() {
+        let world = serde_json::json!({
+            "highlight": {"base": "Comment", "placeholder": "Special"}, "sign": "", "lines": ["for "],
+            "start_line": 0, "cursor_line": 0, "sources": [[{"body": ["for ${1:i} in ${2:xs} do"]}]]
+        });
+        let calc = serde_json::json!({"id": 2, "method": "calc", "params": world});
+        let chunks = |caps: &str| {
+            let hello = format!(
+                r#"{{"id":1,"method":"initialize","params":{{"version":1,"capabilities":{}}}}}"#,
+                caps
+            );
+            let outs = serve(format!("{}\n{}\n", hello, calc), Options::default());
+            outs[1]["result"]["texts"][0]["chunks"].clone()
+        };
+        assert_eq!(
+            chunks(r#"["perNodeHighlights"]"#),
+            serde_json::json!([
+                ["i", "Special"],
+                [" in ", "Comment"],
+                ["xs", "Special"],
+                [" do", "Comment"]
+            ])
+        );
+        assert_eq!(
+            chunks(r#"["multiLinePreviews"]"#),
+            serde_json::json!([["i in xs do", "Comment"]])
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_encodings() {
+        let hello = r#"{"id":1,"method":"initialize","params":{"version":1,"encoding":"yaml"}}"#;
+        let outs = serve(
+            format!("{}\n{}\n", hello, calc_line(2, 1)),
+            Options::default()
+        );
+        assert_eq!(outs[0]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(outs[1]["id"], 2);
+    }
+
+    #[test]
+    #[cfg(feature = "msgpack")]
+    fn switches_encoding() {
+        let hello = r#"{"id":1,"method":"initialize","params":{"version":1,"capabilities":["multiLinePreviews"],"encoding":"msgpack"}}"#;
+        let calc: serde_json::Value = serde_json::from_str(&calc_line(2, 1)).unwrap();
+        let mut input = format!("{}\n", hello).into_bytes();
+        input.extend(rmp_serde::to_vec_named(&calc).unwrap());
+        let mut out = Vec::new();
+        run(Cursor::new(input), &mut out, Options::default());
+        let newline = out.iter().position(|&b| b == b'\n').unwrap();
+        let answer: serde_json::Value = serde_json::from_slice(&out[..newline]).unwrap();
+        assert_eq!(answer["result"]["encoding"], "msgpack");
+        let resp: serde_json::Value = rmp_serde::from_slice(&out[newline + 1..]).unwrap();
+        assert_eq!(resp["id"], 2);
+        assert_eq!(resp["result"]["texts"][0]["chunks"][0][0], " \nend");
+    }
+
+    #[test]
+    #[cfg(feature = "cbor")]
+    fn speaks_cbor() {
+        let calc: serde_json::Value = serde_json::from_str(&calc_line(1, 1)).unwrap();
+        let mut input = Vec::new();
+        ciborium::into_writer(&calc, &mut input).unwrap();
+        // a break outside of anything, after which nothing can be read
+        input.extend(b"\xff");
+        ciborium::into_writer(&calc, &mut input).unwrap();
+        let opts = Options {
+            encoding: Encoding::Cbor,
+            ..Options::default()
+        };
+        let mut out = Vec::new();
+        run(Cursor::new(input), &mut out, opts);
+        let mut r = &out[..];
+        let resp: serde_json::Value = ciborium::from_reader(&mut r).unwrap();
+        assert_eq!(resp["result"]["texts"][0]["line"], 0);
+        let resp: serde_json::Value = ciborium::from_reader(&mut r).unwrap();
+        assert_eq!(resp["error"]["code"], PARSE_ERROR);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn records_usage_and_anchors() {
+        let tmp = TempDir::new("record");
+        let dir = tmp.path();
+        let file = dir.join("usage.tsv");
+        let record = serde_json::json!({
+            "id": 1, "method": "recordUsage",
+            "params": {"usage_file": file, "snippet": "if", "filetype": "lua", "timestamp": 5}
+        });
+        let learn = serde_json::json!({
+            "method": "learnAnchor",
+            "params": {"anchors_file": dir.join("anchors.tsv"), "snippet": "if", "filetype": "lua", "before": "if("}
+        });
+        let outs = serve(format!("{}\n{}\n", record, learn), Options::default());
+        let saved = std::fs::read_to_string(&file).unwrap();
+        let learned = std::fs::read_to_string(dir.join("anchors.tsv")).unwrap();
+        assert_eq!(outs.len(), 1);
+        assert_eq!(outs[0]["id"], 1);
+        assert!(outs[0]["result"].is_null() && outs[0].get("error").is_none());
+        assert_eq!(saved, "lua\tif\t1\t5\n");
+        assert_eq!(learned, "lua\tif\t1\tif(\n");
+    }
+
+    #[test]
+    fn explains_the_cursor_line() {
+        let input = format!(r#"{{"id":1,"method":"explain","params":{}}}"#, WORLD);
+        let outs = serve(input + "\n", Options::default());
+        let candidate = &outs[0]["result"]["candidates"][0];
+        assert_eq!(outs[0]["result"]["text"], "if a then");
+        assert_eq!(candidate["chosen"], true);
+        assert_eq!(
+            candidate["cut"],
+            serde_json::json!({"type": "after", "nodes": 3})
+        );
+        assert_eq!(candidate["tokens"][1]["at"], 5);
+    }
+
+    #[test]
+    fn unknown_method() {
+        let outs = serve(
+            format!(
+                "{{\"id\":1,\"method\":\"foo\"}}\nnot json\n{{\"method\":\"calc\",\"params\":{}}}\n",
+                WORLD
+            ),
+            Options::default()
+        );
+        assert_eq!(outs[0]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(outs[1]["error"]["code"], PARSE_ERROR);
+        assert_eq!(outs[1]["id"], serde_json::Value::Null);
+        assert_eq!(outs[2]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(outs[2]["error"]["message"], "calc needs an id");
+    }
+
+    #[test]
+    fn stops_when_output_is_closed() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+        let (input, mut client) = std::io::pipe().unwrap();
+        let (done, stopped) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            run(std::io::BufReader::new(input), Closed, Options::default());
+            done.send(()).unwrap();
+        });
+        writeln!(client, "{}", calc_line(1, 1)).unwrap();
+        // the input stays open
+        assert!(stopped.recv_timeout(Duration::from_secs(10)).is_ok());
+    }
+}
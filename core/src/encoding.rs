@@ -0,0 +1,137 @@
+//! Wire formats of [`Request`] and [`Response`].
+//!
+//! JSON is what Vim script speaks natively. MessagePack and CBOR are smaller
+//! and faster to parse when requests carry many snippet bodies.
+use crate::{Error, Request, Response};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor
+}
+
+impl Encoding {
+    pub fn read_request<R: Read>(self, r: R) -> Result<Request, Error> {
+        let req: Request = self.read(r)?;
+        req.validate()?;
+        Ok(req)
+    }
+
+    pub fn write_response<W: Write>(self, w: W, resp: &Response<'_>) -> Result<(), Error> {
+        self.write(w, resp)
+    }
+
+    /// One value; MessagePack and CBOR read no further than its end, while
+    /// JSON reads `r` to its end
+    pub(crate) fn read<T: DeserializeOwned, R: Read>(self, r: R) -> Result<T, Error> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_reader(r)?),
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => rmp_serde::from_read(r).map_err(|e| match e {
+                rmp_serde::decode::Error::InvalidMarkerRead(e)
+                | rmp_serde::decode::Error::InvalidDataRead(e) => Error::Io(e),
+                e => Error::Encoding(e.into())
+            }),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => ciborium::from_reader(r).map_err(|e| match e {
+                ciborium::de::Error::Io(e) => Error::Io(e),
+                e => Error::Encoding(e.into())
+            })
+        }
+    }
+
+    pub(crate) fn write<T: Serialize, W: Write>(self, w: W, value: &T) -> Result<(), Error> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_writer(w, value)?),
+            // Structs as maps so that the keys are the same as in JSON
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => {
+                let mut w = w;
+                rmp_serde::encode::write_named(&mut w, value).map_err(|e| Error::Encoding(e.into()))
+            }
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => ciborium::into_writer(value, w).map_err(|e| match e {
+                ciborium::ser::Error::Io(e) => Error::Io(e),
+                e => Error::Encoding(e.into())
+            })
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Ok(Encoding::MessagePack),
+            #[cfg(feature = "cbor")]
+            "cbor" => Ok(Encoding::Cbor),
+            _ => Err(format!("unknown encoding {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Json => "json",
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => "msgpack",
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => "cbor"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calc;
+
+    fn request() -> serde_json::Value {
+        serde_json::json!({
+            "highlight": {"base": "Comment"}, "sign": " ", "lines": ["if a then"],
+            "start_line": 0, "cursor_line": 0,
+            "sources": [[{"body": ["if ${1:true} then", "\t$0", "end"]}]]
+        })
+    }
+
+    #[test]
+    fn can_round_trip() {
+        let encodings = [
+            Encoding::Json,
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack,
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor
+        ];
+        for encoding in encodings {
+            assert_eq!(encoding.to_string().parse(), Ok(encoding));
+            let mut buf = Vec::new();
+            encoding.write(&mut buf, &request()).unwrap();
+            let req = encoding.read_request(&buf[..]).unwrap();
+            let mut out = Vec::new();
+            encoding.write_response(&mut out, &calc(&req)).unwrap();
+            let resp: serde_json::Value = encoding.read(&out[..]).unwrap();
+            assert_eq!(
+                resp,
+                serde_json::json!({"texts": [{"line": 0, "chunks": [[" \nend", "Comment"]]}]}),
+                "{}",
+                encoding
+            );
+            assert!(encoding.read_request(&b"\x01"[..]).is_err());
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Why a line gets the preview it gets.
+//!
+//! [`explain`] runs the matching of [`crate::calc`] for one line and keeps what
+//! it otherwise throws away: the words of every snippet, where in the line
+//! they were found, the counts behind the score and where the preview was cut.
+//!
+//! A snippet is matched from its first text on. The words of that text and the
+//! trimmed texts after it are looked for in the line in order, stopping at the
+//! first one missing. The score is the share of words found; the snippet with
+//! the highest score is previewed, from after its last matched text.
+use crate::{
+    collect_snippets, cut, find_hinted, first_text, hinter, score, source, text, trace_from, Error,
+    Node, Request
+};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::sync::atomic::AtomicBool;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Explanation {
+    /// Buffer line number
+    pub line: usize,
+    pub text: String,
+    /// Every snippet in the order of the request
+    pub candidates: Vec<Candidate>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Candidate {
+    pub prefix: Vec<String>,
+    /// What the snippet shows from its first text on
+    pub body: String,
+    /// Learned phrase that was looked for instead of the first text
+    pub anchor: Option<String>,
+    pub tokens: Vec<Token>,
+    /// Words looked for
+    pub num: usize,
+    /// Words found that count
+    pub hit: usize,
+    /// Words of the first text
+    pub num_first: usize,
+    /// `hit / num`; `None` if nothing was found and the snippet was never ranked
+    pub score: Option<f64>,
+    /// From the usage file, deciding between equal scores
+    pub boost: f64,
+    /// Whether this is the preview of the line
+    pub chosen: bool,
+    /// Where the preview starts; `None` if the snippet was never ranked
+    pub cut: Option<Cut>,
+    pub preview: String
+}
+
+/// A word of a snippet
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Token {
+    pub word: String,
+    /// Of the first text, or of the anchor in its place
+    pub first: bool,
+    /// Character offset in the line where it was found
+    pub at: Option<usize>,
+    /// Found and counted; one letter of the first text is too likely to count
+    pub counted: bool
+}
+
+/// Why a preview starts where it does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize),
+    serde(tag = "type", rename_all = "camelCase")
+)]
+pub enum Cut {
+    /// Not all words of the first text were found, so the whole snippet is shown
+    FirstText,
+    /// The first text, the texts matched after it and what lies between them
+    /// are left out, this many nodes in all
+    After { nodes: usize },
+    /// Nothing but placeholders would be left, so nothing is shown
+    Nothing
+}
+
+/// Matches every snippet of `req` against `line`, a buffer line number from
+/// `start_line` to `cursor_line`.
+pub fn explain(req: &Request, line: usize) -> Result<Explanation, Error> {
+    explain_cached(req, line, &mut source::Cache::default())
+}
+
+/// Same as [`explain`] but reuses the files in `cache`
+pub fn explain_cached(
+    req: &Request,
+    line: usize,
+    cache: &mut source::Cache
+) -> Result<Explanation, Error> {
+    let current = line
+        .checked_sub(req.start_line)
+        .filter(|_| line <= req.cursor_line)
+        .and_then(|i| req.lines.get(i))
+        .ok_or_else(|| {
+            Error::InvalidRequest(format!(
+                "line {} is not one of the lines {} to {}",
+                line, req.start_line, req.cursor_line
+            ))
+        })?;
+    let never = AtomicBool::new(false);
+    let hint = hinter(req, cache);
+    let snippets = collect_snippets(req, cache, &never, |p| (hint(p), p.to_vec()));
+    let mut best: Option<((f64, f64), usize)> = None;
+    let mut candidates: Vec<Candidate> = snippets
+        .iter()
+        .enumerate()
+        .map(|(j, (nodes, (hint, prefix)))| {
+            let nodes = first_text(nodes);
+            let (found, anchor) = find_hinted(current, nodes, Some(hint));
+            let mut tokens = Vec::new();
+            if let (Some(Node::Text(first)), false) = (nodes.first(), current.is_empty()) {
+                let first = anchor.unwrap_or(&first.value);
+                trace_from(current, first, nodes, |s| {
+                    tokens.push(Token {
+                        word: s.word.to_owned(),
+                        first: s.first,
+                        at: s.at,
+                        counted: s.counted
+                    })
+                });
+            }
+            let score = score(&found, Some(hint));
+            if let Some(s) = score {
+                if best.is_none_or(|(b, _)| b < s) {
+                    best = Some((s, j));
+                }
+            }
+            let (tail, cut) = match score {
+                Some(_) => {
+                    let (tail, cut) = cut(nodes, &found);
+                    (tail, Some(cut))
+                }
+                None => (&[][..], None)
+            };
+            Candidate {
+                prefix: prefix.clone(),
+                body: nodes.iter().map(text).collect(),
+                anchor: anchor.map(str::to_owned),
+                tokens,
+                num: found.num,
+                hit: found.hit,
+                num_first: found.num_first,
+                score: score.map(|s| s.0),
+                boost: hint.boost,
+                chosen: false,
+                cut,
+                preview: tail.iter().map(text).collect()
+            }
+        })
+        .collect();
+    if let Some((_, j)) = best {
+        candidates[j].chosen = true;
+    }
+    Ok(Explanation {
+        line,
+        text: current.clone(),
+        candidates
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(lines: &[&str]) -> Request {
+        Request::builder()
+            .lines(lines.iter().copied())
+            .snippet(vec![
+                "if ${1:cond} then".into(),
+                "\t$0".into(),
+                "end".into(),
+            ])
+            .snippet(vec!["for ${1:i} = 1, 10 do".into(), "end".into()])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn can_explain() {
+        let e = explain(&request(&["if a then"]), 0).unwrap();
+        assert_eq!(e.text, "if a then");
+        let [c, d] = &e.candidates[..] else {
+            panic!("{:?}", e.candidates)
+        };
+        assert!(c.chosen);
+        assert_eq!((c.num, c.hit, c.num_first), (3, 2, 1));
+        assert_eq!(c.score, Some(2. / 3.));
+        assert_eq!(
+            c.tokens,
+            vec![
+                Token {
+                    word: "if".into(),
+                    first: true,
+                    at: Some(0),
+                    counted: true
+                },
+                Token {
+                    word: "then".into(),
+                    first: false,
+                    at: Some(5),
+                    counted: true
+                },
+                Token {
+                    word: "end".into(),
+                    first: false,
+                    at: None,
+                    counted: false
+                },
+            ]
+        );
+        assert_eq!(c.cut, Some(Cut::After { nodes: 3 }));
+        assert_eq!(c.preview, "\nend");
+        assert!(!d.chosen);
+        assert_eq!(d.score, None);
+        assert_eq!(d.cut, None);
+        assert_eq!(d.tokens[0].at, None);
+    }
+
+    #[test]
+    fn can_explain_cuts() {
+        let req = |line: &str| {
+            Request::builder()
+                .lines([line])
+                .snippet(vec!["local function ${1:f}()".into(), "end".into()])
+                .snippet(vec!["print(${1:x})".into()])
+                .build()
+                .unwrap()
+        };
+        let e = explain(&req("local"), 0).unwrap();
+        let c = &e.candidates[0];
+        assert!(c.chosen);
+        assert_eq!(c.cut, Some(Cut::FirstText));
+        assert_eq!(c.preview, "local function f()\nend");
+        let e = explain(&req("print(a)"), 0).unwrap();
+        let c = &e.candidates[1];
+        assert!(c.chosen);
+        assert_eq!(c.cut, Some(Cut::Nothing));
+        assert_eq!(c.preview, "");
+        let e = explain(&req("x"), 0).unwrap();
+        assert!(e.candidates.iter().all(|c| !c.chosen));
+        assert!(explain(&req("x"), 1).is_err());
+    }
+}
@@ -1,152 +1,801 @@
+//! Previews of snippets that fit the lines being typed.
+//!
+//! ```
+//! use virtualsnip::{Matcher, Request};
+//!
+//! let matcher = Matcher::from_bodies([vec!["if ${1:cond} then".to_owned(), "\t$0".into(), "end".into()]]);
+//! let previews = matcher.previews(0, &["if x then".to_owned()]);
+//! assert_eq!(previews[0].text(), "\nend");
+//!
+//! let req = Request::builder()
+//!     .lines(["if x then"])
+//!     .snippet(vec!["if ${1:cond} then".into(), "\t$0".into(), "end".into()])
+//!     .build()
+//!     .unwrap();
+//! let resp = virtualsnip::calc(&req);
+//! assert_eq!(resp.texts()[0].line(), 0);
+//! ```
+pub mod anchors;
+#[cfg(feature = "serde")]
+pub mod convert;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(feature = "serde")]
+pub mod encoding;
+pub mod explain;
+pub mod lint;
+#[cfg(feature = "daemon")]
+pub mod lsp;
+#[cfg(feature = "nvim")]
+pub mod nvim;
+mod par;
+pub mod protocol;
+pub mod snipmate;
+pub mod source;
+pub mod suggest;
+pub mod textmate;
+mod tsv;
+pub mod ultisnips;
+pub mod usage;
 pub mod vs_snippet;
-use rayon::prelude::*;
+#[cfg(feature = "daemon")]
+pub mod watch;
+#[cfg(feature = "serde")]
+pub use encoding::Encoding;
+use explain::Cut;
+use par::*;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use std::io::{Read, Write};
 use std::{
     borrow::Cow,
-    io::{Read, Write},
-    ops::Deref
+    collections::HashMap,
+    fmt, io,
+    ops::Deref,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering}
 };
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 pub struct Request {
+    /// [`protocol::VERSION`] the client was written for, if it says so
+    #[cfg_attr(feature = "serde", serde(default))]
+    version: Option<u32>,
     highlight: Highlight,
     sign: String,
     lines: Vec<String>,
     start_line: usize,
     cursor_line: usize,
     // snippets: Vec<Vec<Node>>
-    sources: Vec<Vec<Snippet>>
+    sources: Vec<Vec<Snippet>>,
+    /// Directories of VS Code snippet files read by the core itself
+    #[cfg_attr(feature = "serde", serde(default))]
+    snippet_dirs: Vec<PathBuf>,
+    /// Selects snippets from `snippet_dirs`
+    #[cfg_attr(feature = "serde", serde(default))]
+    filetypes: Vec<String>,
+    /// [`usage`] file breaking ties between snippets
+    #[cfg_attr(feature = "serde", serde(default))]
+    usage_file: Option<PathBuf>,
+    /// [`anchors`] file with other ways the snippets start
+    #[cfg_attr(feature = "serde", serde(default))]
+    anchors_file: Option<PathBuf>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 pub struct Snippet {
+    /// Identifies the snippet in the usage file
+    #[cfg_attr(feature = "serde", serde(default))]
+    prefix: Vec<String>,
     body: Vec<String>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
 struct Highlight {
-    base: String
+    base: String,
+    /// Group of the placeholders in previews split by node; one chunk per
+    /// preview without it
+    #[cfg_attr(feature = "serde", serde(default))]
+    placeholder: Option<String>
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
-#[serde(tag = "type")]
-#[serde(rename_all = "camelCase")]
+/// Builds a [`Request`] without going through JSON
+#[derive(Debug, Default)]
+pub struct RequestBuilder {
+    highlight: Option<String>,
+    placeholder_highlight: Option<String>,
+    sign: String,
+    lines: Vec<String>,
+    start_line: usize,
+    cursor_line: Option<usize>,
+    snippets: Vec<Snippet>,
+    snippet_dirs: Vec<PathBuf>,
+    filetypes: Vec<String>,
+    usage_file: Option<PathBuf>,
+    anchors_file: Option<PathBuf>
+}
+
+impl Request {
+    pub fn builder() -> RequestBuilder { RequestBuilder::default() }
+
+    pub fn lines(&self) -> &[String] { &self.lines }
+
+    /// Buffer line number of the first of [`Request::lines`]
+    pub fn start_line(&self) -> usize { self.start_line }
+
+    pub fn cursor_line(&self) -> usize { self.cursor_line }
+
+    /// Lines past the cursor are never previewed
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(v) = self.version {
+            protocol::check_version(v).map_err(Error::InvalidRequest)?;
+        }
+        if self.cursor_line < self.start_line {
+            return Err(Error::InvalidRequest(format!(
+                "cursor_line {} is before start_line {}",
+                self.cursor_line, self.start_line
+            )));
+        }
+        if self.cursor_line - self.start_line >= self.lines.len() {
+            return Err(Error::InvalidRequest(format!(
+                "cursor_line {} is past the {} lines from start_line {}",
+                self.cursor_line,
+                self.lines.len(),
+                self.start_line
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl RequestBuilder {
+    /// Lines of the buffer up to the cursor line, possibly more
+    pub fn lines<I, S>(mut self, lines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>
+    {
+        self.lines = lines.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn start_line(mut self, line: usize) -> Self {
+        self.start_line = line;
+        self
+    }
+
+    /// Defaults to the last of the lines
+    pub fn cursor_line(mut self, line: usize) -> Self {
+        self.cursor_line = Some(line);
+        self
+    }
+
+    /// Prepended to every preview
+    pub fn sign(mut self, sign: impl Into<String>) -> Self {
+        self.sign = sign.into();
+        self
+    }
+
+    /// Highlight group of the previews, `Comment` by default
+    pub fn highlight(mut self, group: impl Into<String>) -> Self {
+        self.highlight = Some(group.into());
+        self
+    }
+
+    /// Highlight group of the placeholders, which splits every preview into
+    /// one chunk per node
+    pub fn placeholder_highlight(mut self, group: impl Into<String>) -> Self {
+        self.placeholder_highlight = Some(group.into());
+        self
+    }
+
+    /// Adds a snippet body in VS Code's syntax
+    pub fn snippet(mut self, body: Vec<String>) -> Self {
+        self.snippets.push(Snippet {
+            prefix: Vec::new(),
+            body
+        });
+        self
+    }
+
+    /// Adds a directory of snippet files, see [`source::find_files`]
+    pub fn snippet_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.snippet_dirs.push(dir.into());
+        self
+    }
+
+    /// Adds a filetype selecting snippets from the snippet directories
+    pub fn filetype(mut self, filetype: impl Into<String>) -> Self {
+        self.filetypes.push(filetype.into());
+        self
+    }
+
+    /// Prefers the snippets used most among equally good matches, see [`usage`]
+    pub fn usage_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.usage_file = Some(path.into());
+        self
+    }
+
+    /// Also matches snippets by the phrases learned in the file, see [`anchors`]
+    pub fn anchors_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.anchors_file = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Request, Error> {
+        let req = Request {
+            version: None,
+            highlight: Highlight {
+                base: self.highlight.unwrap_or_else(|| "Comment".into()),
+                placeholder: self.placeholder_highlight
+            },
+            sign: self.sign,
+            cursor_line: self
+                .cursor_line
+                .unwrap_or_else(|| self.start_line + self.lines.len().saturating_sub(1)),
+            lines: self.lines,
+            start_line: self.start_line,
+            sources: vec![self.snippets],
+            snippet_dirs: self.snippet_dirs,
+            filetypes: self.filetypes,
+            usage_file: self.usage_file,
+            anchors_file: self.anchors_file
+        };
+        req.validate()?;
+        Ok(req)
+    }
+}
+
+/// Errors of the library API. New variants may be added in minor versions.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The request is not JSON of the expected shape
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
+    /// MessagePack or CBOR that is not a request, or a response that can not
+    /// be encoded
+    Encoding(Box<dyn std::error::Error + Send + Sync>),
+    Io(io::Error),
+    /// The request is well-formed but its lines do not fit
+    InvalidRequest(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "serde")]
+            Error::Json(e) => write!(f, "invalid request: {}", e),
+            Error::Encoding(e) => write!(f, "invalid encoding: {}", e),
+            Error::Io(e) => e.fmt(f),
+            Error::InvalidRequest(m) => write!(f, "invalid request: {}", m)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "serde")]
+            Error::Json(e) => Some(e),
+            Error::Encoding(e) => Some(e.as_ref()),
+            Error::Io(e) => Some(e),
+            Error::InvalidRequest(_) => None
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Json(e)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self { Error::Io(e) }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(tag = "type", rename_all = "camelCase")
+)]
 pub enum Node {
     Variable(NodeVariable),
     Placeholder(NodePlaceholder),
-    Text(NodeText)
+    Text(NodeText),
+    /// Code run at expansion, like UltiSnips' `` `!p ...` ``. It is never shown.
+    Interpolation(NodeInterpolation)
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct NodeText {
     value: String
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct NodePlaceholder {
+    /// Tab stop number, if the source tells
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    index: Option<usize>,
+    /// Options of a choice, `${1|a,b|}`
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    choices: Vec<String>,
+    /// Set on a mirror like `${1/(.*)/${1:/upcase}/}`, whose children are the result
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    transform: Option<NodeTransform>,
     children: Vec<Node>
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct NodeVariable {
+    #[cfg_attr(feature = "serde", serde(default))]
+    name: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    transform: Option<NodeTransform>,
     children: Vec<Node>
 }
 
-#[derive(Debug, Serialize, Default, PartialEq, Eq)]
+/// `/regex/format/options` as written in the syntax of the snippet
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NodeTransform {
+    regex: String,
+    format: String,
+    options: String
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NodeInterpolation {
+    /// python, vim or shell
+    language: String,
+    code: String
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct Response<'a> {
     texts: Vec<Text<'a>>
 }
 
-#[derive(Debug, Serialize, PartialEq, Eq)]
-struct Text<'a> {
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Text<'a> {
     line: usize,
     chunks: Vec<(Cow<'a, str>, Cow<'a, str>)>
 }
 
-pub fn read_request<R: Read>(r: R) -> Request { serde_json::from_reader(r).unwrap() }
+impl<'a> Response<'a> {
+    /// One per previewed line
+    pub fn texts(&self) -> &[Text<'a>] { &self.texts }
+}
+
+impl Text<'_> {
+    /// Buffer line number the preview is shown at
+    pub fn line(&self) -> usize { self.line }
+
+    /// Pieces of text and their highlight groups
+    pub fn chunks(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.chunks.iter().map(|(s, h)| (s.as_ref(), h.as_ref()))
+    }
+
+    /// The whole preview without highlights
+    pub fn text(&self) -> String { self.chunks().map(|(s, _)| s).collect() }
+}
+
+/// Reads a JSON request; see [`Encoding`] for the others
+#[cfg(feature = "serde")]
+pub fn read_request<R: Read>(r: R) -> Result<Request, Error> { Encoding::Json.read_request(r) }
 
-pub fn write_response<W: Write>(w: W, resp: &Response<'_>) {
-    serde_json::to_writer(w, resp).unwrap()
+#[cfg(feature = "serde")]
+pub fn write_response<W: Write>(w: W, resp: &Response<'_>) -> Result<(), Error> {
+    Encoding::Json.write_response(w, resp)
+}
+
+/// Snippets parsed once and matched against lines as often as needed
+#[derive(Debug, Default, Clone)]
+pub struct Matcher {
+    snippets: Vec<Vec<Node>>
+}
+
+/// What a snippet would add after a line
+#[derive(Debug, PartialEq, Eq)]
+pub struct Preview<'a> {
+    line: usize,
+    nodes: &'a [Node]
+}
+
+impl Matcher {
+    pub fn new(snippets: impl IntoIterator<Item = Vec<Node>>) -> Self {
+        Matcher {
+            snippets: snippets.into_iter().collect()
+        }
+    }
+
+    /// Parses bodies in VS Code's syntax
+    pub fn from_bodies<B: AsRef<[String]>>(bodies: impl IntoIterator<Item = B>) -> Self {
+        Self::new(bodies.into_iter().map(|b| nodes(b.as_ref())))
+    }
+
+    pub fn snippets(&self) -> &[Vec<Node>] { &self.snippets }
+
+    /// The best preview for each of `lines`, the first of which is `start_line`
+    pub fn previews(&self, start_line: usize, lines: &[String]) -> Vec<Preview<'_>> {
+        let never = AtomicBool::new(false);
+        r#match(start_line, lines, &self.snippets, &[], &never)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(nodes, _)| !nodes.is_empty())
+            .map(|(nodes, line)| Preview { line, nodes })
+            .collect()
+    }
+}
+
+impl<'a> Preview<'a> {
+    /// Line number the preview is shown at
+    pub fn line(&self) -> usize { self.line }
+
+    /// The part of the snippet that is previewed
+    pub fn nodes(&self) -> &'a [Node] { self.nodes }
+
+    pub fn text(&self) -> String { self.nodes.iter().map(text).collect() }
 }
 
 pub fn calc(req: &Request) -> Response<'_> {
-    let snippets: Vec<_> = req
-        .sources
+    let never = AtomicBool::new(false);
+    calc_cancellable(req, &mut source::Cache::default(), &never).unwrap_or_default()
+}
+
+/// Same as [`calc`] but reuses snippet files in `cache` and gives up as soon as
+/// `cancel` is set, returning `None`.
+pub fn calc_cancellable<'a>(
+    req: &'a Request,
+    cache: &mut source::Cache,
+    cancel: &AtomicBool
+) -> Option<Response<'a>> {
+    let hint = hinter(req, cache);
+    let (snippets, hints): (Vec<Cow<'_, [Node]>>, Vec<Hint>) =
+        collect_snippets(req, cache, cancel, hint)
+            .into_iter()
+            .unzip();
+    calc_nodes(req, &snippets, &hints, cancel)
+}
+
+/// Builds the [`Hint`] of a snippet from its prefixes
+fn hinter<'r>(
+    req: &'r Request,
+    cache: &mut source::Cache
+) -> impl Fn(&[String]) -> Hint + Sync + 'r {
+    let usage = req
+        .usage_file
+        .as_deref()
+        .map(|p| cache.usage(p))
+        .unwrap_or_default();
+    let anchors = req
+        .anchors_file
+        .as_deref()
+        .map(|p| cache.anchors(p))
+        .unwrap_or_default();
+    let now = usage::now();
+    move |prefix| Hint {
+        boost: usage.score(&req.filetypes, prefix, now),
+        anchors: anchors.get(&req.filetypes, prefix)
+    }
+}
+
+/// Parsed snippets of `req`, inline ones first, with what `f` makes of their
+/// prefixes. The order is kept so that the first of equally good snippets wins.
+fn collect_snippets<'c, T: Send>(
+    req: &Request,
+    cache: &'c mut source::Cache,
+    cancel: &AtomicBool,
+    f: impl Fn(&[String]) -> T + Sync
+) -> Vec<(Cow<'c, [Node]>, T)> {
+    let files: Vec<_> = req
+        .snippet_dirs
         .iter()
-        .flat_map(|snippets| snippets.iter())
-        .par_bridge()
-        .filter_map(nodes)
+        .flat_map(|d| cache.find_files(d))
         .collect();
+    let selected = cache.select(&files, &req.filetypes);
+    let inline: Vec<&Snippet> = req.sources.iter().flatten().collect();
+    let inline = inline
+        .into_par_iter()
+        .filter(|_| !is_cancelled(cancel))
+        .map(|s| (Cow::Owned(nodes(&s.body)), f(&s.prefix)));
+    let cached = selected
+        .into_par_iter()
+        .map(|e| (Cow::Borrowed(e.nodes.as_slice()), f(&e.def.prefix)));
+    inline.chain(cached).collect()
+}
+
+/// What is known about a snippet besides its body
+#[derive(Debug, Default)]
+pub(crate) struct Hint {
+    /// Breaks ties, see [`usage`]
+    boost: f64,
+    /// Other first texts, see [`anchors`]
+    anchors: Vec<String>
+}
+
+/// Previews of `snippets` for the lines of `req`, ignoring its snippet sources.
+/// `hints` may be shorter than `snippets`.
+pub(crate) fn calc_nodes<'a>(
+    req: &'a Request,
+    snippets: &[Cow<'_, [Node]>],
+    hints: &[Hint],
+    cancel: &AtomicBool
+) -> Option<Response<'a>> {
+    if is_cancelled(cancel) {
+        return None;
+    }
     if snippets.is_empty() {
-        return Response::default();
+        return Some(Response::default());
     }
     let num = req.cursor_line - req.start_line + 1;
     let before_cursor_inclusive = &req.lines[..num];
-    let matched = r#match(req.start_line, before_cursor_inclusive, &snippets);
+    let matched = r#match(
+        req.start_line,
+        before_cursor_inclusive,
+        snippets,
+        hints,
+        cancel
+    )?;
     let mut texts = Vec::new();
     for (l, i) in matched {
         let nodes = l;
         if nodes.is_empty() {
             continue;
         }
-        let chunks = vec![(
-            Cow::Owned(format!(
-                "{}{}",
-                req.sign,
-                nodes
-                    .iter()
-                    .map(|n| text(n).to_string())
-                    .collect::<Vec<_>>()
-                    .join("")
-            )),
-            Cow::Borrowed(&req.highlight.base as &str)
-        )];
+        let base = &req.highlight.base as &str;
+        let chunks = match &req.highlight.placeholder {
+            Some(placeholder) => node_chunks(&req.sign, nodes, base, placeholder),
+            None => vec![(
+                Cow::Owned(format!(
+                    "{}{}",
+                    req.sign,
+                    nodes
+                        .iter()
+                        .map(|n| text(n).to_string())
+                        .collect::<Vec<_>>()
+                        .join("")
+                )),
+                Cow::Borrowed(base)
+            )]
+        };
         let text = Text { line: i, chunks };
         texts.push(text);
     }
-    Response { texts }
+    Some(Response { texts })
+}
+
+/// The sign and the texts of `nodes` in `base`, those of tab stops and
+/// variables in `placeholder`, neighbours of the same group together
+fn node_chunks<'a>(
+    sign: &str,
+    nodes: &[Node],
+    base: &'a str,
+    placeholder: &'a str
+) -> Vec<(Cow<'a, str>, Cow<'a, str>)> {
+    let mut chunks: Vec<(String, &str)> = vec![(sign.to_owned(), base)];
+    for node in nodes {
+        let group = match node {
+            Node::Placeholder(p) if p.index.is_some() => placeholder,
+            Node::Variable(_) => placeholder,
+            _ => base
+        };
+        let s = text(node);
+        if s.is_empty() {
+            continue;
+        }
+        match chunks.last_mut() {
+            Some((t, g)) if *g == group => t.push_str(&s),
+            _ => chunks.push((s.into_owned(), group))
+        }
+    }
+    chunks
+        .into_iter()
+        .filter(|(t, _)| !t.is_empty())
+        .map(|(t, g)| (Cow::Owned(t), Cow::Borrowed(g)))
+        .collect()
 }
 
-fn nodes(snip: &Snippet) -> Option<Vec<Node>> {
-    let b = snip.body.join("\n");
-    let ast = vs_snippet::parse(&b)?;
-    Some(ast.0.into_iter().map(node_from_ast).collect())
+fn is_cancelled(cancel: &AtomicBool) -> bool { cancel.load(Ordering::Relaxed) }
+
+/// Malformed parts are shown as text, as vim-vsnip does
+fn nodes(body: &[String]) -> Vec<Node> {
+    let mut nodes = parse_nodes(&body.join("\n"));
+    resolve_mirrors(&mut nodes, |t, value| {
+        vs_snippet::resolve_transform_str(&t.regex, &t.format, &t.options, value)
+    });
+    nodes
+}
+
+/// A body in VS Code's syntax as it is written, mirrors still empty
+pub(crate) fn parse_nodes(s: &str) -> Vec<Node> {
+    let (ast, _) = vs_snippet::parse_recovering(s);
+    ast.0.iter().map(node_from_ast).collect()
 }
 
-fn node_from_ast(any: vs_snippet::Any<'_>) -> Node {
+pub(crate) fn node_from_ast(any: &vs_snippet::Any<'_>) -> Node {
+    let children = |cs: &[vs_snippet::Any<'_>]| cs.iter().map(node_from_ast).collect();
     match any {
-        vs_snippet::Any::TabStop(_) => Node::Placeholder(NodePlaceholder { children: vec![] }),
-        vs_snippet::Any::Placeholder(_, cs) => Node::Placeholder(NodePlaceholder {
-            children: cs.into_iter().map(node_from_ast).collect()
+        vs_snippet::Any::TabStop(n) => Node::Placeholder(NodePlaceholder {
+            index: Some(*n),
+            ..Default::default()
+        }),
+        vs_snippet::Any::TabStopTransform(n, regex, formats, options) => {
+            Node::Placeholder(NodePlaceholder {
+                index: Some(*n),
+                transform: Some(NodeTransform::new(regex, formats, options)),
+                ..Default::default()
+            })
+        }
+        vs_snippet::Any::Placeholder(n, cs) => Node::Placeholder(NodePlaceholder {
+            index: Some(*n),
+            children: children(cs),
+            ..Default::default()
+        }),
+        vs_snippet::Any::Choice(n, xs) => Node::Placeholder(NodePlaceholder {
+            index: Some(*n),
+            choices: xs.clone(),
+            ..Default::default()
         }),
-        vs_snippet::Any::Choice(_, _) => Node::Placeholder(NodePlaceholder { children: vec![] }),
-        vs_snippet::Any::Variable(_, vs_snippet::V::Any(cs)) => Node::Variable(NodeVariable {
-            children: cs.into_iter().map(node_from_ast).collect()
+        vs_snippet::Any::Variable(name, v) => Node::Variable(NodeVariable {
+            name: (*name).to_owned(),
+            transform: match v {
+                vs_snippet::V::Transform(regex, formats, options) => {
+                    Some(NodeTransform::new(regex, formats, options))
+                }
+                _ => None
+            },
+            children: match v {
+                vs_snippet::V::Any(cs) => children(cs),
+                _ => vec![]
+            }
         }),
-        vs_snippet::Any::Variable(_, _) => Node::Variable(NodeVariable { children: vec![] }),
-        vs_snippet::Any::Text(s) => Node::Text(NodeText { value: s })
+        vs_snippet::Any::Text(s) => Node::Text(NodeText { value: s.clone() })
     }
 }
 
-fn r#match<'a>(
+/// Gives every empty tab stop the children of the first placeholder of the same
+/// number, whatever the syntax of the body. A mirror with a transform shows
+/// what `transform` makes of that text, or the text itself if it gives `None`.
+pub(crate) fn resolve_mirrors(
+    nodes: &mut [Node],
+    transform: impl Fn(&NodeTransform, &str) -> Option<String> + Copy
+) {
+    let mut sources = HashMap::new();
+    first_placeholders(nodes, &mut sources);
+    fill_mirrors(nodes, &sources, &mut Vec::new(), transform);
+}
+
+/// Children of the first placeholder of each tab stop, which its mirrors repeat
+fn first_placeholders(nodes: &[Node], found: &mut HashMap<usize, Vec<Node>>) {
+    for n in nodes {
+        match n {
+            Node::Placeholder(p) => {
+                if let (Some(i), false, None) = (p.index, p.children.is_empty(), &p.transform) {
+                    found.entry(i).or_insert_with(|| p.children.clone());
+                } else if let (Some(i), Some(c)) = (p.index, p.choices.first()) {
+                    found
+                        .entry(i)
+                        .or_insert_with(|| vec![Node::Text(NodeText { value: c.clone() })]);
+                }
+                first_placeholders(&p.children, found);
+            }
+            Node::Variable(v) => first_placeholders(&v.children, found),
+            _ => ()
+        }
+    }
+}
+
+/// `seen` holds the tab stops being expanded so that `${1:a $1}` does not recurse forever
+fn fill_mirrors(
+    nodes: &mut [Node],
+    sources: &HashMap<usize, Vec<Node>>,
+    seen: &mut Vec<usize>,
+    transform: impl Fn(&NodeTransform, &str) -> Option<String> + Copy
+) {
+    for n in nodes {
+        let p = match n {
+            Node::Placeholder(p) => p,
+            Node::Variable(v) => {
+                fill_mirrors(&mut v.children, sources, seen, transform);
+                continue;
+            }
+            _ => continue
+        };
+        let index = match p.index {
+            Some(i) if seen.contains(&i) => {
+                p.children.clear();
+                continue;
+            }
+            Some(i) => i,
+            None => continue
+        };
+        let mirror = p.children.is_empty() && p.choices.is_empty();
+        if mirror {
+            p.children = sources.get(&index).cloned().unwrap_or_default();
+        }
+        seen.push(index);
+        fill_mirrors(&mut p.children, sources, seen, transform);
+        seen.pop();
+        if let (true, Some(t)) = (mirror, &p.transform) {
+            let value: String = p.children.iter().map(text).collect();
+            let value = transform(t, &value).unwrap_or(value);
+            p.children = vec![Node::Text(NodeText { value })];
+        }
+    }
+}
+
+impl NodeTransform {
+    fn new(
+        regex: &vs_snippet::Regex<'_>,
+        formats: &[vs_snippet::Format<'_>],
+        options: &vs_snippet::Options<'_>
+    ) -> Self {
+        NodeTransform {
+            regex: regex.0.to_owned(),
+            format: vs_snippet::Formats(formats).to_string(),
+            options: options.0.to_owned()
+        }
+    }
+}
+
+fn r#match<'a, S: Deref<Target = [Node]> + Sync>(
     start_line: usize,
     buf: &[String],
-    snippets: &'a [Vec<Node>]
-) -> Vec<(&'a [Node], usize)> {
+    snippets: &'a [S],
+    hints: &[Hint],
+    cancel: &AtomicBool
+) -> Option<Vec<(&'a [Node], usize)>> {
     let snips: Vec<&[Node]> = snippets.iter().map(Deref::deref).map(first_text).collect();
-    buf.iter()
-        .zip(start_line..)
-        .par_bridge()
-        .map(|(l, i)| {
-            let founds: Vec<_> = snips.iter().map(|nodes| find(l, nodes)).collect();
+    buf.into_par_iter()
+        .enumerate()
+        .map(|(k, l)| {
+            let i = start_line + k;
+            if is_cancelled(cancel) {
+                return None;
+            }
+            let founds: Vec<_> = snips
+                .iter()
+                .enumerate()
+                .map(|(j, nodes)| find_hinted(l, nodes, hints.get(j)).0)
+                .collect();
             let max: Option<(_, _)> = {
-                let mut max = 0.;
+                let mut max = (0., 0.);
                 let mut v = None;
-                for (n, f) in snips.iter().zip(founds.iter()) {
-                    if f.num == 0 || f.hit == 0 {
-                        continue;
-                    }
-                    let score = f.hit as f64 / f.num as f64;
+                for (j, (n, f)) in snips.iter().zip(founds.iter()).enumerate() {
+                    let score = match score(f, hints.get(j)) {
+                        Some(s) => s,
+                        None => continue
+                    };
                     if max < score {
                         max = score;
                         v = Some((n, f));
@@ -155,18 +804,23 @@ fn r#match<'a>(
                 v
             };
             let nodes_for_this_line = max.map(tail_excluding_matches).unwrap_or_default();
-            (nodes_for_this_line, i)
+            Some((nodes_for_this_line, i))
         })
         .collect()
 }
 
 fn tail_excluding_matches<'a, 'b>(found: (&'a &'b [Node], &'a Found)) -> &'b [Node] {
     let (s, f) = found;
+    cut(s, f).0
+}
+
+/// The nodes previewed after a line matching `s` as `f` says, and why
+fn cut<'b>(s: &'b [Node], f: &Found) -> (&'b [Node], Cut) {
     if f.hit < f.num_first {
-        return s;
+        return (s, Cut::FirstText);
     }
     let ns = match s.len() {
-        0 | 1 => return &[],
+        0 | 1 => return (&[], Cut::Nothing),
         _ => &s[1..]
     };
     let matches = f.hit - f.num_first;
@@ -186,10 +840,22 @@ fn tail_excluding_matches<'a, 'b>(found: (&'a &'b [Node], &'a Found)) -> &'b [No
     };
     let tail = &ns[idx..];
     if tail.iter().all(|n| !n.is_text()) {
-        &[]
+        (&[], Cut::Nothing)
     } else {
-        tail
+        (tail, Cut::After { nodes: idx + 1 })
+    }
+}
+
+/// Ranks a snippet for a line; `None` if it does not match at all.
+/// The boost only decides between equal ratios.
+fn score(f: &Found, hint: Option<&Hint>) -> Option<(f64, f64)> {
+    if f.num == 0 || f.hit == 0 {
+        return None;
     }
+    Some((
+        f.hit as f64 / f.num as f64,
+        hint.map(|h| h.boost).unwrap_or_default()
+    ))
 }
 
 fn first_text(nodes: &[Node]) -> &[Node] {
@@ -216,46 +882,95 @@ fn find(line: &str, nodes: &[Node]) -> Found {
         Node::Text(n) => n,
         _ => unreachable!()
     };
-    let fs = first.value.trim().split(char::is_whitespace);
+    find_from(line, &first.value, nodes)
+}
+
+/// [`find`], or [`find_anchored`] with the anchors of `hint`, and the anchor
+/// that stood in for the first text
+fn find_hinted<'h>(line: &str, nodes: &[Node], hint: Option<&'h Hint>) -> (Found, Option<&'h str>) {
+    match hint {
+        Some(h) if !h.anchors.is_empty() => find_anchored(line, nodes, &h.anchors),
+        _ => (find(line, nodes), None)
+    }
+}
+
+/// The best of [`find`] and of finding each anchor in place of the first text.
+/// The original wins ties.
+fn find_anchored<'h>(
+    line: &str,
+    nodes: &[Node],
+    anchors: &'h [String]
+) -> (Found, Option<&'h str>) {
+    let mut best = (find(line, nodes), None);
+    if nodes.is_empty() || line.is_empty() {
+        return best;
+    }
+    let ratio = |f: &Found| match f.num {
+        0 => 0.,
+        n => f.hit as f64 / n as f64
+    };
+    for a in anchors {
+        let f = find_from(line, a, nodes);
+        if ratio(&best.0) < ratio(&f) {
+            best = (f, Some(a.as_str()));
+        }
+    }
+    best
+}
+
+/// `first` stands for the text of `nodes[0]`
+fn find_from(line: &str, first: &str, nodes: &[Node]) -> Found {
+    trace_from(line, first, nodes, |_| ())
+}
+
+/// A word of a snippet looked for in a line
+struct Step<'a> {
+    word: &'a str,
+    /// Of the first text
+    first: bool,
+    /// Where it was found, in characters
+    at: Option<usize>,
+    /// Words of one letter in the first text do not count as a hit
+    counted: bool
+}
+
+/// [`find_from`] telling `step` about every word, also those after the first
+/// one missing
+fn trace_from(line: &str, first: &str, nodes: &[Node], mut step: impl FnMut(Step<'_>)) -> Found {
+    let fs = first.trim().split(char::is_whitespace);
     let num_first = fs.clone().count();
-    let rest: Vec<_> = nodes[1..]
-        .iter()
-        .filter_map(|n| match n {
-            Node::Text(n) => Some(n),
-            _ => None
-        })
-        .collect();
-    let num = fs.clone().count() + rest.len();
+    let rest = nodes[1..].iter().filter_map(|n| match n {
+        Node::Text(n) => Some(n.value.trim()),
+        _ => None
+    });
+    let num = num_first + rest.clone().count();
     let chars: Vec<char> = line.chars().collect();
     let mut hit = 0;
     let mut cur = 0;
-    for word in fs {
-        if let Some(i) = contains(&chars[cur..], word) {
-            // Even if one letter exists, it's unlikely.
-            if word.len() > 1 {
-                hit += 1;
-            }
-            cur = i + word.chars().count();
+    let mut missed = false;
+    for (j, word) in fs.chain(rest).enumerate() {
+        let first = j < num_first;
+        let found = if missed {
+            None
         } else {
-            return Found {
-                hit,
-                num,
-                num_first
-            };
-        }
-    }
-    for n in rest {
-        let word = n.value.trim();
-        if let Some(i) = contains(&chars[cur..], word) {
-            hit += 1;
-            cur = i + word.chars().count();
-        } else {
-            return Found {
-                hit,
-                num,
-                num_first
-            };
+            contains(&chars[cur..], word)
+        };
+        let at = found.map(|i| cur + i);
+        // Even if one letter exists, it's unlikely.
+        let counted = found.is_some() && (!first || word.len() > 1);
+        match found {
+            Some(i) => {
+                hit += usize::from(counted);
+                cur = i + word.chars().count();
+            }
+            None => missed = true
         }
+        step(Step {
+            word,
+            first,
+            at,
+            counted
+        });
     }
     Found {
         hit,
@@ -279,13 +994,57 @@ fn contains(sentence: &[char], words: &str) -> Option<usize> {
 
 impl Node {
     fn is_text(&self) -> bool { matches!(self, Node::Text(_)) }
+
+    /// What the preview shows for the node
+    pub fn text(&self) -> Cow<'_, str> { text(self) }
+}
+
+impl NodeText {
+    pub fn value(&self) -> &str { &self.value }
 }
 
+impl NodePlaceholder {
+    pub fn index(&self) -> Option<usize> { self.index }
+
+    pub fn choices(&self) -> &[String] { &self.choices }
+
+    pub fn transform(&self) -> Option<&NodeTransform> { self.transform.as_ref() }
+
+    pub fn children(&self) -> &[Node] { &self.children }
+}
+
+impl NodeVariable {
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn transform(&self) -> Option<&NodeTransform> { self.transform.as_ref() }
+
+    pub fn children(&self) -> &[Node] { &self.children }
+}
+
+impl NodeTransform {
+    pub fn regex(&self) -> &str { &self.regex }
+
+    pub fn format(&self) -> &str { &self.format }
+
+    pub fn options(&self) -> &str { &self.options }
+}
+
+impl NodeInterpolation {
+    pub fn language(&self) -> &str { &self.language }
+
+    pub fn code(&self) -> &str { &self.code }
+}
+
+/// A choice shows its first option
 fn text(node: &Node) -> Cow<'_, str> {
     let children = match node {
         Node::Text(t) => return Cow::Borrowed(&t.value),
+        Node::Interpolation(_) => return Cow::Borrowed(""),
         Node::Variable(n) => &n.children,
-        Node::Placeholder(n) => &n.children
+        Node::Placeholder(n) => match (n.children.is_empty(), n.choices.first()) {
+            (true, Some(c)) => return Cow::Borrowed(c),
+            _ => &n.children
+        }
     };
     Cow::Owned(children.iter().map(text).collect::<Vec<_>>().join(""))
 }
@@ -0,0 +1,318 @@
+//! Checks snippet files for bodies that can not be parsed and for constructs
+//! that parse but probably do not do what the author meant.
+//!
+//! Only bodies in the LSP syntax are checked; UltiSnips and SnipMate bodies
+//! always parse since anything that is not a construct is text.
+//! What the reader of a file skips, like UltiSnips' `global !p`, is a warning
+//! for the whole file.
+use crate::{
+    source::{self, SnippetDef, Syntax},
+    vs_snippet::{self, Any, Format, Regex, V}
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::Path
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Name of the snippet; empty for the whole file
+    pub snippet: String,
+    /// 1-based line and column in the body
+    pub position: Option<(usize, usize)>,
+    pub message: String
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.snippet.is_empty() {
+            write!(f, "{}:", self.snippet)?;
+        }
+        if let Some((line, column)) = self.position {
+            write!(f, "{}:{}:", line, column)?;
+        }
+        write!(f, " {}: {}", self.severity, self.message)
+    }
+}
+
+/// https://code.visualstudio.com/docs/editor/userdefinedsnippets#_variables
+const VARIABLES: &[&str] = &[
+    "TM_SELECTED_TEXT",
+    "TM_CURRENT_LINE",
+    "TM_CURRENT_WORD",
+    "TM_LINE_INDEX",
+    "TM_LINE_NUMBER",
+    "TM_FILENAME",
+    "TM_FILENAME_BASE",
+    "TM_DIRECTORY",
+    "TM_FILEPATH",
+    "RELATIVE_FILEPATH",
+    "CLIPBOARD",
+    "WORKSPACE_NAME",
+    "WORKSPACE_FOLDER",
+    "CURSOR_INDEX",
+    "CURSOR_NUMBER",
+    "CURRENT_YEAR",
+    "CURRENT_YEAR_SHORT",
+    "CURRENT_MONTH",
+    "CURRENT_MONTH_NAME",
+    "CURRENT_MONTH_NAME_SHORT",
+    "CURRENT_DATE",
+    "CURRENT_DAY_NAME",
+    "CURRENT_DAY_NAME_SHORT",
+    "CURRENT_HOUR",
+    "CURRENT_MINUTE",
+    "CURRENT_SECOND",
+    "CURRENT_SECONDS_UNIX",
+    "CURRENT_TIMEZONE_OFFSET",
+    "RANDOM",
+    "RANDOM_HEX",
+    "UUID",
+    "BLOCK_COMMENT_START",
+    "BLOCK_COMMENT_END",
+    "LINE_COMMENT"
+];
+
+pub fn lint_file(path: &Path) -> Vec<Diagnostic> {
+    match source::read_file(path) {
+        Ok(file) => file
+            .warnings
+            .into_iter()
+            .map(|message| Diagnostic {
+                severity: Severity::Warning,
+                snippet: String::new(),
+                position: None,
+                message
+            })
+            .chain(file.snippets.iter().flat_map(lint_snippet))
+            .collect(),
+        Err(e) => vec![Diagnostic {
+            severity: Severity::Error,
+            snippet: String::new(),
+            position: None,
+            message: e.to_string()
+        }]
+    }
+}
+
+pub fn lint_snippet(def: &SnippetDef) -> Vec<Diagnostic> {
+    if def.syntax != Syntax::Lsp {
+        return Vec::new();
+    }
+    let diagnostic = |severity, position, message| Diagnostic {
+        severity,
+        snippet: def.name.clone(),
+        position,
+        message
+    };
+    let body = def.body.join("\n");
+    let ast = match vs_snippet::parse_with_diagnostics(&body) {
+        Ok(ast) => ast,
+        Err(e) => {
+            return vec![diagnostic(
+                Severity::Error,
+                Some((e.line, e.column)),
+                e.message()
+            )]
+        }
+    };
+    let mut w = Walk::default();
+    w.walk(&ast.0);
+    let mut messages = Vec::new();
+    for (n, defaults) in &w.defaults {
+        if defaults.len() > 1 {
+            let defaults: Vec<_> = defaults.iter().map(|d| format!("`{}`", d)).collect();
+            messages.push(format!(
+                "${} has conflicting defaults {}",
+                n,
+                defaults.join(" and ")
+            ));
+        }
+    }
+    if !w.tabstops.is_empty() && !w.tabstops.contains(&0) {
+        messages.push("no $0; the cursor ends up after the snippet".into());
+    }
+    if w.open != w.close {
+        messages.push(format!(
+            "unbalanced braces in text: {} `{{` and {} `}}`",
+            w.open, w.close
+        ));
+    }
+    for name in &w.unknown {
+        messages.push(format!("unknown variable {}", name));
+    }
+    messages.extend(w.transforms);
+    messages
+        .into_iter()
+        .map(|m| diagnostic(Severity::Warning, None, m))
+        .collect()
+}
+
+#[derive(Default)]
+struct Walk {
+    /// Distinct defaults of each tab stop
+    defaults: BTreeMap<usize, BTreeSet<String>>,
+    tabstops: BTreeSet<usize>,
+    unknown: BTreeSet<String>,
+    /// Problems found in transforms
+    transforms: Vec<String>,
+    open: usize,
+    close: usize
+}
+
+impl Walk {
+    fn walk(&mut self, xs: &[Any<'_>]) {
+        for x in xs {
+            match x {
+                Any::TabStop(n) | Any::Choice(n, _) => {
+                    self.tabstops.insert(*n);
+                }
+                Any::TabStopTransform(n, regex, formats, _) => {
+                    self.tabstops.insert(*n);
+                    self.transform(&format!("${}", n), regex, formats);
+                }
+                Any::Placeholder(n, children) => {
+                    self.tabstops.insert(*n);
+                    let default = children.iter().map(ToString::to_string).collect();
+                    self.defaults.entry(*n).or_default().insert(default);
+                    self.walk(children);
+                }
+                Any::Variable(name, v) => {
+                    if !VARIABLES.contains(name) {
+                        self.unknown.insert((*name).to_owned());
+                    }
+                    match v {
+                        V::None => (),
+                        V::Any(children) => self.walk(children),
+                        V::Transform(regex, formats, _) => self.transform(name, regex, formats)
+                    }
+                }
+                Any::Text(t) => {
+                    self.open += t.matches('{').count();
+                    self.close += t.matches('}').count();
+                }
+            }
+        }
+    }
+
+    fn transform(&mut self, name: &str, regex: &Regex<'_>, formats: &[Format<'_>]) {
+        let groups = count_groups(regex.0);
+        let used: BTreeSet<usize> = formats
+            .iter()
+            .filter_map(|f| match f {
+                Format::Matched(n)
+                | Format::Upcase(n)
+                | Format::Downcase(n)
+                | Format::Capitalize(n)
+                | Format::If(n, _)
+                | Format::IfElse(n, _, _)
+                | Format::Else(n, _) => Some(*n),
+                Format::Text(_) => None
+            })
+            .collect();
+        for n in used.iter().filter(|n| **n > groups) {
+            self.transforms.push(format!(
+                "transform of {} refers to group {} but the regex has {}",
+                name, n, groups
+            ));
+        }
+        for n in (1..=groups).filter(|n| !used.contains(n)) {
+            self.transforms.push(format!(
+                "group {} in the transform of {} is unused",
+                n, name
+            ));
+        }
+    }
+}
+
+/// Capturing groups of a JavaScript regex
+fn count_groups(regex: &str) -> usize {
+    let mut n = 0;
+    let mut in_class = false;
+    let mut chars = regex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '(' if !in_class => {
+                if chars.peek() != Some(&'?') {
+                    n += 1;
+                    continue;
+                }
+                // (?<name>...) captures while (?:, (?=, (?<= and (?<! do not
+                let mut ahead = chars.clone().skip(1);
+                if ahead.next() == Some('<') && !matches!(ahead.next(), Some('=' | '!')) {
+                    n += 1;
+                }
+            }
+            _ => ()
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(body: &str) -> Vec<String> {
+        let def = SnippetDef {
+            name: "s".into(),
+            prefix: vec!["s".into()],
+            body: body.split('\n').map(String::from).collect(),
+            description: String::new(),
+            scope: Vec::new(),
+            syntax: Syntax::Lsp,
+            priority: 0
+        };
+        lint_snippet(&def).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn can_lint() {
+        assert_eq!(lint("if ${1:a} then\n\t$0\nend"), Vec::<String>::new());
+        assert_eq!(lint("if a\n\tb ${1:c"), vec!["s:2:9: error: expected `}`"]);
+        assert_eq!(
+            lint("${1:a} ${1:b} $1 {"),
+            vec![
+                "s: warning: $1 has conflicting defaults `a` and `b`",
+                "s: warning: no $0; the cursor ends up after the snippet",
+                "s: warning: unbalanced braces in text: 1 `{` and 0 `}`"
+            ]
+        );
+        assert_eq!(
+            lint("$FOO ${TM_FILENAME/(a)(?:b)(?<c>d)[(]/$2${4:/upcase}/} $0"),
+            vec![
+                "s: warning: unknown variable FOO",
+                "s: warning: transform of TM_FILENAME refers to group 4 but the regex has 2",
+                "s: warning: group 1 in the transform of TM_FILENAME is unused"
+            ]
+        );
+    }
+
+    #[test]
+    fn can_count_groups() {
+        assert_eq!(count_groups(r"(a)\((b)"), 2);
+        assert_eq!(count_groups(r"(?:a)(?=b)(?<!c)(?<d>e)[()]"), 1);
+    }
+}
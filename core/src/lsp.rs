@@ -0,0 +1,569 @@
+//! Minimal language server publishing remainders as inlay hints.
+//!
+//! Snippets are read from `.vscode/*.code-snippets` of every workspace folder and
+//! from the directories in `initializationOptions.snippetDirectories`. The
+//! directories are listed again for every request, so snippet files added or
+//! changed later are picked up. Documents are synchronized in full. Snippets
+//! are completed in LSP's syntax whatever they are written in, dropping what it
+//! cannot express like Python interpolation.
+use crate::{calc_nodes, convert, source, source::Syntax, Highlight, Node, Request};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    sync::atomic::AtomicBool
+};
+
+const INSERT_TEXT_FORMAT_SNIPPET: u8 = 2;
+const COMPLETION_ITEM_KIND_SNIPPET: u8 = 15;
+const TEXT_DOCUMENT_SYNC_FULL: u8 = 1;
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Debug, Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+    /// `.vscode` folders, whose other files are not snippets
+    workspace_dirs: Vec<PathBuf>,
+    snippet_dirs: Vec<PathBuf>,
+    cache: source::Cache,
+    shutdown: bool
+}
+
+#[derive(Debug)]
+struct Document {
+    language_id: String,
+    text: String
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    id: Option<Value>,
+    method: Option<String>,
+    #[serde(default)]
+    params: Value
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InitializeParams {
+    root_uri: Option<String>,
+    workspace_folders: Option<Vec<WorkspaceFolder>>,
+    #[serde(default)]
+    initialization_options: InitializationOptions
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InitializationOptions {
+    #[serde(default)]
+    snippet_directories: Vec<PathBuf>
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkspaceFolder {
+    uri: String
+}
+
+#[derive(Debug, Deserialize)]
+struct TextDocumentItem {
+    uri: String,
+    #[serde(rename = "languageId")]
+    language_id: String,
+    text: String
+}
+
+#[derive(Debug, Deserialize)]
+struct TextDocumentIdentifier {
+    uri: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenParams {
+    text_document: TextDocumentItem
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidChangeParams {
+    text_document: TextDocumentIdentifier,
+    content_changes: Vec<ContentChange>
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentChange {
+    text: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentParams {
+    text_document: TextDocumentIdentifier
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHintParams {
+    text_document: TextDocumentIdentifier,
+    range: Range
+}
+
+#[derive(Debug, Deserialize)]
+struct Range {
+    start: Position,
+    end: Position
+}
+
+#[derive(Debug, Deserialize)]
+struct Position {
+    line: usize
+}
+
+/// Serves until `exit`. Returns the process exit code LSP asks for.
+pub fn run<R: BufRead, W: Write>(mut r: R, mut w: W) -> io::Result<i32> {
+    let mut server = Server::default();
+    while let Some(body) = read_message(&mut r)? {
+        let msg: Message = match serde_json::from_slice(&body) {
+            Ok(m) => m,
+            Err(e) => {
+                let error = json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": PARSE_ERROR, "message": e.to_string()}
+                });
+                write_message(&mut w, &error)?;
+                continue;
+            }
+        };
+        let method = match msg.method {
+            Some(m) => m,
+            // response to a request we never send
+            None => continue
+        };
+        if method == "exit" {
+            return Ok(if server.shutdown { 0 } else { 1 });
+        }
+        let result = if server.shutdown {
+            Err((INVALID_REQUEST, "the server is shut down".to_owned()))
+        } else {
+            server.handle(&method, msg.params)
+        };
+        if let Some(id) = msg.id {
+            let resp = match result {
+                Ok(v) => json!({"jsonrpc": "2.0", "id": id, "result": v}),
+                Err((code, message)) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": code, "message": message}
+                })
+            };
+            write_message(&mut w, &resp)?;
+        }
+    }
+    Ok(1)
+}
+
+impl Server {
+    fn handle(&mut self, method: &str, params: Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => {
+                let p: InitializeParams = from_params(params)?;
+                self.set_snippet_dirs(&p);
+                Ok(json!({
+                    "capabilities": {
+                        "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+                        "inlayHintProvider": true,
+                        "completionProvider": {}
+                    },
+                    "serverInfo": {"name": "virtualsnip", "version": env!("CARGO_PKG_VERSION")}
+                }))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/didOpen" => {
+                let p: DidOpenParams = from_params(params)?;
+                let d = p.text_document;
+                self.documents.insert(
+                    d.uri,
+                    Document {
+                        language_id: d.language_id,
+                        text: d.text
+                    }
+                );
+                Ok(Value::Null)
+            }
+            "textDocument/didChange" => {
+                let p: DidChangeParams = from_params(params)?;
+                if let (Some(doc), Some(change)) = (
+                    self.documents.get_mut(&p.text_document.uri),
+                    p.content_changes.into_iter().last()
+                ) {
+                    doc.text = change.text;
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didClose" => {
+                let p: DocumentParams = from_params(params)?;
+                self.documents.remove(&p.text_document.uri);
+                Ok(Value::Null)
+            }
+            "textDocument/inlayHint" => {
+                let p: InlayHintParams = from_params(params)?;
+                Ok(self.inlay_hints(&p))
+            }
+            "textDocument/completion" => {
+                let p: DocumentParams = from_params(params)?;
+                Ok(self.completion(&p.text_document.uri))
+            }
+            _ if method.starts_with("$/") => Ok(Value::Null),
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method)))
+        }
+    }
+
+    fn set_snippet_dirs(&mut self, p: &InitializeParams) {
+        let folders: Vec<&str> = match (&p.workspace_folders, &p.root_uri) {
+            (Some(fs), _) if !fs.is_empty() => fs.iter().map(|f| f.uri.as_str()).collect(),
+            (_, Some(root)) => vec![root.as_str()],
+            _ => Vec::new()
+        };
+        self.workspace_dirs = folders
+            .into_iter()
+            .filter_map(uri_to_path)
+            .map(|d| d.join(".vscode"))
+            .collect();
+        self.snippet_dirs = p.initialization_options.snippet_directories.clone();
+    }
+
+    fn snippet_files(&mut self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for dir in &self.workspace_dirs {
+            // .vscode also has settings.json and the like
+            files.extend(
+                self.cache
+                    .find_files(dir)
+                    .into_iter()
+                    .filter(|f| f.extension().is_some_and(|e| e == "code-snippets"))
+            );
+        }
+        for dir in &self.snippet_dirs {
+            files.extend(self.cache.find_files(dir));
+        }
+        files
+    }
+
+    fn inlay_hints(&mut self, p: &InlayHintParams) -> Value {
+        let files = self.snippet_files();
+        let doc = match self.documents.get(&p.text_document.uri) {
+            Some(d) => d,
+            None => return json!([])
+        };
+        let lines: Vec<&str> = doc.text.split('\n').collect();
+        let start = p.range.start.line.min(lines.len());
+        let end = (p.range.end.line + 1).min(lines.len());
+        if start >= end {
+            return json!([]);
+        }
+        let snippets: Vec<Cow<'_, [Node]>> = self
+            .cache
+            .select(&files, std::slice::from_ref(&doc.language_id))
+            .into_iter()
+            .map(|e| Cow::Borrowed(e.nodes.as_slice()))
+            .collect();
+        let req = Request {
+            version: None,
+            highlight: Highlight {
+                base: String::new(),
+                placeholder: None
+            },
+            sign: String::new(),
+            lines: lines[start..end]
+                .iter()
+                .map(|l| l.trim_end_matches('\r').to_owned())
+                .collect(),
+            start_line: start,
+            cursor_line: end - 1,
+            sources: Vec::new(),
+            snippet_dirs: Vec::new(),
+            filetypes: Vec::new(),
+            usage_file: None,
+            anchors_file: None
+        };
+        let never = AtomicBool::new(false);
+        let resp = calc_nodes(&req, &snippets, &[], &never).unwrap_or_default();
+        let hints: Vec<Value> = resp
+            .texts
+            .iter()
+            .map(|t| {
+                let label = t.text();
+                let character = req.lines[t.line - start].encode_utf16().count();
+                json!({
+                    "position": {"line": t.line, "character": character},
+                    "label": one_line(&label),
+                    "paddingLeft": true
+                })
+            })
+            .collect();
+        json!(hints)
+    }
+
+    fn completion(&mut self, uri: &str) -> Value {
+        let files = self.snippet_files();
+        let doc = match self.documents.get(uri) {
+            Some(d) => d,
+            None => return json!([])
+        };
+        let items: Vec<Value> = self
+            .cache
+            .select(&files, std::slice::from_ref(&doc.language_id))
+            .into_iter()
+            .map(|e| &e.def)
+            .flat_map(|s| {
+                let body = convert::parse_body(&s.body.join("\n"), s.syntax);
+                let text = convert::print(&body, s.syntax, Syntax::Lsp, &mut |_| ());
+                s.prefix.iter().map(move |prefix| {
+                    json!({
+                        "label": prefix,
+                        "kind": COMPLETION_ITEM_KIND_SNIPPET,
+                        "detail": s.name,
+                        "documentation": s.description,
+                        "insertText": text,
+                        "insertTextFormat": INSERT_TEXT_FORMAT_SNIPPET
+                    })
+                })
+            })
+            .collect();
+        json!(items)
+    }
+}
+
+fn from_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+/// Inlay hints are shown inline, so newlines and indentation are folded
+fn one_line(s: &str) -> String { s.split_whitespace().collect::<Vec<_>>().join(" ") }
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+/// The body of the next message. Headers without a length are skipped, since
+/// there is no telling where their body ends.
+fn read_message<R: BufRead>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        let (mut len, mut headers) = (None, 0);
+        loop {
+            let mut header = String::new();
+            if r.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            headers += 1;
+            if let Some(v) = header.strip_prefix("Content-Length:") {
+                len = v.trim().parse::<usize>().ok();
+            }
+        }
+        match len {
+            Some(len) => {
+                let mut buf = vec![0; len];
+                r.read_exact(&mut buf)?;
+                return Ok(Some(buf));
+            }
+            None if headers > 0 => eprintln!("lsp: skipped a message without Content-Length"),
+            None => ()
+        }
+    }
+}
+
+fn write_message<W: Write>(w: &mut W, v: &Value) -> io::Result<()> {
+    let body = serde_json::to_string(v)?;
+    write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+    use std::{fs, io::Cursor};
+
+    fn frame(v: Value) -> String {
+        let body = v.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn responses(out: &[u8]) -> Vec<Value> {
+        let mut r = Cursor::new(out);
+        let mut vs = Vec::new();
+        while let Some(body) = read_message(&mut r).unwrap() {
+            vs.push(serde_json::from_slice(&body).unwrap());
+        }
+        vs
+    }
+
+    #[test]
+    fn can_uri_to_path() {
+        assert_eq!(
+            uri_to_path("file:///home/a%20b/c"),
+            Some(PathBuf::from("/home/a b/c"))
+        );
+        assert_eq!(uri_to_path("untitled:foo"), None);
+    }
+
+    #[test]
+    fn session() {
+        let tmp = TempDir::new("lsp");
+        let root = tmp.path();
+        fs::create_dir_all(root.join(".vscode")).unwrap();
+        fs::write(
+            root.join(".vscode/lua.code-snippets"),
+            r#"{"if": {"prefix": "if", "body": ["if ${1:true} then", "\t$0", "end"], "scope": "lua"}}"#
+        )
+        .unwrap();
+        fs::create_dir_all(root.join("snippets")).unwrap();
+        fs::write(
+            root.join("snippets/lua.json"),
+            r#"{"while": {"prefix": "while", "body": ["while ${1:true} do", "end"]},}"#
+        )
+        .unwrap();
+        let uri = "file:///a.lua";
+        let mut input = String::from("Content-Type: x\r\n\r\n");
+        input.extend([
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": {"rootUri": format!("file://{}", root.display()),
+                    "initializationOptions": {"snippetDirectories": [root.join("snippets")]}}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didOpen",
+                "params": {"textDocument": {"uri": uri, "languageId": "lua", "version": 1, "text": "x\n"}}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didChange",
+                "params": {"textDocument": {"uri": uri, "version": 2},
+                    "contentChanges": [{"text": "local a\nif a then\n"}]}}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/inlayHint",
+                "params": {"textDocument": {"uri": uri},
+                    "range": {"start": {"line": 0, "character": 0}, "end": {"line": 2, "character": 0}}}}),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/completion",
+                "params": {"textDocument": {"uri": uri}, "position": {"line": 2, "character": 0}}}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "shutdown"}),
+            json!({"jsonrpc": "2.0", "id": 5, "method": "textDocument/completion",
+                "params": {"textDocument": {"uri": uri}, "position": {"line": 2, "character": 0}}})
+        ]
+        .into_iter()
+        .map(frame));
+        input.push_str("Content-Length: 3\r\n\r\n{x}");
+        input.push_str(&frame(json!({"jsonrpc": "2.0", "method": "exit"})));
+        let mut out = Vec::new();
+        let code = run(Cursor::new(input.into_bytes()), &mut out).unwrap();
+        assert_eq!(code, 0);
+        let rs = responses(&out);
+        assert_eq!(rs.len(), 6);
+        assert_eq!(rs[0]["result"]["capabilities"]["inlayHintProvider"], true);
+        assert_eq!(
+            rs[1]["result"],
+            json!([{"position": {"line": 1, "character": 9}, "label": "end", "paddingLeft": true}])
+        );
+        assert_eq!(rs[2]["result"][0]["label"], "if");
+        assert_eq!(
+            rs[2]["result"][0]["insertText"],
+            "if ${1:true} then\n\t$0\nend"
+        );
+        assert_eq!(rs[3]["result"], Value::Null);
+        assert_eq!(rs[4]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(rs[5]["error"]["code"], PARSE_ERROR);
+        assert_eq!(rs[5]["id"], Value::Null);
+    }
+
+    #[test]
+    fn completes_in_lsp_syntax() {
+        let tmp = TempDir::new("lsp-ultisnips");
+        let dir = tmp.path();
+        fs::write(
+            dir.join("lua.snippets"),
+            "snippet fn\nfunction ${1:name}(`!p snip.rv = 1`)\n\t${VISUAL}$0\nend\nendsnippet\n"
+        )
+        .unwrap();
+        let uri = "file:///a.lua";
+        let input: String = [
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize",
+                "params": {"initializationOptions": {"snippetDirectories": [dir]}}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didOpen",
+                "params": {"textDocument": {"uri": uri, "languageId": "lua", "version": 1, "text": ""}}}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/completion",
+                "params": {"textDocument": {"uri": uri}, "position": {"line": 0, "character": 0}}}),
+            json!({"jsonrpc": "2.0", "method": "exit"})
+        ]
+        .into_iter()
+        .map(frame)
+        .collect();
+        let mut out = Vec::new();
+        run(Cursor::new(input.into_bytes()), &mut out).unwrap();
+        let rs = responses(&out);
+        assert_eq!(rs[1]["result"][0]["label"], "fn");
+        assert_eq!(
+            rs[1]["result"][0]["insertText"],
+            "function ${1:name}()\n\t$TM_SELECTED_TEXT$0\nend"
+        );
+    }
+
+    #[test]
+    fn picks_up_new_snippet_files() {
+        use std::io::BufReader;
+        let tmp = TempDir::new("lsp-new");
+        let dir = tmp.path();
+        let (input, mut client) = std::io::pipe().unwrap();
+        let (output, server) = std::io::pipe().unwrap();
+        let lsp = std::thread::spawn(move || run(BufReader::new(input), server));
+        let mut output = BufReader::new(output);
+        let mut request = |v: Value| -> Option<Value> {
+            client.write_all(frame(v.clone()).as_bytes()).unwrap();
+            v.get("id")?;
+            serde_json::from_slice(&read_message(&mut output).unwrap().unwrap()).ok()
+        };
+        let uri = "file:///a.lua";
+        request(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize",
+            "params": {"initializationOptions": {"snippetDirectories": [dir]}}}));
+        request(json!({"jsonrpc": "2.0", "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "languageId": "lua", "version": 1, "text": ""}}}));
+        let completion = json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/completion",
+            "params": {"textDocument": {"uri": uri}, "position": {"line": 0, "character": 0}}});
+        assert_eq!(request(completion.clone()).unwrap()["result"], json!([]));
+        fs::write(
+            dir.join("lua.json"),
+            r#"{"if": {"prefix": "if", "body": "if"}}"#
+        )
+        .unwrap();
+        let found = request(completion).unwrap();
+        request(json!({"jsonrpc": "2.0", "method": "exit"}));
+        lsp.join().unwrap().unwrap();
+        assert_eq!(found["result"][0]["label"], "if");
+    }
+}
@@ -1,7 +1,232 @@
-use std::io::{stdin, stdout};
+use std::{
+    env,
+    io::{stdin, stdout, Read, Write},
+    path::Path,
+    process
+};
+use virtualsnip::{anchors, convert, explain, lint, source, suggest, usage, Encoding};
 
 fn main() {
-    let req = virtualsnip::read_request(stdin());
-    let resp = virtualsnip::calc(&req);
-    virtualsnip::write_response(stdout(), &resp);
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => run_once(Encoding::Json),
+        Some("--encoding") => run_once(
+            args.get(1)
+                .map(|s| s.parse().unwrap_or_else(|e: String| usage(&e)))
+                .unwrap_or_else(|| usage("--encoding takes json, msgpack or cbor"))
+        ),
+        Some("convert") => process::exit(run_convert(&args[1..])),
+        Some("lint") => process::exit(run_lint(&args[1..])),
+        Some("explain") => run_explain(&args[1..]),
+        Some("suggest") => process::exit(run_suggest(&args[1..])),
+        Some("record") => match &args[1..] {
+            [path, filetype, prefix] => {
+                let event = usage::Event::now(prefix.as_str(), filetype.as_str());
+                if let Err(e) = usage::record(Path::new(path), &event) {
+                    eprintln!("virtualsnip: {}: {}", path, e);
+                    process::exit(1)
+                }
+            }
+            _ => usage("record needs a usage file, a filetype and a prefix")
+        },
+        Some("learn") => match &args[1..] {
+            [path, filetype, prefix, before] => {
+                if let Err(e) = anchors::learn(Path::new(path), filetype, prefix, before) {
+                    eprintln!("virtualsnip: {}: {}", path, e);
+                    process::exit(1)
+                }
+            }
+            _ => usage("learn needs an anchors file, a filetype, a prefix and the text before it")
+        },
+        Some(x) => usage(&format!("unknown subcommand {}", x))
+    }
+}
+
+/// Answers one request read from stdin
+fn run_once(encoding: Encoding) {
+    let result = encoding.read_request(stdin()).and_then(|req| {
+        let resp = virtualsnip::calc(&req);
+        encoding.write_response(stdout(), &resp)
+    });
+    if let Err(e) = result {
+        eprintln!("virtualsnip: {}", e);
+        process::exit(1)
+    }
+}
+
+/// Explains the cursor line of the request read from stdin, or `--line`
+fn run_explain(args: &[String]) {
+    let line = match args {
+        [] => None,
+        [flag, n] if flag == "--line" => Some(
+            n.parse()
+                .unwrap_or_else(|_| usage("--line takes a line number"))
+        ),
+        _ => usage("explain takes only --line <line>")
+    };
+    let result = Encoding::Json.read_request(stdin()).and_then(|req| {
+        let e = explain::explain(&req, line.unwrap_or(req.cursor_line()))?;
+        serde_json::to_writer_pretty(stdout(), &e)?;
+        Ok(())
+    });
+    if let Err(e) = result {
+        eprintln!("virtualsnip: {}", e);
+        process::exit(1)
+    }
+}
+
+/// Reads every file, or stdin without files, and writes one file to stdout
+fn run_convert(args: &[String]) -> i32 {
+    let (mut from, mut to, mut paths) = (None, None, Vec::new());
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        let mut format = || {
+            it.next()
+                .map(|s| s.parse::<convert::Format>().unwrap_or_else(|e| usage(&e)))
+                .unwrap_or_else(|| usage(&format!("{} takes a format", a)))
+        };
+        match a.as_str() {
+            "--from" => from = Some(format()),
+            "--to" => to = Some(format()),
+            x if x.starts_with("--") => usage(&format!("unknown option {}", x)),
+            x => paths.push(Path::new(x))
+        }
+    }
+    let (from, to) = match (from, to) {
+        (Some(f), Some(t)) => (f, t),
+        _ => usage("convert needs --from and --to")
+    };
+    let mut code = 0;
+    let mut file = convert::File::default();
+    let inputs = if paths.is_empty() {
+        let mut s = String::new();
+        vec![(
+            "-".to_owned(),
+            stdin()
+                .read_to_string(&mut s)
+                .map_err(Into::into)
+                .and_then(|_| convert::read(&s, from))
+                .map_err(|e| e.to_string())
+        )]
+    } else {
+        paths
+            .iter()
+            .map(|p| {
+                let file = convert::read_file(p, from).map_err(|e| e.to_string());
+                (p.display().to_string(), file)
+            })
+            .collect()
+    };
+    for (path, input) in inputs {
+        match input {
+            Ok(f) => {
+                for w in f.warnings {
+                    eprintln!("virtualsnip: {}: {}", path, w);
+                }
+                file.extends.extend(f.extends);
+                file.snippets.extend(f.snippets);
+            }
+            Err(e) => {
+                eprintln!("virtualsnip: {}: {}", path, e);
+                code = 1;
+            }
+        }
+    }
+    code.max(write_file(&file, to))
+}
+
+/// Proposes snippets for what repeats within each file, or stdin without files
+fn run_suggest(args: &[String]) -> i32 {
+    let (mut to, mut paths) = (convert::Format::VsCode, Vec::new());
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "--to" => {
+                to = it
+                    .next()
+                    .map(|s| s.parse().unwrap_or_else(|e: String| usage(&e)))
+                    .unwrap_or_else(|| usage("--to takes a format"))
+            }
+            x if x.starts_with("--") => usage(&format!("unknown option {}", x)),
+            x => paths.push(Path::new(x))
+        }
+    }
+    let mut code = 0;
+    let texts = if paths.is_empty() {
+        let mut s = String::new();
+        vec![stdin()
+            .read_to_string(&mut s)
+            .map(|_| s)
+            .map_err(|e| ("-".to_owned(), e))]
+    } else {
+        paths
+            .iter()
+            .map(|p| std::fs::read_to_string(p).map_err(|e| (p.display().to_string(), e)))
+            .collect()
+    };
+    let mut file = convert::File::default();
+    for text in texts {
+        match text {
+            Ok(s) => {
+                let lines: Vec<&str> = s.lines().collect();
+                file.snippets
+                    .extend(suggest::to_file(&suggest::suggest(&lines)).snippets);
+            }
+            Err((path, e)) => {
+                eprintln!("virtualsnip: {}: {}", path, e);
+                code = 1;
+            }
+        }
+    }
+    code.max(write_file(&file, to))
+}
+
+/// Writes `file` to stdout with the warnings of `to` to stderr
+fn write_file(file: &convert::File, to: convert::Format) -> i32 {
+    let (out, warnings) = convert::write(file, to).unwrap_or_else(|e| usage(&e));
+    for w in warnings {
+        eprintln!("virtualsnip: {}", w);
+    }
+    match stdout().write_all(out.as_bytes()) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("virtualsnip: {}", e);
+            1
+        }
+    }
+}
+
+/// Directories are linted file by file
+fn run_lint(args: &[String]) -> i32 {
+    let mut deny_warnings = false;
+    let mut paths = Vec::new();
+    for a in args {
+        match a.as_str() {
+            "--deny-warnings" => deny_warnings = true,
+            x if x.starts_with("--") => usage(&format!("unknown option {}", x)),
+            x if Path::new(x).is_dir() => paths.extend(source::find_files(Path::new(x))),
+            x => paths.push(Path::new(x).to_path_buf())
+        }
+    }
+    if paths.is_empty() {
+        usage("lint needs files");
+    }
+    let mut failed = false;
+    for path in &paths {
+        for d in lint::lint_file(path) {
+            failed |= d.severity == lint::Severity::Error || deny_warnings;
+            println!("{}: {}", path.display(), d);
+        }
+    }
+    i32::from(failed)
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("virtualsnip: {}", msg);
+    eprintln!(
+        "usage: virtualsnip [--encoding <encoding> | explain [--line <line>] | convert --from <format> --to <format> [<file>...] | lint [--deny-warnings] <file>... | suggest [--to <format>] [<file>...] | record <usage file> <filetype> <prefix> | learn <anchors file> <filetype> <prefix> <text before>]"
+    );
+    eprintln!("encodings: json, msgpack, cbor");
+    eprintln!("formats: vscode, ultisnips, snipmate, textmate (read only)");
+    process::exit(2)
 }
@@ -0,0 +1,313 @@
+//! Neovim remote plugin speaking msgpack-RPC over stdio.
+//!
+//! Started with `jobstart([core, 'nvim'], {'rpc': v:true})`, it answers
+//! `rpcrequest`s instead of JSON lines:
+//!
+//! ```text
+//! initialize(hello) -> hello
+//! calc(request) -> response
+//! show(bufnr, ns_id, request) -> number of lines with a preview
+//! record(usage_file, filetype, prefix) -> nil
+//! learn(anchors_file, filetype, prefix, before) -> nil
+//! ```
+//!
+//! `show` replaces the extmarks of `ns_id` in `bufnr` by itself with one
+//! `nvim_call_atomic` notification, so Vim script never sees the response.
+use crate::{
+    anchors, calc_cancellable,
+    protocol::{Hello, Session},
+    source::Cache,
+    usage::{self, Event},
+    Request, Response, Text
+};
+use rmpv::{decode::read_value, encode::write_value, ext::from_value, Value};
+use std::{
+    io::{self, Read, Write},
+    sync::atomic::AtomicBool
+};
+
+const REQUEST: u64 = 0;
+const RESPONSE: u64 = 1;
+const NOTIFICATION: u64 = 2;
+
+#[derive(Default)]
+struct Host {
+    cache: Cache,
+    session: Session
+}
+
+/// Serves requests read from `r` until the channel is closed.
+pub fn run<R: Read, W: Write>(mut r: R, mut w: W) -> io::Result<()> {
+    let mut host = Host::default();
+    // EOF and garbage both end the session since msgpack can not resync
+    while let Ok(msg) = read_value(&mut r) {
+        let msg = match msg {
+            Value::Array(msg) => msg,
+            _ => continue
+        };
+        // Neither notifications nor responses to our notifications need an answer
+        if let (Some(REQUEST), Some(id), Some(method)) =
+            (msg.first().and_then(Value::as_u64), msg.get(1), msg.get(2))
+        {
+            let params = match msg.get(3) {
+                Some(Value::Array(params)) => params.as_slice(),
+                _ => &[]
+            };
+            let (error, result) = match host.handle(&mut w, method, params)? {
+                Ok(result) => (Value::Nil, result),
+                Err(message) => (Value::from(message), Value::Nil)
+            };
+            let reply = Value::Array(vec![RESPONSE.into(), id.clone(), error, result]);
+            send(&mut w, &reply)?;
+        }
+    }
+    Ok(())
+}
+
+impl Host {
+    fn handle<W: Write>(
+        &mut self,
+        w: &mut W,
+        method: &Value,
+        params: &[Value]
+    ) -> io::Result<Result<Value, String>> {
+        let method = method.as_str().unwrap_or_default();
+        Ok(match (method, params) {
+            ("initialize", [hello]) => from_value::<Hello>(hello.clone())
+                .map_err(|e| format!("Invalid hello: {}", e))
+                .and_then(|hello| hello.negotiate())
+                .map(|(hello, session)| {
+                    self.session = session;
+                    Value::Map(vec![
+                        ("version".into(), hello.version.into()),
+                        (
+                            "capabilities".into(),
+                            hello
+                                .capabilities
+                                .into_iter()
+                                .map(Value::from)
+                                .collect::<Vec<_>>()
+                                .into()
+                        ),
+                    ])
+                }),
+            ("calc", [req]) => request(req).map(|req| response(&self.calc(&req))),
+            ("show", [bufnr, ns, req]) => match request(req) {
+                Ok(req) => {
+                    let resp = self.calc(&req);
+                    send(w, &extmarks(bufnr, ns, &resp))?;
+                    Ok(resp.texts.len().into())
+                }
+                Err(e) => Err(e)
+            },
+            ("record", [path, filetype, prefix]) => {
+                match (path.as_str(), filetype.as_str(), prefix.as_str()) {
+                    (Some(path), Some(filetype), Some(prefix)) => {
+                        usage::record(path.as_ref(), &Event::now(prefix, filetype))
+                            .map(|_| Value::Nil)
+                            .map_err(|e| e.to_string())
+                    }
+                    _ => Err("record takes three strings".into())
+                }
+            }
+            ("learn", [path, filetype, prefix, before]) => match (
+                path.as_str(),
+                filetype.as_str(),
+                prefix.as_str(),
+                before.as_str()
+            ) {
+                (Some(path), Some(filetype), Some(prefix), Some(before)) => {
+                    anchors::learn(path.as_ref(), filetype, prefix, before)
+                        .map(|_| Value::Nil)
+                        .map_err(|e| e.to_string())
+                }
+                _ => Err("learn takes four strings".into())
+            },
+            ("initialize" | "calc" | "show" | "record" | "learn", _) => {
+                Err(format!("Wrong number of arguments for {}", method))
+            }
+            _ => Err(format!("Unknown method {}", method))
+        })
+    }
+
+    fn calc<'a>(&mut self, req: &'a Request) -> Response<'a> {
+        let never = AtomicBool::new(false);
+        let mut resp = calc_cancellable(req, &mut self.cache, &never).unwrap_or_default();
+        self.session.adapt(req, &mut resp);
+        resp
+    }
+}
+
+fn request(v: &Value) -> Result<Request, String> {
+    from_value::<Request>(v.clone())
+        .map_err(|e| format!("Invalid request: {}", e))
+        .and_then(|req| req.validate().map(|_| req).map_err(|e| e.to_string()))
+}
+
+/// `to_value` would turn structs into arrays but Vim script wants dictionaries
+fn response(resp: &Response<'_>) -> Value {
+    let texts = resp
+        .texts
+        .iter()
+        .map(|t| {
+            Value::Map(vec![
+                ("line".into(), t.line.into()),
+                ("chunks".into(), chunks(t)),
+            ])
+        })
+        .collect();
+    Value::Map(vec![("texts".into(), Value::Array(texts))])
+}
+
+fn chunks(t: &Text<'_>) -> Value {
+    t.chunks()
+        .map(|(text, hl)| Value::Array(vec![text.into(), hl.into()]))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// Clears `ns` in `bufnr` and shows every text at the end of its line
+fn extmarks(bufnr: &Value, ns: &Value, resp: &Response<'_>) -> Value {
+    let call = |name: &str, args: Vec<Value>| Value::Array(vec![name.into(), Value::Array(args)]);
+    let mut calls = vec![call(
+        "nvim_buf_clear_namespace",
+        vec![bufnr.clone(), ns.clone(), 0.into(), (-1).into()]
+    )];
+    for t in &resp.texts {
+        let opts = Value::Map(vec![("virt_text".into(), chunks(t))]);
+        calls.push(call(
+            "nvim_buf_set_extmark",
+            vec![bufnr.clone(), ns.clone(), t.line.into(), 0.into(), opts]
+        ));
+    }
+    Value::Array(vec![
+        NOTIFICATION.into(),
+        "nvim_call_atomic".into(),
+        Value::Array(vec![Value::Array(calls)]),
+    ])
+}
+
+fn send<W: Write>(w: &mut W, v: &Value) -> io::Result<()> {
+    write_value(w, v)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmpv::ext::to_value;
+    use std::io::Cursor;
+
+    fn world() -> Value {
+        let req = serde_json::json!({
+            "highlight": {"base": "Comment"}, "sign": " ", "lines": ["if a then"],
+            "start_line": 0, "cursor_line": 0,
+            "sources": [[{"body": ["if ${1:true} then", "\t$0", "end"]}]]
+        });
+        to_value(req).unwrap()
+    }
+
+    fn serve(msgs: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for m in msgs {
+            write_value(&mut input, m).unwrap();
+        }
+        let mut out = Vec::new();
+        run(Cursor::new(input), &mut out).unwrap();
+        let mut r = Cursor::new(out);
+        let mut outs = Vec::new();
+        while (r.position() as usize) < r.get_ref().len() {
+            outs.push(read_value(&mut r).unwrap());
+        }
+        outs
+    }
+
+    fn req(id: u64, method: &str, params: Vec<Value>) -> Value {
+        Value::Array(vec![
+            REQUEST.into(),
+            id.into(),
+            method.into(),
+            Value::Array(params),
+        ])
+    }
+
+    #[test]
+    fn answers_calc() {
+        let outs = serve(&[req(1, "calc", vec![world()])]);
+        let [out] = &outs[..] else {
+            panic!("{:?}", outs)
+        };
+        assert_eq!(out[0], Value::from(RESPONSE));
+        assert_eq!(out[1], Value::from(1));
+        assert_eq!(out[2], Value::Nil);
+        let resp: serde_json::Value = from_value(out[3].clone()).unwrap();
+        assert_eq!(
+            resp,
+            serde_json::json!({"texts": [{"line": 0, "chunks": [[" \nend", "Comment"]]}]})
+        );
+    }
+
+    #[test]
+    fn shows_extmarks() {
+        let outs = serve(&[req(2, "show", vec![3.into(), 7.into(), world()])]);
+        assert_eq!(outs.len(), 2);
+        assert_eq!(outs[0][0], Value::from(NOTIFICATION));
+        assert_eq!(outs[0][1], Value::from("nvim_call_atomic"));
+        let calls = outs[0][2][0].as_array().unwrap();
+        assert_eq!(calls[0][0], Value::from("nvim_buf_clear_namespace"));
+        assert_eq!(calls[1][0], Value::from("nvim_buf_set_extmark"));
+        assert_eq!(
+            calls[1][1],
+            Value::Array(vec![
+                3.into(),
+                7.into(),
+                0.into(),
+                0.into(),
+                Value::Map(vec![(
+                    "virt_text".into(),
+                    Value::Array(vec![Value::Array(vec![" \nend".into(), "Comment".into()])])
+                )])
+            ])
+        );
+        assert_eq!(
+            outs[1],
+            Value::Array(vec![RESPONSE.into(), 2.into(), Value::Nil, 1.into()])
+        );
+    }
+
+    #[test]
+    fn negotiates_capabilities() {
+        let hello = |version: u32| {
+            Value::Map(vec![
+                ("version".into(), version.into()),
+                ("capabilities".into(), Value::Array(vec![])),
+            ])
+        };
+        let outs = serve(&[
+            req(1, "initialize", vec![hello(2)]),
+            req(2, "initialize", vec![hello(1)]),
+            req(3, "calc", vec![world()])
+        ]);
+        assert!(outs[0][2].as_str().unwrap().contains("protocol version 2"));
+        assert_eq!(outs[1][3], hello(1));
+        assert_eq!(outs[2][3]["texts"][0]["chunks"][0][0], Value::from("  end"));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let outs = serve(&[
+            Value::Array(vec![
+                NOTIFICATION.into(),
+                "calc".into(),
+                Value::Array(vec![]),
+            ]),
+            req(1, "foo", vec![]),
+            req(2, "calc", vec![]),
+            req(3, "calc", vec![Value::from("x")])
+        ]);
+        let errors: Vec<_> = outs.iter().map(|o| o[2].as_str().unwrap()).collect();
+        assert_eq!(errors[0], "Unknown method foo");
+        assert_eq!(errors[1], "Wrong number of arguments for calc");
+        assert!(errors[2].starts_with("Invalid request"));
+    }
+}
@@ -0,0 +1,12 @@
+//! rayon's iterators when the `parallel` feature is on, plain iterators otherwise,
+//! so that callers are written once.
+#[cfg(feature = "parallel")]
+pub use rayon::prelude::*;
+
+#[cfg(not(feature = "parallel"))]
+pub trait IntoParallelIterator: IntoIterator + Sized {
+    fn into_par_iter(self) -> Self::IntoIter { self.into_iter() }
+}
+
+#[cfg(not(feature = "parallel"))]
+impl<I: IntoIterator> IntoParallelIterator for I {}
@@ -0,0 +1,180 @@
+//! Version and capabilities exchanged by `initialize` before the first `calc`.
+//!
+//! ```text
+//! -> {"id":1,"method":"initialize","params":{"version":1,"capabilities":["multiLinePreviews"]}}
+//! <- {"id":1,"result":{"version":1,"capabilities":["multiLinePreviews"]}}
+//! ```
+//!
+//! The result lists the capabilities both sides have; unknown ones are dropped
+//! so that newer clients keep working. A client may also ask for an
+//! `encoding` of the messages after the answer, like `"msgpack"`, which the
+//! answer repeats once the daemon has accepted it. A client speaking another version is
+//! refused with [`INCOMPATIBLE_VERSION`]. Clients that never say hello get
+//! every capability of the core, which is how the core behaved before.
+//! One-shot requests may carry a `version` instead, which is only checked.
+use crate::{Request, Response};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Bumped on changes that old clients or cores would misread
+pub const VERSION: u32 = 1;
+
+/// Error code of an `initialize` with another [`VERSION`]
+pub const INCOMPATIBLE_VERSION: i64 = -32001;
+
+/// Previews may span several lines, separated by `\n`
+pub const MULTI_LINE_PREVIEWS: &str = "multiLinePreviews";
+
+/// Previews may have several chunks, placeholders in the `placeholder` group
+/// of the request's `highlight`
+pub const PER_NODE_HIGHLIGHTS: &str = "perNodeHighlights";
+
+/// What this core can do
+pub const CAPABILITIES: &[&str] = &[MULTI_LINE_PREVIEWS, PER_NODE_HIGHLIGHTS];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Hello {
+    pub version: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capabilities: Vec<String>,
+    /// Name of an [`Encoding`](crate::Encoding); JSON if not given
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub encoding: Option<String>
+}
+
+/// What was agreed on; the default is what clients without a handshake get
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub multi_line_previews: bool,
+    pub per_node_highlights: bool
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            multi_line_previews: true,
+            per_node_highlights: true
+        }
+    }
+}
+
+impl Hello {
+    /// The greeting of this core
+    pub fn core() -> Self {
+        Self {
+            version: VERSION,
+            capabilities: CAPABILITIES.iter().map(|&c| c.to_owned()).collect(),
+            encoding: None
+        }
+    }
+
+    /// Answers the greeting of a client
+    pub fn negotiate(&self) -> Result<(Hello, Session), String> {
+        check_version(self.version)?;
+        let capabilities: Vec<String> = CAPABILITIES
+            .iter()
+            .filter(|c| self.capabilities.iter().any(|d| d == *c))
+            .map(|&c| c.to_owned())
+            .collect();
+        let has = |name: &str| capabilities.iter().any(|c| c == name);
+        let session = Session {
+            multi_line_previews: has(MULTI_LINE_PREVIEWS),
+            per_node_highlights: has(PER_NODE_HIGHLIGHTS)
+        };
+        Ok((
+            Hello {
+                version: VERSION,
+                capabilities,
+                encoding: None
+            },
+            session
+        ))
+    }
+}
+
+impl Session {
+    /// Rewrites `resp` to `req` for what the client can show
+    pub fn adapt(&self, req: &Request, resp: &mut Response<'_>) {
+        for t in &mut resp.texts {
+            if !self.per_node_highlights && t.chunks.len() > 1 {
+                let s: String = t.chunks.iter().map(|(s, _)| s.as_ref()).collect();
+                t.chunks = vec![(Cow::Owned(s), Cow::Owned(req.highlight.base.clone()))];
+            }
+            if self.multi_line_previews {
+                continue;
+            }
+            for (s, _) in &mut t.chunks {
+                if s.contains('\n') {
+                    *s = Cow::Owned(join_lines(s));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn check_version(version: u32) -> Result<(), String> {
+    if version == VERSION {
+        Ok(())
+    } else {
+        Err(format!(
+            "protocol version {} is not supported, the core speaks version {}",
+            version, VERSION
+        ))
+    }
+}
+
+/// `"a\n\tb"` becomes `"a b"`
+fn join_lines(s: &str) -> String {
+    let mut lines = s.split('\n');
+    let first = lines.next().unwrap_or_default().to_owned();
+    lines.fold(first, |acc, l| acc + " " + l.trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_negotiate() {
+        let client = Hello {
+            version: VERSION,
+            capabilities: vec![
+                "diffResponses".into(),
+                PER_NODE_HIGHLIGHTS.into(),
+                MULTI_LINE_PREVIEWS.into(),
+            ],
+            encoding: None
+        };
+        let (hello, session) = client.negotiate().unwrap();
+        assert_eq!(hello, Hello::core());
+        assert!(session.multi_line_previews && session.per_node_highlights);
+        let (hello, session) = Hello {
+            version: VERSION,
+            capabilities: vec![],
+            encoding: None
+        }
+        .negotiate()
+        .unwrap();
+        assert!(hello.capabilities.is_empty());
+        assert!(!session.multi_line_previews && !session.per_node_highlights);
+        let err = Hello {
+            version: VERSION + 1,
+            capabilities: vec![],
+            encoding: None
+        }
+        .negotiate()
+        .unwrap_err();
+        assert!(err.contains("protocol version 2"));
+    }
+
+    #[test]
+    fn can_join_lines() {
+        assert_eq!(join_lines(" \n\t$0\nend"), "  $0 end");
+        assert_eq!(join_lines("a"), "a");
+    }
+}
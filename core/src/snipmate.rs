@@ -0,0 +1,180 @@
+// https://github.com/garbas/vim-snipmate/blob/master/doc/SnipMate.txt
+// file      ::= (snippet | directive | comment | empty)*
+// snippet   ::= 'snippet' trigger description? '\n' ('\t' line '\n' | '\n')*
+// directive ::= 'extends' ft (',' ft)* | 'priority' int | 'version' int | 'delete' .*
+// Bodies are written like UltiSnips' except that every interpolation is Vim script.
+
+use crate::{ultisnips, Node, NodeInterpolation, NodePlaceholder, NodeVariable};
+
+pub use crate::ultisnips::Error;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct File {
+    pub extends: Vec<String>,
+    pub snippets: Vec<Snippet>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snippet {
+    pub trigger: String,
+    pub description: String,
+    pub priority: i64,
+    pub body: Vec<String>
+}
+
+pub fn parse(s: &str) -> Result<File, Error> {
+    let mut file = File::default();
+    let mut priority = 0;
+    let mut current: Option<Snippet> = None;
+    for (no, line) in s.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        let error = |message: String| Error { line: no, message };
+        if let Some(snip) = &mut current {
+            if let Some(l) = line.strip_prefix('\t') {
+                snip.body.push(l.to_owned());
+                continue;
+            }
+            if line.trim().is_empty() {
+                snip.body.push(String::new());
+                continue;
+            }
+            file.snippets.extend(current.take().map(finish));
+        }
+        let (head, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match head {
+            "" if line.trim().is_empty() => (),
+            _ if head.starts_with('#') => (),
+            "snippet" => {
+                let rest = rest.trim();
+                let (trigger, description) =
+                    rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                if trigger.is_empty() {
+                    return Err(error("missing trigger".into()));
+                }
+                current = Some(Snippet {
+                    trigger: trigger.to_owned(),
+                    description: description.trim().to_owned(),
+                    priority,
+                    body: Vec::new()
+                });
+            }
+            "extends" => file.extends.extend(
+                rest.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+            ),
+            "priority" => {
+                priority = rest
+                    .trim()
+                    .parse()
+                    .map_err(|_| error(format!("invalid priority {}", rest.trim())))?
+            }
+            "version" | "delete" => (),
+            _ => return Err(error(format!("unexpected {}", line)))
+        }
+    }
+    file.snippets.extend(current.map(finish));
+    Ok(file)
+}
+
+/// Blank lines separating snippets are not part of the body
+fn finish(mut snip: Snippet) -> Snippet {
+    while snip.body.last().is_some_and(|l| l.is_empty()) {
+        snip.body.pop();
+    }
+    snip
+}
+
+/// Never fails; whatever is not a construct is kept as text.
+pub fn parse_body(s: &str) -> Vec<Node> {
+    ultisnips::parse_body(s).into_iter().map(to_vim).collect()
+}
+
+/// The body as it is written, mirrors still empty
+#[cfg(feature = "serde")]
+pub(crate) fn parse_nodes(s: &str) -> Vec<Node> {
+    ultisnips::parse_nodes(s).into_iter().map(to_vim).collect()
+}
+
+fn to_vim(node: Node) -> Node {
+    match node {
+        Node::Interpolation(n) => Node::Interpolation(NodeInterpolation {
+            language: "vim".into(),
+            code: n.code
+        }),
+        Node::Placeholder(n) => Node::Placeholder(NodePlaceholder {
+            children: n.children.into_iter().map(to_vim).collect(),
+            ..n
+        }),
+        Node::Variable(n) => Node::Variable(NodeVariable {
+            children: n.children.into_iter().map(to_vim).collect(),
+            ..n
+        }),
+        n => n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NodeText;
+
+    #[test]
+    fn can_parse() {
+        let file = parse(
+            "# comment\nextends html, javascript\n\nsnippet for for loop\n\tfor (${1:i}) {\n\n\t\t${0}\n\t}\n\nsnippet date\n\t`strftime(\"%Y\")`\n",
+        )
+        .unwrap();
+        assert_eq!(file.extends, vec!["html", "javascript"]);
+        assert_eq!(
+            file.snippets,
+            vec![
+                Snippet {
+                    trigger: "for".into(),
+                    description: "for loop".into(),
+                    priority: 0,
+                    body: vec![
+                        "for (${1:i}) {".into(),
+                        "".into(),
+                        "\t${0}".into(),
+                        "}".into()
+                    ]
+                },
+                Snippet {
+                    trigger: "date".into(),
+                    description: "".into(),
+                    priority: 0,
+                    body: vec!["`strftime(\"%Y\")`".into()]
+                }
+            ]
+        );
+        assert_eq!(parse("snippet\n").unwrap_err().line, 1);
+        assert_eq!(parse("\tstray\n").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn can_parse_body() {
+        assert_eq!(
+            parse_body("${1:`expand('%')`} $1"),
+            vec![
+                Node::Placeholder(NodePlaceholder {
+                    index: Some(1),
+                    children: vec![Node::Interpolation(NodeInterpolation {
+                        language: "vim".into(),
+                        code: "expand('%')".into()
+                    })],
+                    ..Default::default()
+                }),
+                Node::Text(NodeText { value: " ".into() }),
+                Node::Placeholder(NodePlaceholder {
+                    index: Some(1),
+                    children: vec![Node::Interpolation(NodeInterpolation {
+                        language: "vim".into(),
+                        code: "expand('%')".into()
+                    })],
+                    ..Default::default()
+                })
+            ]
+        );
+    }
+}
@@ -0,0 +1,772 @@
+//! Snippet files in the VS Code format.
+//!
+//! ```json
+//! {
+//!   // comments and trailing commas are allowed
+//!   "If": { "prefix": "if", "body": ["if ${1:true} then", "\t$0", "end"], "scope": "lua" },
+//! }
+//! ```
+//!
+//! `*.code-snippets` declare their languages with `scope`, while `<filetype>.json`
+//! applies to the filetype in its name unless it is `global.json`.
+//!
+//! UltiSnips and SnipMate files, `<filetype>.snippets` or `<filetype>_<name>.snippets`,
+//! are also read. `all.snippets` and `_.snippets` apply to every filetype. A
+//! `.snippets` file is UltiSnips' if it has `endsnippet`.
+//!
+//! TextMate snippets, one per `*.tmSnippet`, are read from the directory or from
+//! `Snippets/` of a `.tmbundle`. Their scope selectors are mapped to filetypes.
+use crate::{anchors::Anchors, nodes, par::*, snipmate, textmate, ultisnips, usage::Usage, Node};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnippetDef {
+    pub name: String,
+    pub prefix: Vec<String>,
+    pub body: Vec<String>,
+    pub description: String,
+    /// Language ids; empty means every language
+    pub scope: Vec<String>,
+    pub syntax: Syntax,
+    /// Hides snippets with the same prefix and lower priorities
+    pub priority: i64
+}
+
+/// How a body is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// https://github.com/Microsoft/language-server-protocol/blob/main/snippetSyntax.md
+    #[default]
+    Lsp,
+    UltiSnips,
+    SnipMate
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SnippetFile {
+    pub snippets: Vec<SnippetDef>,
+    /// Filetype implied by the file name
+    pub scope: Option<String>,
+    /// Filetypes whose snippets are also used for `scope`
+    pub extends: Vec<String>,
+    /// Why parts of the file were skipped
+    pub warnings: Vec<String>
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Deserialize)]
+struct Raw {
+    #[serde(default)]
+    prefix: OneOrMany,
+    body: OneOrMany,
+    #[serde(default)]
+    description: OneOrMany,
+    #[serde(default)]
+    scope: String
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>)
+}
+
+#[cfg(feature = "serde")]
+impl Default for OneOrMany {
+    fn default() -> Self { OneOrMany::Many(Vec::new()) }
+}
+
+#[cfg(feature = "serde")]
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(xs) => xs
+        }
+    }
+
+    /// A body given as one string still has to become lines
+    fn into_lines(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => s.split('\n').map(String::from).collect(),
+            OneOrMany::Many(xs) => xs
+        }
+    }
+}
+
+impl SnippetDef {
+    /// Malformed parts of the body are text
+    pub fn nodes(&self) -> Vec<Node> {
+        match self.syntax {
+            Syntax::Lsp => nodes(&self.body),
+            Syntax::UltiSnips => ultisnips::parse_body(&self.body.join("\n")),
+            Syntax::SnipMate => snipmate::parse_body(&self.body.join("\n"))
+        }
+    }
+
+    pub fn is_in_scope(&self, language_id: &str) -> bool {
+        self.scope.is_empty() || self.scope.iter().any(|s| s == language_id)
+    }
+}
+
+#[cfg(feature = "serde")]
+pub fn parse_json(s: &str) -> serde_json::Result<Vec<SnippetDef>> {
+    let raw: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&strip_jsonc(s))?;
+    let mut defs = Vec::with_capacity(raw.len());
+    for (name, v) in raw {
+        let r: Raw = serde_json::from_value(v)?;
+        defs.push(SnippetDef {
+            name,
+            prefix: r.prefix.into_vec(),
+            body: r.body.into_lines(),
+            description: r.description.into_vec().join("\n"),
+            scope: r
+                .scope
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect(),
+            syntax: Syntax::Lsp,
+            priority: 0
+        });
+    }
+    Ok(defs)
+}
+
+pub fn read_file(path: &Path) -> io::Result<SnippetFile> {
+    let s = fs::read_to_string(path)?;
+    let invalid =
+        |e: Box<dyn std::error::Error + Send + Sync>| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut file = if path
+        .extension()
+        .is_some_and(|e| e == "tmSnippet" || e == "plist")
+    {
+        from_textmate(textmate::parse(&s).map_err(|e| invalid(e.into()))?)
+    } else if path.extension().is_some_and(|e| e == "snippets") {
+        if s.lines().any(|l| l.trim_end() == "endsnippet") {
+            from_ultisnips(ultisnips::parse(&s).map_err(|e| invalid(e.into()))?)
+        } else {
+            from_snipmate(snipmate::parse(&s).map_err(|e| invalid(e.into()))?)
+        }
+    } else {
+        SnippetFile {
+            snippets: from_json(&s)?,
+            ..SnippetFile::default()
+        }
+    };
+    file.scope = implied_scope(path).map(String::from);
+    if let Some(lang) = &file.scope {
+        for d in file.snippets.iter_mut().filter(|d| d.scope.is_empty()) {
+            d.scope.push(lang.clone());
+        }
+    }
+    Ok(file)
+}
+
+#[cfg(feature = "serde")]
+fn from_json(s: &str) -> io::Result<Vec<SnippetDef>> {
+    parse_json(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(feature = "serde"))]
+fn from_json(_: &str) -> io::Result<Vec<SnippetDef>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "VS Code snippet files need the serde feature"
+    ))
+}
+
+fn from_ultisnips(file: ultisnips::File) -> SnippetFile {
+    let snippets = file
+        .snippets
+        .into_iter()
+        .map(|s| SnippetDef {
+            name: s.trigger.clone(),
+            prefix: vec![s.trigger],
+            body: s.body,
+            description: s.description,
+            scope: Vec::new(),
+            syntax: Syntax::UltiSnips,
+            priority: s.priority
+        })
+        .collect();
+    SnippetFile {
+        snippets,
+        scope: None,
+        extends: file.extends,
+        warnings: file.warnings.iter().map(ToString::to_string).collect()
+    }
+}
+
+fn from_snipmate(file: snipmate::File) -> SnippetFile {
+    let snippets = file
+        .snippets
+        .into_iter()
+        .map(|s| SnippetDef {
+            name: s.trigger.clone(),
+            prefix: vec![s.trigger],
+            body: s.body,
+            description: s.description,
+            scope: Vec::new(),
+            syntax: Syntax::SnipMate,
+            priority: s.priority
+        })
+        .collect();
+    SnippetFile {
+        snippets,
+        extends: file.extends,
+        ..SnippetFile::default()
+    }
+}
+
+fn from_textmate(snip: textmate::Snippet) -> SnippetFile {
+    let scope = snip.filetypes();
+    SnippetFile {
+        snippets: vec![SnippetDef {
+            name: if snip.name.is_empty() {
+                snip.tab_trigger.clone()
+            } else {
+                snip.name
+            },
+            prefix: vec![snip.tab_trigger],
+            body: snip.content.split('\n').map(String::from).collect(),
+            description: String::new(),
+            scope,
+            syntax: Syntax::Lsp,
+            priority: 0
+        }],
+        ..SnippetFile::default()
+    }
+}
+
+pub(crate) fn implied_scope(path: &Path) -> Option<&str> {
+    let stem = path.file_stem()?.to_str()?;
+    match path.extension()?.to_str()? {
+        "json" => Some(stem).filter(|s| *s != "global"),
+        "snippets" => stem
+            .split('_')
+            .next()
+            .filter(|s| !s.is_empty() && *s != "all"),
+        _ => None
+    }
+}
+
+/// `*.json`, `*.code-snippets`, `*.snippets` and `*.tmSnippet` directly under `dir`,
+/// sorted by name. A `.tmbundle` is searched in its `Snippets/`.
+pub fn find_files(dir: &Path) -> Vec<PathBuf> {
+    let in_bundle = is_bundle(dir);
+    let entries = match fs::read_dir(snippet_dir(dir)) {
+        Ok(es) => es,
+        Err(_) => return Vec::new()
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.file_name().is_some_and(|n| n != "package.json"))
+        .filter(|p| {
+            p.extension().is_some_and(|e| {
+                e == "json"
+                    || e == "code-snippets"
+                    || e == "snippets"
+                    || e == "tmSnippet"
+                    || (in_bundle && e == "plist")
+            })
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// The directory whose files [`find_files`] lists
+pub fn snippet_dir(dir: &Path) -> PathBuf {
+    if is_bundle(dir) {
+        dir.join("Snippets")
+    } else {
+        dir.to_owned()
+    }
+}
+
+fn is_bundle(dir: &Path) -> bool { dir.extension().is_some_and(|e| e == "tmbundle") }
+
+/// A snippet with its body parsed once when the file is read
+#[derive(Debug)]
+pub struct Entry {
+    pub def: SnippetDef,
+    pub nodes: Vec<Node>
+}
+
+/// Parsed snippet files, and the usage and anchors files ranking them.
+///
+/// By default an entry is reread when the modification time of its file changes.
+/// A watched cache trusts its entries and directory listings until
+/// [`Cache::invalidate`] is called for them. The usage and anchors files are
+/// always reread when their modification time or length changes, since they
+/// are written from elsewhere.
+#[derive(Debug, Default)]
+pub struct Cache {
+    files: HashMap<PathBuf, CachedFile>,
+    dirs: HashMap<PathBuf, Vec<PathBuf>>,
+    usage: HashMap<PathBuf, Stamped<Usage>>,
+    anchors: HashMap<PathBuf, Stamped<Anchors>>,
+    watched: bool
+}
+
+#[derive(Debug)]
+struct Stamped<T> {
+    stamp: Option<(SystemTime, u64)>,
+    value: Arc<T>
+}
+
+#[derive(Debug)]
+struct CachedFile {
+    modified: Option<SystemTime>,
+    scope: Option<String>,
+    extends: Vec<String>,
+    entries: Vec<Entry>
+}
+
+impl Cache {
+    pub fn watched() -> Self {
+        Cache {
+            watched: true,
+            ..Cache::default()
+        }
+    }
+
+    /// Snippet files in `dir` like [`find_files`]
+    pub fn find_files(&mut self, dir: &Path) -> Vec<PathBuf> {
+        if !self.watched {
+            return find_files(dir);
+        }
+        self.dirs
+            .entry(snippet_dir(dir))
+            .or_insert_with(|| find_files(dir))
+            .clone()
+    }
+
+    pub fn load(&mut self, files: &[PathBuf]) -> Vec<&Entry> {
+        for path in files {
+            if self.watched && self.files.contains_key(path) {
+                continue;
+            }
+            let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+            let fresh = self
+                .files
+                .get(path)
+                .is_some_and(|c| c.modified.is_some() && c.modified == modified);
+            if fresh {
+                continue;
+            }
+            // A broken file is cached as empty so that it is reported only once per change
+            let file = read_file(path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path.display(), e);
+                SnippetFile::default()
+            });
+            let entries = file
+                .snippets
+                .into_par_iter()
+                .map(|def| Entry {
+                    nodes: def.nodes(),
+                    def
+                })
+                .collect();
+            self.files.insert(
+                path.clone(),
+                CachedFile {
+                    modified,
+                    scope: file.scope,
+                    extends: file.extends,
+                    entries
+                }
+            );
+        }
+        files
+            .iter()
+            .filter_map(|p| self.files.get(p))
+            .flat_map(|c| c.entries.iter())
+            .collect()
+    }
+
+    /// Snippets in `files` for any of `filetypes` or the filetypes they extend.
+    /// Of the snippets sharing a prefix, only those with the highest priority are kept.
+    pub fn select(&mut self, files: &[PathBuf], filetypes: &[String]) -> Vec<&Entry> {
+        self.load(files);
+        let mut fts: Vec<&str> = filetypes.iter().map(String::as_str).collect();
+        let mut i = 0;
+        while i < fts.len() {
+            for c in files.iter().filter_map(|p| self.files.get(p)) {
+                if c.scope.as_deref() == Some(fts[i]) {
+                    for e in &c.extends {
+                        if !fts.contains(&e.as_str()) {
+                            fts.push(e);
+                        }
+                    }
+                }
+            }
+            i += 1;
+        }
+        let selected: Vec<&Entry> = files
+            .iter()
+            .filter_map(|p| self.files.get(p))
+            .flat_map(|c| c.entries.iter())
+            .filter(|e| e.def.scope.is_empty() || fts.iter().any(|f| e.def.is_in_scope(f)))
+            .collect();
+        let mut highest: HashMap<&str, i64> = HashMap::new();
+        for e in &selected {
+            if let Some(p) = e.def.prefix.first() {
+                let h = highest.entry(p).or_insert(e.def.priority);
+                *h = (*h).max(e.def.priority);
+            }
+        }
+        selected
+            .into_iter()
+            .filter(|e| {
+                e.def
+                    .prefix
+                    .first()
+                    .is_none_or(|p| highest[p.as_str()] == e.def.priority)
+            })
+            .collect()
+    }
+
+    /// The usage file at `path`; one that can not be read has no usage
+    pub fn usage(&mut self, path: &Path) -> Arc<Usage> {
+        reload(&mut self.usage, path, Usage::load)
+    }
+
+    /// The anchors file at `path`; one that can not be read has no anchors
+    pub fn anchors(&mut self, path: &Path) -> Arc<Anchors> {
+        reload(&mut self.anchors, path, Anchors::load)
+    }
+
+    /// Forgets changed files and the listings of the directories containing them
+    pub fn invalidate(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            self.files.remove(path);
+            self.dirs.remove(path);
+            if let Some(parent) = path.parent() {
+                self.dirs.remove(parent);
+            }
+        }
+    }
+}
+
+fn reload<T: Default>(
+    files: &mut HashMap<PathBuf, Stamped<T>>,
+    path: &Path,
+    load: fn(&Path) -> io::Result<T>
+) -> Arc<T> {
+    let stamp = fs::metadata(path)
+        .and_then(|m| Ok((m.modified()?, m.len())))
+        .ok();
+    if let Some(f) = files
+        .get(path)
+        .filter(|f| f.stamp.is_some() && f.stamp == stamp)
+    {
+        return Arc::clone(&f.value);
+    }
+    let value = Arc::new(load(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path.display(), e);
+        T::default()
+    }));
+    files.insert(
+        path.to_owned(),
+        Stamped {
+            stamp,
+            value: Arc::clone(&value)
+        }
+    );
+    value
+}
+
+/// Blanks out comments and trailing commas, keeping line and column positions.
+pub fn strip_jsonc(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    let mut in_string = false;
+    // byte offset in `out` of a comma that may turn out to be trailing
+    let mut comma: Option<usize> = None;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => ()
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    chars.next();
+                    out.push(' ');
+                }
+                out.push(' ');
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                out.push_str("  ");
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    out.push(if c == '\n' { '\n' } else { ' ' });
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            (']' | '}', _) => {
+                if let Some(i) = comma.take() {
+                    out.replace_range(i..i + 1, " ");
+                }
+                out.push(c);
+            }
+            (',', _) => {
+                comma = Some(out.len());
+                out.push(c);
+            }
+            _ => {
+                if !c.is_whitespace() {
+                    comma = None;
+                }
+                if c == '"' {
+                    in_string = true;
+                }
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn can_parse_json() {
+        let defs = parse_json(
+            r#"{
+                "If": {"prefix": "if", "body": ["if ${1:true} then", "end"], "scope": "lua, moon"},
+                "Print": {"prefix": ["p", "print"], "body": "print($0)\n", "description": "print"}
+            }"#
+        )
+        .unwrap();
+        assert_eq!(
+            defs,
+            vec![
+                SnippetDef {
+                    name: "If".into(),
+                    prefix: vec!["if".into()],
+                    body: vec!["if ${1:true} then".into(), "end".into()],
+                    description: "".into(),
+                    scope: vec!["lua".into(), "moon".into()],
+                    syntax: Syntax::Lsp,
+                    priority: 0
+                },
+                SnippetDef {
+                    name: "Print".into(),
+                    prefix: vec!["p".into(), "print".into()],
+                    body: vec!["print($0)".into(), "".into()],
+                    description: "print".into(),
+                    scope: vec![],
+                    syntax: Syntax::Lsp,
+                    priority: 0
+                }
+            ]
+        );
+        assert!(defs[0].is_in_scope("moon"));
+        assert!(!defs[0].is_in_scope("rust"));
+        assert!(defs[1].is_in_scope("rust"));
+    }
+
+    #[test]
+    fn can_strip_jsonc() {
+        assert_eq!(
+            strip_jsonc("{\"a//b\": [1, 2,], // c\n/* d\n */\"e,\": \"\\\"}\",}"),
+            "{\"a//b\": [1, 2 ],     \n    \n   \"e,\": \"\\\"}\" }"
+        );
+        #[cfg(feature = "serde")]
+        {
+            let defs = parse_json(
+                r#"{
+                // comment
+                "If": {
+                    "prefix": "if",
+                    "body": ["if ${1:true} then", "end"], /* trailing */
+                },
+            }"#
+            )
+            .unwrap();
+            assert_eq!(defs[0].body, vec!["if ${1:true} then", "end"]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn can_invalidate() {
+        let tmp = TempDir::new("watched");
+        let dir = tmp.path();
+        let file = dir.join("lua.json");
+        fs::write(&file, r#"{"a": {"body": "a"}}"#).unwrap();
+        let mut cache = Cache::watched();
+        let files = cache.find_files(dir);
+        assert_eq!(cache.load(&files)[0].def.name, "a");
+        fs::write(&file, r#"{"b": {"body": "b"}}"#).unwrap();
+        fs::write(dir.join("rust.json"), r#"{"c": {"body": "c"}}"#).unwrap();
+        let files = cache.find_files(dir);
+        assert_eq!(files, vec![file.clone()]);
+        assert_eq!(cache.load(&files)[0].def.name, "a");
+        cache.invalidate(std::slice::from_ref(&file));
+        let files = cache.find_files(dir);
+        let names: Vec<_> = cache
+            .load(&files)
+            .iter()
+            .map(|e| e.def.name.clone())
+            .collect();
+        assert_eq!(names, vec!["b", "c"]);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn can_select() {
+        let tmp = TempDir::new("select");
+        let dir = tmp.path();
+        fs::write(
+            dir.join("cpp.snippets"),
+            "extends c\npriority 10\nsnippet if \"cpp if\"\nif (${1:cond}) `!p snip.rv = 1`\nendsnippet\n"
+        )
+        .unwrap();
+        fs::write(
+            dir.join("c.json"),
+            r#"{"if": {"prefix": "if", "body": "if"}, "for": {"prefix": "for", "body": "for"}}"#
+        )
+        .unwrap();
+        fs::write(
+            dir.join("all.snippets"),
+            "snippet date\n`date`\nendsnippet\n"
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rust_test.snippets"),
+            "snippet test\n#[test]\nendsnippet\n"
+        )
+        .unwrap();
+        let files = find_files(dir);
+        let mut cache = Cache::default();
+        let names = |cache: &mut Cache, ft: &str| -> Vec<(String, String)> {
+            cache
+                .select(&files, &[ft.to_owned()])
+                .iter()
+                .map(|e| (e.def.name.clone(), e.def.description.clone()))
+                .collect()
+        };
+        let cpp = names(&mut cache, "cpp");
+        let c = names(&mut cache, "c");
+        let rust = names(&mut cache, "rust");
+        let entries = cache.select(&files, &["cpp".to_owned()]);
+        let if_nodes = entries
+            .iter()
+            .find(|e| e.def.name == "if")
+            .unwrap()
+            .nodes
+            .clone();
+        let own = |a: &str, b: &str| (a.to_owned(), b.to_owned());
+        assert_eq!(
+            cpp,
+            vec![own("date", ""), own("for", ""), own("if", "cpp if")]
+        );
+        assert_eq!(c, vec![own("date", ""), own("for", ""), own("if", "")]);
+        assert_eq!(rust, vec![own("date", ""), own("test", "")]);
+        assert_eq!(if_nodes.len(), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn can_cache() {
+        let tmp = TempDir::new("source");
+        let dir = tmp.path();
+        fs::write(
+            dir.join("lua.json"),
+            r#"{"if": {"prefix": "if", "body": "if"}}"#
+        )
+        .unwrap();
+        fs::write(
+            dir.join("all.code-snippets"),
+            r#"{"p": {"prefix": "p", "body": "print"}}"#
+        )
+        .unwrap();
+        fs::write(dir.join("package.json"), "{}").unwrap();
+        fs::write(dir.join("global.json"), r#"{"g": {"body": "g"}}"#).unwrap();
+        let files = find_files(dir);
+        let mut cache = Cache::default();
+        let entries = cache.load(&files);
+        assert!(entries.iter().all(|e| !e.nodes.is_empty()));
+        let scopes: Vec<_> = entries
+            .iter()
+            .map(|e| (e.def.name.as_str(), e.def.scope.clone()))
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![("p", vec![]), ("g", vec![]), ("if", vec!["lua".to_owned()])]
+        );
+    }
+
+    #[test]
+    fn can_cache_usage() {
+        use crate::usage::{record, Event};
+        let tmp = TempDir::new("cached");
+        let dir = tmp.path();
+        let path = dir.join("usage.tsv");
+        let lua = ["lua".to_owned()];
+        let mut cache = Cache::default();
+        assert_eq!(cache.usage(&path).score(&lua, &["if"], 0), 0.);
+        record(&path, &Event::now("if", "lua")).unwrap();
+        let first = cache.usage(&path);
+        let again = cache.usage(&path);
+        record(&path, &Event::now("for", "lua")).unwrap();
+        let changed = cache.usage(&path);
+        assert!(Arc::ptr_eq(&first, &again));
+        assert!(first.score(&lua, &["if"], 0) > 0.);
+        assert!(changed.score(&lua, &["for"], 0) > 0.);
+    }
+
+    #[test]
+    fn can_read_bundle() {
+        let tmp = TempDir::new("bundle");
+        let bundle = tmp.path().join("Ruby.tmbundle");
+        fs::create_dir_all(bundle.join("Snippets")).unwrap();
+        fs::write(
+            bundle.join("Snippets/if.tmSnippet"),
+            "{ content = 'if ${1:cond}\n\t$0\nend'; tabTrigger = if; scope = 'source.ruby'; }"
+        )
+        .unwrap();
+        fs::write(bundle.join("info.plist"), "{ name = Ruby; }").unwrap();
+        let files = find_files(&bundle);
+        let file = read_file(&files[0]);
+        assert_eq!(files.len(), 1);
+        let def = &file.unwrap().snippets[0];
+        assert_eq!(def.name, "if");
+        assert_eq!(def.prefix, vec!["if"]);
+        assert_eq!(def.body, vec!["if ${1:cond}", "\t$0", "end"]);
+        assert!(def.is_in_scope("ruby") && !def.is_in_scope("lua"));
+        assert!(!def.nodes().is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+//! Snippets proposed from code that repeats in a buffer.
+//!
+//! Windows of consecutive lines are compared by their shape: the indentation
+//! and punctuation must be the same while the words may differ. A shape seen
+//! at several places that do not overlap becomes a snippet whose words that
+//! differ between the places are placeholders, defaulting to the first place.
+//! Words that differ in the same way at every place are one tab stop, so a
+//! name used twice is typed once.
+//!
+//! Windows covering the most lines are preferred over those overlapping them,
+//! and a window that only repeats a smaller one, like two functions of a run
+//! of four, is skipped.
+use crate::vs_snippet::{Any, Ast};
+use std::collections::HashMap;
+
+/// Shortest window worth a snippet
+pub const MIN_LINES: usize = 2;
+/// Longest window compared
+pub const MAX_LINES: usize = 12;
+/// Places a shape must be seen at
+pub const MIN_OCCURRENCES: usize = 2;
+
+#[derive(Debug, PartialEq)]
+pub struct Suggestion {
+    /// First lines of the places, 0-based
+    pub occurrences: Vec<usize>,
+    /// Number of lines of each place
+    pub lines: usize,
+    pub body: Ast<'static>
+}
+
+/// A line split into words and what is between them
+struct Line<'a> {
+    indent: &'a str,
+    tokens: Vec<(bool, &'a str)>
+}
+
+/// Indentation relative to the first line and the text between words
+type Shape<'a> = Vec<(&'a str, Vec<Option<&'a str>>)>;
+
+impl Suggestion {
+    /// The first word that is the same at every place, for a prefix
+    pub fn keyword(&self) -> Option<&str> {
+        self.body.0.iter().find_map(|x| match x {
+            Any::Text(t) => t.split(|c| !is_word(c)).find(|w| !w.is_empty()),
+            _ => None
+        })
+    }
+}
+
+pub fn suggest<S: AsRef<str>>(lines: &[S]) -> Vec<Suggestion> {
+    let lines: Vec<Line<'_>> = lines.iter().map(|l| line(l.as_ref())).collect();
+    let mut found = Vec::new();
+    for len in MIN_LINES..=MAX_LINES.min(lines.len()) {
+        let mut groups: HashMap<Shape<'_>, Vec<usize>> = HashMap::new();
+        for start in 0..=lines.len() - len {
+            if let Some(shape) = shape(&lines[start..start + len]) {
+                let starts = groups.entry(shape).or_default();
+                if starts.last().is_none_or(|&s| s + len <= start) {
+                    starts.push(start);
+                }
+            }
+        }
+        found.extend(
+            groups
+                .into_values()
+                .filter(|starts| starts.len() >= MIN_OCCURRENCES)
+                .filter_map(|starts| template(&lines, starts, len))
+        );
+    }
+    // Most lines covered first, then longer windows
+    found.sort_by_key(|s| {
+        (
+            std::cmp::Reverse((s.lines * s.occurrences.len(), s.lines)),
+            s.occurrences[0]
+        )
+    });
+    let mut taken: Vec<(usize, usize)> = Vec::new();
+    let mut kept = Vec::new();
+    for s in found {
+        let overlaps = |&o: &usize| taken.iter().any(|&(a, b)| o < b && a < o + s.lines);
+        if s.occurrences.iter().any(overlaps) {
+            continue;
+        }
+        taken.extend(s.occurrences.iter().map(|&o| (o, o + s.lines)));
+        kept.push(s);
+    }
+    kept.sort_by_key(|s| s.occurrences[0]);
+    kept
+}
+
+fn is_word(c: char) -> bool { c == '_' || c.is_alphanumeric() }
+
+fn line(s: &str) -> Line<'_> {
+    let rest = s.trim_start();
+    let indent = &s[..s.len() - rest.len()];
+    let mut rest = rest.trim_end();
+    let mut tokens = Vec::new();
+    while let Some(c) = rest.chars().next() {
+        let word = is_word(c);
+        let end = rest.find(|c| is_word(c) != word).unwrap_or(rest.len());
+        tokens.push((word, &rest[..end]));
+        rest = &rest[end..];
+    }
+    Line { indent, tokens }
+}
+
+/// `None` for windows starting or ending with a blank line or going left of
+/// their first line
+fn shape<'a>(window: &[Line<'a>]) -> Option<Shape<'a>> {
+    let (first, last) = (window.first()?, window.last()?);
+    if first.tokens.is_empty() || last.tokens.is_empty() {
+        return None;
+    }
+    let shape: Shape<'a> = window
+        .iter()
+        .map(|l| {
+            if l.tokens.is_empty() {
+                return Some(("", Vec::new()));
+            }
+            let indent = l.indent.strip_prefix(first.indent)?;
+            let tokens = l.tokens.iter().map(|&(w, t)| (!w).then_some(t)).collect();
+            Some((indent, tokens))
+        })
+        .collect::<Option<_>>()?;
+    let periodic = (1..shape.len())
+        .filter(|&p| shape.len().is_multiple_of(p))
+        .any(|p| (p..shape.len()).all(|i| shape[i] == shape[i - p]));
+    (!periodic).then_some(shape)
+}
+
+/// `None` when more words differ than stay the same, which is more likely a
+/// coincidence of punctuation than a pattern
+fn template(lines: &[Line<'_>], occurrences: Vec<usize>, len: usize) -> Option<Suggestion> {
+    let words = |start: usize| -> Vec<&str> {
+        lines[start..start + len]
+            .iter()
+            .flat_map(|l| l.tokens.iter().filter(|t| t.0).map(|t| t.1))
+            .collect()
+    };
+    let places: Vec<Vec<&str>> = occurrences.iter().map(|&o| words(o)).collect();
+    let column = |i: usize| -> Vec<&str> { places.iter().map(|p| p[i]).collect() };
+    let mut stops: Vec<Vec<&str>> = Vec::new();
+    let mut constants = 0;
+    let mut body: Vec<Any<'static>> = Vec::new();
+    let mut slot = 0;
+    let first = &lines[occurrences[0]..occurrences[0] + len];
+    for (i, l) in first.iter().enumerate() {
+        if i > 0 {
+            push_text(&mut body, "\n");
+        }
+        if !l.tokens.is_empty() {
+            push_text(&mut body, &l.indent[first[0].indent.len()..]);
+        }
+        for &(word, t) in &l.tokens {
+            if !word {
+                push_text(&mut body, t);
+                continue;
+            }
+            let values = column(slot);
+            slot += 1;
+            if values.iter().all(|&v| v == t) {
+                constants += 1;
+                push_text(&mut body, t);
+            } else if let Some(n) = stops.iter().position(|s| *s == values) {
+                body.push(Any::TabStop(n + 1));
+            } else {
+                stops.push(values);
+                body.push(Any::Placeholder(stops.len(), vec![Any::Text(t.to_owned())]));
+            }
+        }
+    }
+    (constants > 0 && stops.len() <= constants).then_some(Suggestion {
+        occurrences,
+        lines: len,
+        body: Ast(body)
+    })
+}
+
+fn push_text(body: &mut Vec<Any<'static>>, s: &str) {
+    match body.last_mut() {
+        Some(Any::Text(t)) => t.push_str(s),
+        _ => body.push(Any::Text(s.to_owned()))
+    }
+}
+
+/// Suggestions as snippets named after their first word, numbered from the
+/// second one with the same word on like `local`, `local2`
+#[cfg(feature = "serde")]
+pub fn to_file(suggestions: &[Suggestion]) -> crate::convert::File {
+    use crate::{convert, node_from_ast};
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let snippets = suggestions
+        .iter()
+        .map(|s| {
+            let keyword = s.keyword().unwrap_or("snippet");
+            let n = seen.entry(keyword).or_insert(0);
+            *n += 1;
+            let prefix = match *n {
+                1 => keyword.to_owned(),
+                n => format!("{}{}", keyword, n)
+            };
+            let at: Vec<String> = s.occurrences.iter().map(|o| (o + 1).to_string()).collect();
+            convert::Snippet {
+                name: prefix.clone(),
+                prefix: vec![prefix],
+                description: format!("Repeated at lines {}", at.join(", ")),
+                scope: Vec::new(),
+                options: String::new(),
+                priority: 0,
+                body: s.body.0.iter().map(node_from_ast).collect(),
+                syntax: crate::source::Syntax::Lsp
+            }
+        })
+        .collect();
+    convert::File {
+        snippets,
+        ..convert::File::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bodies(lines: &[&str]) -> Vec<(Vec<usize>, String)> {
+        suggest(lines)
+            .into_iter()
+            .map(|s| (s.occurrences, s.body.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn can_suggest() {
+        let lines = [
+            "local function add(a, b)",
+            "  return a + b",
+            "end",
+            "",
+            "local function sub(x, y)",
+            "  return x + y",
+            "end",
+            "print(add(1, 2))"
+        ];
+        assert_eq!(
+            bodies(&lines),
+            vec![(
+                vec![0, 4],
+                "local function ${1:add}(${2:a}, ${3:b})\n  return $2 + $3\nend".into()
+            )]
+        );
+        assert!(bodies(&lines[..4]).is_empty());
+    }
+
+    #[test]
+    fn can_skip_repeats_of_smaller_windows() {
+        let lines = [
+            "  if a then",
+            "    f(a)",
+            "  end",
+            "  if b then",
+            "    f(b)",
+            "  end",
+            "  if c then",
+            "    f(c)",
+            "  end",
+            "  if d then",
+            "    f(d)",
+            "  end"
+        ];
+        let found = suggest(&lines);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].occurrences, vec![0, 3, 6, 9]);
+        assert_eq!(found[0].lines, 3);
+        assert_eq!(found[0].body.to_string(), "if ${1:a} then\n  f($1)\nend");
+        assert_eq!(found[0].keyword(), Some("if"));
+    }
+
+    #[test]
+    fn can_ignore_coincidences() {
+        assert!(bodies(&["x = y + z", "p(q)", "a = b + c", "r(s)"]).is_empty());
+        assert!(bodies(&["}", "}", "}", "}"]).is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn can_write_vscode() {
+        let lines = [
+            "if a then",
+            "  f(a, 1)",
+            "end",
+            "if b then",
+            "  f(b, 1)",
+            "end"
+        ];
+        let file = to_file(&suggest(&lines));
+        let (out, warnings) = crate::convert::write(&file, crate::convert::Format::VsCode).unwrap();
+        assert!(warnings.is_empty());
+        let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"if": {
+                "prefix": "if",
+                "body": ["if ${1:a} then", "  f($1, 1)", "end"],
+                "description": "Repeated at lines 1, 4"
+            }})
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn can_name_suggestions_uniquely() {
+        let lines = [
+            "local x = f(1)",
+            "print(x)",
+            "",
+            "local y = f(2)",
+            "print(y)",
+            "",
+            "local function g(a)",
+            "  return a",
+            "end",
+            "local function h(b)",
+            "  return b",
+            "end"
+        ];
+        let file = to_file(&suggest(&lines));
+        let names: Vec<_> = file
+            .snippets
+            .iter()
+            .map(|s| (s.name.as_str(), s.prefix.clone()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("local", vec!["local".to_owned()]),
+                ("local2", vec!["local2".to_owned()])
+            ]
+        );
+    }
+}
@@ -1,6 +1,26 @@
 use super::*;
+use std::path::{Path, PathBuf};
+
+/// A directory under the system temporary one, removed with its contents on
+/// drop so that a failing test does not leave it behind
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("virtualsnip-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path { &self.0 }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) { let _ = std::fs::remove_dir_all(&self.0); }
+}
 
 #[test]
+#[cfg(feature = "serde")]
 fn serialize() {
     let resp = Response {
         texts: vec![Text {
@@ -13,6 +33,7 @@ fn serialize() {
 }
 
 #[test]
+#[cfg(feature = "serde")]
 fn deserialize() {
     let s = r#"{"type":"text","value":"a"}"#;
     let n: Node = serde_json::from_str(s).unwrap();
@@ -28,7 +49,8 @@ fn can_find() {
         Node::Placeholder(NodePlaceholder {
             children: vec![Node::Text(NodeText {
                 value: "condition".into()
-            })]
+            })],
+            ..Default::default()
         }),
         Node::Text(NodeText {
             value: " {\n    ".into()
@@ -36,7 +58,8 @@ fn can_find() {
         Node::Placeholder(NodePlaceholder {
             children: vec![Node::Text(NodeText {
                 value: "unimplemented!();".into()
-            })]
+            })],
+            ..Default::default()
         }),
         Node::Text(NodeText {
             value: "\n}".into()
@@ -73,7 +96,8 @@ fn can_match() {
         Node::Placeholder(NodePlaceholder {
             children: vec![Node::Text(NodeText {
                 value: "condition".into()
-            })]
+            })],
+            ..Default::default()
         }),
         Node::Text(NodeText {
             value: " {\n    ".into()
@@ -81,14 +105,16 @@ fn can_match() {
         Node::Placeholder(NodePlaceholder {
             children: vec![Node::Text(NodeText {
                 value: "unimplemented!();".into()
-            })]
+            })],
+            ..Default::default()
         }),
         Node::Text(NodeText {
             value: "\n}".into()
         }),
     ]];
     let before_cursor_inclusive = &["fn main(){".into(), "    if a == b {".into()];
-    let ms = r#match(2, before_cursor_inclusive, snippets);
+    let never = AtomicBool::new(false);
+    let ms = r#match(2, before_cursor_inclusive, snippets, &[], &never).unwrap();
     assert_eq!(ms.len(), 2);
     for (ns, l) in ms {
         match l {
@@ -99,7 +125,8 @@ fn can_match() {
                     Node::Placeholder(NodePlaceholder {
                         children: vec![Node::Text(NodeText {
                             value: "unimplemented!();".into()
-                        })]
+                        })],
+                        ..Default::default()
                     }),
                     Node::Text(NodeText {
                         value: "\n}".into()
@@ -121,12 +148,16 @@ fn can_tail_excluding_matches() {
         Placeholder(NodePlaceholder {
             children: vec![Text(NodeText {
                 value: "true".into()
-            })]
+            })],
+            ..Default::default()
         }),
         Text(NodeText {
             value: " then\n\t".into()
         }),
-        Placeholder(NodePlaceholder { children: vec![] }),
+        Placeholder(NodePlaceholder {
+            children: vec![],
+            ..Default::default()
+        }),
         Text(NodeText {
             value: "\nend".into()
         })
@@ -146,8 +177,10 @@ fn can_tail_excluding_matches() {
 #[test]
 fn can_calc() {
     let req = Request {
+        version: None,
         highlight: Highlight {
-            base: "Comment".into()
+            base: "Comment".into(),
+            placeholder: None
         },
         sign: " ".into(),
         start_line: 2,
@@ -158,6 +191,7 @@ fn can_calc() {
         ],
         sources: vec![vec![
             Snippet {
+                prefix: vec![],
                 body: vec![
                     "f = io.open(${1:\"${2:filename}\"}, \"${3:r}\")\n".into(),
                     "while true do".into(),
@@ -168,6 +202,7 @@ fn can_calc() {
                 ]
             },
             Snippet {
+                prefix: vec![],
                 body: vec![
                     "for i, ${1:x} in pairs(${2:table}) do".into(),
                     "\t$0".into(),
@@ -175,12 +210,15 @@ fn can_calc() {
                 ]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["elseif ${1:true} then".into(), "\t$0".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["while ${1:true} do".into(), "\t$0".into(), "end".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec![
                     "function self:${1:methodName}($2)".into(),
                     "\t$0".into(),
@@ -188,12 +226,15 @@ fn can_calc() {
                 ]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["local ${1:var} = require(\"${2:module}\")".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["require(\"${1:module}\")".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec![
                     "for ${1:i}=${2:1},${3:10} do".into(),
                     "\t$0".into(),
@@ -201,12 +242,15 @@ fn can_calc() {
                 ]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["local ${1:varName} = ${0:value}".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["if ${1:true} then".into(), "\t$0".into(), "end".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec![
                     "function ${1:name}($2)".into(),
                     "\t${3:-- code}".into(),
@@ -214,9 +258,11 @@ fn can_calc() {
                 ]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["return $0".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec![
                     "local ${1:name} = function($2)".into(),
                     "\t${0:-- code}".into(),
@@ -224,6 +270,7 @@ fn can_calc() {
                 ]
             },
             Snippet {
+                prefix: vec![],
                 body: vec![
                     "${1:className} = {}\n".into(),
                     "$1.${2:new} = function($3)".into(),
@@ -234,12 +281,18 @@ fn can_calc() {
                 ]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["local ${0}".into()]
             },
             Snippet {
+                prefix: vec![],
                 body: vec!["print(${0})".into()]
             },
-        ]]
+        ]],
+        snippet_dirs: vec![],
+        filetypes: vec![],
+        usage_file: None,
+        anchors_file: None
     };
     let y = calc(&req);
     assert_eq!(
@@ -1424,3 +1477,228 @@ fn can_calc() {
 //        }
 //    );
 //}
+
+#[test]
+fn can_mirror_transform() {
+    let body = vec!["${1:name} ${1/(.*)/${1:/upcase}/}".into()];
+    assert_eq!(
+        nodes(&body),
+        vec![
+            Node::Placeholder(NodePlaceholder {
+                index: Some(1),
+                children: vec![Node::Text(NodeText {
+                    value: "name".into()
+                })],
+                ..Default::default()
+            }),
+            Node::Text(NodeText { value: " ".into() }),
+            Node::Placeholder(NodePlaceholder {
+                index: Some(1),
+                transform: Some(NodeTransform {
+                    regex: "(.*)".into(),
+                    format: "${1:/upcase}".into(),
+                    options: "".into()
+                }),
+                children: vec![Node::Text(NodeText {
+                    value: "NAME".into()
+                })],
+                ..Default::default()
+            })
+        ]
+    );
+}
+
+#[test]
+fn can_mirror() {
+    let shown = |body: &str| -> Vec<(Option<usize>, String)> {
+        nodes(&[body.into()])
+            .iter()
+            .map(|n| match n {
+                Node::Placeholder(p) => (p.index, text(n).into_owned()),
+                _ => (None, text(n).into_owned())
+            })
+            .collect()
+    };
+    assert_eq!(
+        shown("${1:className} = {}\n$1.${2:new}"),
+        vec![
+            (Some(1), "className".into()),
+            (None, " = {}\n".into()),
+            (Some(1), "className".into()),
+            (None, ".".into()),
+            (Some(2), "new".into())
+        ]
+    );
+    assert_eq!(
+        shown("$2 ${1:a ${2:b}} $1"),
+        vec![
+            (Some(2), "b".into()),
+            (None, " ".into()),
+            (Some(1), "a b".into()),
+            (None, " ".into()),
+            (Some(1), "a b".into())
+        ]
+    );
+    assert_eq!(
+        shown("${1:a $1} $1"),
+        vec![
+            (Some(1), "a ".into()),
+            (None, " ".into()),
+            (Some(1), "a ".into())
+        ]
+    );
+    assert_eq!(
+        shown("${1|let,const|} $1"),
+        vec![
+            (Some(1), "let".into()),
+            (None, " ".into()),
+            (Some(1), "let".into())
+        ]
+    );
+    #[cfg(feature = "serde")]
+    {
+        let n: Node =
+            serde_json::from_str(r#"{"type":"placeholder","index":3,"children":[]}"#).unwrap();
+        assert_eq!(
+            n,
+            Node::Placeholder(NodePlaceholder {
+                index: Some(3),
+                children: vec![],
+                ..Default::default()
+            })
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn can_serialize_nodes() {
+    let body = vec!["${1|a,b|} ${TM_FILENAME/(.*)/${1:/upcase}x/g} ${2:$1}".into()];
+    assert_eq!(
+        serde_json::to_string(&nodes(&body)).unwrap(),
+        concat!(
+            r#"[{"type":"placeholder","index":1,"choices":["a","b"],"children":[]},"#,
+            r#"{"type":"text","value":" "},"#,
+            r#"{"type":"variable","name":"TM_FILENAME","transform":{"regex":"(.*)","format":"${1:/upcase}x","options":"g"},"children":[]},"#,
+            r#"{"type":"text","value":" "},"#,
+            r#"{"type":"placeholder","index":2,"children":[{"type":"placeholder","index":1,"children":[{"type":"text","value":"a"}]}]}]"#
+        )
+    );
+    let nodes = nodes(&body);
+    let json = serde_json::to_string(&nodes).unwrap();
+    assert_eq!(serde_json::from_str::<Vec<Node>>(&json).unwrap(), nodes);
+}
+
+#[test]
+fn can_build_request() {
+    let req = Request::builder()
+        .lines(["fn main() {", "    if a {"])
+        .start_line(10)
+        .sign("> ")
+        .snippet(vec!["if ${1:cond} {".into(), "\t$0".into(), "}".into()])
+        .build()
+        .unwrap();
+    assert_eq!((req.start_line(), req.cursor_line()), (10, 11));
+    let resp = calc(&req);
+    let texts: Vec<_> = resp.texts().iter().map(|t| (t.line(), t.text())).collect();
+    assert_eq!(texts, vec![(11, "> \n}".to_owned())]);
+    assert_eq!(
+        resp.texts()[0].chunks().collect::<Vec<_>>(),
+        vec![("> \n}", "Comment")]
+    );
+    assert!(matches!(
+        Request::builder().lines(["a"]).cursor_line(1).build(),
+        Err(Error::InvalidRequest(_))
+    ));
+    #[cfg(feature = "serde")]
+    assert!(matches!(read_request(&b"{}"[..]), Err(Error::Json(_))));
+}
+
+#[test]
+fn can_highlight_placeholders() {
+    let req = Request::builder()
+        .lines(["for "])
+        .sign("> ")
+        .placeholder_highlight("Special")
+        .snippet(vec!["for ${1:i} in ${2:xs} do$0".into(), "end".into()])
+        .build()
+        .unwrap();
+    let mut resp = calc(&req);
+    assert_eq!(
+        resp.texts()[0].chunks().collect::<Vec<_>>(),
+        vec![
+            ("> ", "Comment"),
+            ("i", "Special"),
+            (" in ", "Comment"),
+            ("xs", "Special"),
+            (" do\nend", "Comment")
+        ]
+    );
+    let declined = protocol::Session {
+        multi_line_previews: false,
+        per_node_highlights: false
+    };
+    declined.adapt(&req, &mut resp);
+    assert_eq!(
+        resp.texts()[0].chunks().collect::<Vec<_>>(),
+        vec![("> i in xs do end", "Comment")]
+    );
+}
+
+#[test]
+fn can_match_with_matcher() {
+    let matcher = Matcher::from_bodies([vec!["if ${1:cond} {".to_owned(), "}".into()]]);
+    assert_eq!(matcher.snippets().len(), 1);
+    let previews = matcher.previews(3, &["x".into(), "if a {".into()]);
+    assert_eq!(previews.len(), 1);
+    assert_eq!(
+        (previews[0].line(), previews[0].text()),
+        (4, "cond {\n}".into())
+    );
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn can_prefer_used_snippets() {
+    let tmp = TempDir::new("ranking");
+    let dir = tmp.path();
+    let usage_file = dir.join("usage.tsv");
+    let req = serde_json::json!({
+        "highlight": {"base": "Comment"}, "sign": "", "lines": ["if a then"],
+        "start_line": 0, "cursor_line": 0, "filetypes": ["lua"],
+        "usage_file": usage_file,
+        "sources": [[
+            {"prefix": ["if"], "body": ["if ${1:c} then", "\t$0", "end"]},
+            {"prefix": ["ife"], "body": ["if ${1:c} then", "\t$0", "else", "end"]}
+        ]]
+    });
+    let shown = || {
+        let req = read_request(req.to_string().as_bytes()).unwrap();
+        let resp = calc(&req);
+        resp.texts()[0].text()
+    };
+    // without usage either may win the tie
+    let (other, expected) = match shown().as_str() {
+        "\nend" => ("ife", "\nelse\nend"),
+        _ => ("if", "\nend")
+    };
+    usage::record(&usage_file, &usage::Event::now(other, "lua")).unwrap();
+    let preferred = shown();
+    assert_eq!(preferred, expected);
+}
+
+#[test]
+fn can_match_anchors() {
+    let snippet = nodes(&["#[test]".into(), "fn ${1:name}() {".into(), "}".into()]);
+    let line = "#[my_test]";
+    assert_eq!(find(line, &snippet).hit, 0);
+    let anchors = vec!["#[my_test]".to_owned()];
+    let (found, anchor) = find_anchored(line, &snippet, &anchors);
+    assert_eq!((found.hit, anchor), (1, Some("#[my_test]")));
+    let hints = [Hint { boost: 0., anchors }];
+    let never = AtomicBool::new(false);
+    let snippets = [snippet.as_slice()];
+    let ms = r#match(0, &[line.into()], &snippets, &hints, &never).unwrap();
+    let shown: String = ms[0].0.iter().map(text).collect();
+    assert_eq!(shown, "name() {\n}");
+}
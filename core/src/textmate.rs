@@ -0,0 +1,373 @@
+// https://macromates.com/manual/en/snippets
+// A snippet is a property list, either XML or the old ASCII format, whose dict
+// has `content`, `tabTrigger`, `scope` and `name`. `content` is written in the
+// syntax LSP's was derived from, so it is parsed with `vs_snippet`.
+//
+// xml         ::= prolog* '<plist' attrs '>' value '</plist>'
+// value       ::= '<dict>' ('<key>' text '</key>' value)* '</dict>'
+//                | '<array>' value* '</array>' | '<string>' text '</string>'
+//                | '<' tag '/>' | '<' tag '>' text '</' tag '>'
+// ascii       ::= '{' (word '=' ascii_value ';')* '}'
+// ascii_value ::= '"' escaped '"' | "'" ('' | [^'])* "'" | word
+//                | '(' (ascii_value (',' ascii_value)*)? ','? ')' | ascii
+// word        ::= [A-Za-z0-9_$+/:.-]+
+
+use crate::vs_snippet;
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped_transform, is_not, tag, take_until, take_while, take_while1},
+    character::complete::{char, multispace0, none_of},
+    combinator::{map, opt, value},
+    multi::{many0, separated_list0},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    IResult
+};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Snippet {
+    pub name: String,
+    pub tab_trigger: String,
+    /// Scope selector like `source.ruby, source.erb`
+    pub scope: String,
+    pub content: String
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error(pub String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str(&self.0) }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Array(Vec<Value>),
+    Dict(Vec<(String, Value)>),
+    /// numbers, booleans, dates and data are of no use for snippets
+    Other
+}
+
+impl Snippet {
+    pub fn ast(&self) -> Option<vs_snippet::Ast<'_>> { vs_snippet::parse(&self.content) }
+
+    /// Filetypes of the scope selector, `source.ruby, text.html.basic` to `ruby, html`
+    pub fn filetypes(&self) -> Vec<String> {
+        self.scope
+            .split(',')
+            .filter_map(|s| s.split_whitespace().next())
+            .map(|s| {
+                let s = s
+                    .strip_prefix("source.")
+                    .or_else(|| s.strip_prefix("text."))
+                    .unwrap_or(s);
+                s.split('.').next().unwrap_or(s).to_owned()
+            })
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+}
+
+pub fn parse(s: &str) -> Result<Snippet, Error> {
+    let s = s.trim_start_matches('\u{feff}');
+    let parsed = if s.trim_start().starts_with('<') {
+        terminated(xml, multispace0)(s)
+    } else {
+        terminated(delimited(ws, ascii_dict, ws), multispace0)(s)
+    };
+    let dict = match parsed {
+        Ok(("", Value::Dict(d))) => d,
+        Ok(("", _)) => return Err(Error("the root is not a dict".into())),
+        Ok((rest, _)) => return Err(Error(format!("unexpected {}", excerpt(rest)))),
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+            return Err(Error(format!("invalid plist at {}", excerpt(e.input))))
+        }
+        Err(nom::Err::Incomplete(_)) => return Err(Error("incomplete plist".into()))
+    };
+    let mut snip = Snippet::default();
+    for (k, v) in dict {
+        let v = match v {
+            Value::String(v) => v,
+            _ => continue
+        };
+        match k.as_str() {
+            "name" => snip.name = v,
+            "tabTrigger" => snip.tab_trigger = v,
+            "scope" => snip.scope = v,
+            "content" => snip.content = v,
+            _ => ()
+        }
+    }
+    Ok(snip)
+}
+
+fn excerpt(s: &str) -> String { s.chars().take(20).collect() }
+
+fn xml(s: &str) -> IResult<&str, Value> {
+    preceded(
+        many0(alt((
+            value((), take_while1(char::is_whitespace)),
+            value((), delimited(tag("<?"), take_until("?>"), tag("?>"))),
+            value((), delimited(tag("<!--"), take_until("-->"), tag("-->"))),
+            value((), delimited(tag("<!"), is_not(">"), char('>')))
+        ))),
+        delimited(
+            tuple((tag("<plist"), opt(is_not(">")), char('>'))),
+            delimited(multispace0, xml_value, multispace0),
+            tag("</plist>")
+        )
+    )(s)
+}
+
+fn xml_value(s: &str) -> IResult<&str, Value> {
+    alt((
+        map(element("string", xml_text), Value::String),
+        value(Value::String(String::new()), tag("<string/>")),
+        map(element("key", xml_text), Value::String),
+        map(
+            element(
+                "dict",
+                many0(delimited(
+                    multispace0,
+                    pair(element("key", xml_text), preceded(multispace0, xml_value)),
+                    multispace0
+                ))
+            ),
+            Value::Dict
+        ),
+        value(Value::Dict(Vec::new()), tag("<dict/>")),
+        map(
+            element(
+                "array",
+                many0(delimited(multispace0, xml_value, multispace0))
+            ),
+            Value::Array
+        ),
+        value(Value::Array(Vec::new()), tag("<array/>")),
+        value(
+            Value::Other,
+            tuple((char('<'), take_while1(char::is_alphanumeric), tag("/>")))
+        ),
+        value(Value::Other, xml_scalar)
+    ))(s)
+}
+
+/// <integer>1</integer>, <real>, <date> and <data>
+fn xml_scalar(s: &str) -> IResult<&str, ()> {
+    let (s, name) = delimited(char('<'), take_while1(char::is_alphanumeric), char('>'))(s)?;
+    let (s, _) = take_until("</")(s)?;
+    value((), tuple((tag("</"), tag(name), char('>'))))(s)
+}
+
+fn element<'a, O, F>(name: &'static str, inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O>
+{
+    delimited(
+        tuple((char('<'), tag(name), char('>'))),
+        inner,
+        tuple((tag("</"), tag(name), char('>')))
+    )
+}
+
+/// Character data with entities, possibly empty
+fn xml_text(s: &str) -> IResult<&str, String> {
+    let (rest, raw) = take_while(|c| c != '<')(s)?;
+    Ok((rest, unescape_xml(raw)))
+}
+
+fn unescape_xml(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = match rest.find(';') {
+            Some(e) => e,
+            None => break
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|d| d.parse().ok()))
+                .and_then(char::from_u32)
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whitespace and comments
+fn ws(s: &str) -> IResult<&str, ()> {
+    value(
+        (),
+        many0(alt((
+            value((), take_while1(char::is_whitespace)),
+            value((), pair(tag("//"), take_while(|c| c != '\n'))),
+            value((), tuple((tag("/*"), take_until("*/"), tag("*/"))))
+        )))
+    )(s)
+}
+
+fn ascii_value(s: &str) -> IResult<&str, Value> {
+    alt((
+        map(ascii_double_quoted, Value::String),
+        map(ascii_single_quoted, Value::String),
+        map(ascii_word, |w| Value::String(w.to_owned())),
+        map(
+            delimited(
+                pair(char('('), ws),
+                terminated(
+                    separated_list0(delimited(ws, char(','), ws), ascii_value),
+                    pair(ws, opt(pair(char(','), ws)))
+                ),
+                char(')')
+            ),
+            Value::Array
+        ),
+        ascii_dict
+    ))(s)
+}
+
+fn ascii_dict(s: &str) -> IResult<&str, Value> {
+    let entry = tuple((
+        alt((
+            map(ascii_double_quoted, String::from),
+            map(ascii_single_quoted, String::from),
+            map(ascii_word, String::from)
+        )),
+        delimited(ws, char('='), ws),
+        ascii_value,
+        pair(ws, char(';'))
+    ));
+    map(
+        delimited(
+            pair(char('{'), ws),
+            many0(terminated(map(entry, |(k, _, v, _)| (k, v)), ws)),
+            char('}')
+        ),
+        Value::Dict
+    )(s)
+}
+
+fn ascii_word(s: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_alphanumeric() || "_$+/:.-".contains(c))(s)
+}
+
+fn ascii_double_quoted(s: &str) -> IResult<&str, String> {
+    delimited(
+        char('"'),
+        map(
+            opt(escaped_transform(
+                none_of("\\\""),
+                '\\',
+                alt((
+                    value('\n', char('n')),
+                    value('\t', char('t')),
+                    value('\r', char('r')),
+                    value('"', char('"')),
+                    value('\\', char('\\'))
+                ))
+            )),
+            Option::unwrap_or_default
+        ),
+        char('"')
+    )(s)
+}
+
+/// '' is a quote
+fn ascii_single_quoted(s: &str) -> IResult<&str, String> {
+    delimited(
+        char('\''),
+        map(many0(alt((value('\'', tag("''")), none_of("'")))), |cs| {
+            cs.into_iter().collect()
+        }),
+        char('\'')
+    )(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_xml() {
+        let s = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>content</key>
+	<string>if ${1:a &lt; b}
+	$0
+end</string>
+	<key>keyEquivalent</key>
+	<string/>
+	<key>name</key>
+	<string>if &#x2026;</string>
+	<key>scope</key>
+	<string>source.ruby, text.html.erb</string>
+	<key>semanticClass</key>
+	<array><string>x</string><integer>1</integer><true/></array>
+	<key>tabTrigger</key>
+	<string>if</string>
+</dict>
+</plist>
+"#;
+        let snip = parse(s).unwrap();
+        assert_eq!(
+            snip,
+            Snippet {
+                name: "if \u{2026}".into(),
+                tab_trigger: "if".into(),
+                scope: "source.ruby, text.html.erb".into(),
+                content: "if ${1:a < b}\n\t$0\nend".into()
+            }
+        );
+        assert_eq!(snip.filetypes(), vec!["ruby", "html"]);
+        assert_eq!(
+            snip.ast(),
+            Some(vs_snippet::Ast(vec![
+                vs_snippet::Any::Text("if ".into()),
+                vs_snippet::Any::Placeholder(1, vec![vs_snippet::Any::Text("a < b".into())]),
+                vs_snippet::Any::Text("\n\t".into()),
+                vs_snippet::Any::TabStop(0),
+                vs_snippet::Any::Text("\nend".into())
+            ]))
+        );
+    }
+
+    #[test]
+    fn can_parse_ascii() {
+        let s = "{\tcontent = 'def ${1:name}\n\t$0\nend # it''s';\n\tname = \"def \\\"x\\\"\"; // comment\n\tscope = source.ruby;\n\ttabTrigger = def;\n\tkeys = (a, 'b', );\n}\n";
+        assert_eq!(
+            parse(s).unwrap(),
+            Snippet {
+                name: "def \"x\"".into(),
+                tab_trigger: "def".into(),
+                scope: "source.ruby".into(),
+                content: "def ${1:name}\n\t$0\nend # it's".into()
+            }
+        );
+        assert!(parse("{ content = 'a' }").is_err());
+        assert!(parse("<plist><array/></plist>").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Small local files of tab-separated rows, like the usage and anchors files.
+//!
+//! Backslashes, tabs and newlines in fields are escaped as `\\`, `\t` and
+//! `\n` so that every row stays one line. A file is replaced as a whole
+//! through a temporary file next to it, so readers never see half a file, and
+//! updated under a lock file next to it, so concurrent writers keep each
+//! other's rows.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering}
+};
+
+/// Rows of the file at `path`; a missing file has none
+pub(crate) fn load(path: &Path) -> io::Result<Vec<Vec<String>>> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(parse(&s)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Writes `rows` in their order, creating the directory if needed
+pub(crate) fn save(path: &Path, rows: &[Vec<String>]) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmp = beside(
+        path,
+        &format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    )?;
+    fs::write(&tmp, format(rows))?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Loads, changes and saves the rows of `path` holding a lock on a file next
+/// to it, so that processes updating it at the same time do not lose updates
+pub(crate) fn update(
+    path: &Path,
+    f: impl FnOnce(Vec<Vec<String>>) -> Vec<Vec<String>>
+) -> io::Result<()> {
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(beside(path, "lock")?)?;
+    lock.lock()?;
+    save(path, &f(load(path)?))
+}
+
+/// A hidden file in the directory of `path` named after it, creating the
+/// directory if needed
+fn beside(path: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir)?;
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let name = format!(".{}.{}", name.to_string_lossy(), suffix);
+    Ok(dir.map_or_else(|| PathBuf::from(&name), |d| d.join(&name)))
+}
+
+pub(crate) fn parse(s: &str) -> Vec<Vec<String>> {
+    s.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.split('\t').map(unescape).collect())
+        .collect()
+}
+
+pub(crate) fn format(rows: &[Vec<String>]) -> String {
+    let mut out = String::new();
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|f| escape(f)).collect();
+        out.push_str(&fields.join("\t"));
+        out.push('\n');
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(c) => out.push(c),
+            None => out.push('\\')
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_escape() {
+        let rows = vec![
+            vec!["lua".to_owned(), "a\tb\nc\\d".into(), "1".into()],
+            vec!["".to_owned(), "e".into()],
+        ];
+        let s = format(&rows);
+        assert_eq!(s, "lua\ta\\tb\\nc\\\\d\t1\n\te\n");
+        assert_eq!(parse(&s), rows);
+    }
+}
@@ -0,0 +1,526 @@
+// https://github.com/SirVer/ultisnips/blob/master/doc/UltiSnips.txt
+// file        ::= (snippet | global | directive | comment | empty)*
+// snippet     ::= 'snippet' trigger ('"' description '"' options?)? '\n' body 'endsnippet'
+// global      ::= 'global' '!p' '\n' .* 'endglobal'
+// directive   ::= 'extends' ft (',' ft)* | 'priority' int | 'clearsnippets' .*
+//                | 'context' .* | 'pre_expand' .* | 'post_expand' .* | 'post_jump' .*
+// body        ::= (tabstop | placeholder | transform | visual | interp | text)*
+// tabstop     ::= '$' int | '${' int '}'
+// placeholder ::= '${' int ':' body '}'
+// transform   ::= '${' int '/' regex '/' replacement '/' options '}'
+// visual      ::= '${VISUAL' (':' text)? ('/' regex '/' replacement '/' options)? '}'
+// interp      ::= '`' ('!p' | '!v' | '!python' | '!vim')? code '`'
+// Anything else, like a `$` that starts nothing, is text. Globals, directives
+// other than `extends` and `priority`, and lines that are none of the above are
+// skipped with a warning.
+
+use crate::{
+    resolve_mirrors, Node, NodeInterpolation, NodePlaceholder, NodeText, NodeTransform,
+    NodeVariable
+};
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped_transform, tag, take_while1},
+    character::complete::{anychar, char, digit1, multispace1, none_of, one_of},
+    combinator::{map, opt, recognize, value},
+    error::ErrorKind,
+    multi::many0,
+    sequence::{delimited, preceded, terminated, tuple},
+    IResult
+};
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct File {
+    pub extends: Vec<String>,
+    pub snippets: Vec<Snippet>,
+    /// Why lines were skipped
+    pub warnings: Vec<Error>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snippet {
+    pub trigger: String,
+    pub description: String,
+    pub options: String,
+    pub priority: i64,
+    pub body: Vec<String>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error {
+    /// 1-based
+    pub line: usize,
+    pub message: String
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn parse(s: &str) -> Result<File, Error> {
+    let mut file = File::default();
+    let mut priority = 0;
+    let mut lines = s.lines().enumerate().map(|(i, l)| (i + 1, l));
+    while let Some((no, line)) = lines.next() {
+        let error = |message: String| Error { line: no, message };
+        let mut skip = |message: String| file.warnings.push(error(message));
+        let (head, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match head {
+            "" => (),
+            _ if head.starts_with('#') => (),
+            "snippet" => {
+                let (trigger, description, options) = header(rest.trim()).map_err(error)?;
+                let mut body = Vec::new();
+                loop {
+                    match lines.next() {
+                        Some((_, l)) if l.trim_end() == "endsnippet" => break,
+                        Some((_, l)) => body.push(l.to_owned()),
+                        None => return Err(error("missing endsnippet".into()))
+                    }
+                }
+                file.snippets.push(Snippet {
+                    trigger,
+                    description,
+                    options,
+                    priority,
+                    body
+                });
+            }
+            "global" => {
+                skip(format!("{} is not supported", line.trim_end()));
+                loop {
+                    match lines.next() {
+                        Some((_, l)) if l.trim_end() == "endglobal" => break,
+                        Some(_) => (),
+                        None => return Err(error("missing endglobal".into()))
+                    }
+                }
+            }
+            "extends" => file.extends.extend(
+                rest.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+            ),
+            "priority" => {
+                priority = rest
+                    .trim()
+                    .parse()
+                    .map_err(|_| error(format!("invalid priority {}", rest.trim())))?
+            }
+            "clearsnippets" | "context" | "pre_expand" | "post_expand" | "post_jump" => {
+                skip(format!("{} is not supported", head))
+            }
+            _ => skip(format!("unexpected {}", line))
+        }
+    }
+    Ok(file)
+}
+
+/// Splits `trigger "description" options` the way UltiSnips does.
+fn header(line: &str) -> Result<(String, String, String), String> {
+    let mut remain = line;
+    let mut options = "";
+    let words: Vec<&str> = remain.split_whitespace().collect();
+    if words.len() > 2 {
+        let (last, second) = (words[words.len() - 1], words[words.len() - 2]);
+        if !last.contains('"') && second.ends_with('"') {
+            options = last;
+            remain = remain[..remain.len() - last.len()].trim_end();
+        }
+    }
+    let mut description = "";
+    if let Some(inner) = remain.strip_suffix('"') {
+        if let Some(left) = inner.rfind('"').filter(|l| *l > 0) {
+            description = &inner[left + 1..];
+            remain = &remain[..left];
+        }
+    }
+    let mut trigger = remain.trim();
+    if trigger.is_empty() {
+        return Err("missing trigger".into());
+    }
+    if trigger.split_whitespace().nth(1).is_some() || options.contains('r') {
+        let mut cs = trigger.chars();
+        let (first, last) = (cs.next(), cs.next_back());
+        if last.is_none() || first != last {
+            return Err(format!("invalid multiword trigger {}", trigger));
+        }
+        let q = first.unwrap().len_utf8();
+        trigger = &trigger[q..trigger.len() - q];
+    }
+    Ok((
+        trigger.to_owned(),
+        description.to_owned(),
+        options.to_owned()
+    ))
+}
+
+/// Never fails; whatever is not a construct is kept as text. Mirrors repeat
+/// the text of their tab stop, untransformed.
+pub fn parse_body(s: &str) -> Vec<Node> {
+    let mut nodes = parse_nodes(s);
+    resolve_mirrors(&mut nodes, |_, _| None);
+    nodes
+}
+
+/// The body as it is written, mirrors still empty
+pub(crate) fn parse_nodes(s: &str) -> Vec<Node> {
+    let (_, nodes) = many0(item)(s).expect("every input is text at worst");
+    merge_texts(nodes)
+}
+
+fn item(s: &str) -> IResult<&str, Node> {
+    if s.is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(s, ErrorKind::Eof)));
+    }
+    alt((
+        tab_stop,
+        placeholder,
+        transform,
+        visual,
+        interpolation,
+        text,
+        literal
+    ))(s)
+}
+
+fn item_inner_braces(s: &str) -> IResult<&str, Node> {
+    if s.is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(s, ErrorKind::Eof)));
+    }
+    alt((
+        tab_stop,
+        placeholder,
+        transform,
+        visual,
+        interpolation,
+        text_inner_braces,
+        map(none_of("}"), |c| text_node(c.to_string()))
+    ))(s)
+}
+
+fn number(s: &str) -> IResult<&str, usize> {
+    map(digit1, |n: &str| n.parse().unwrap_or(usize::MAX))(s)
+}
+
+/// $1 || ${1}
+fn tab_stop(s: &str) -> IResult<&str, Node> {
+    map(
+        alt((
+            preceded(char('$'), number),
+            delimited(tag("${"), number, char('}'))
+        )),
+        |n| {
+            Node::Placeholder(NodePlaceholder {
+                index: Some(n),
+                ..Default::default()
+            })
+        }
+    )(s)
+}
+
+/// ${1:default}
+fn placeholder(s: &str) -> IResult<&str, Node> {
+    map(
+        tuple((
+            tag("${"),
+            number,
+            char(':'),
+            many0(item_inner_braces),
+            char('}')
+        )),
+        |(_, n, _, children, _)| {
+            Node::Placeholder(NodePlaceholder {
+                index: Some(n),
+                children: merge_texts(children),
+                ..Default::default()
+            })
+        }
+    )(s)
+}
+
+/// ${1/regex/replacement/options}
+fn transform(s: &str) -> IResult<&str, Node> {
+    map(
+        delimited(tag("${"), tuple((number, substitution)), char('}')),
+        |(n, transform)| {
+            Node::Placeholder(NodePlaceholder {
+                index: Some(n),
+                transform: Some(transform),
+                ..Default::default()
+            })
+        }
+    )(s)
+}
+
+/// /regex/replacement/options, kept as written
+fn substitution(s: &str) -> IResult<&str, NodeTransform> {
+    let part = || recognize(many0(alt((preceded(char('\\'), anychar), none_of("/")))));
+    map(
+        tuple((
+            char('/'),
+            part(),
+            char('/'),
+            part(),
+            char('/'),
+            opt(take_while1(|c| c != '}'))
+        )),
+        |(_, regex, _, format, _, options): (_, &str, _, &str, _, Option<&str>)| NodeTransform {
+            regex: regex.to_owned(),
+            format: format.to_owned(),
+            options: options.unwrap_or_default().to_owned()
+        }
+    )(s)
+}
+
+/// ${VISUAL} || ${VISUAL:default} || ${VISUAL/regex/replacement/options}
+fn visual(s: &str) -> IResult<&str, Node> {
+    map(
+        delimited(
+            tag("${VISUAL"),
+            tuple((
+                opt(preceded(char(':'), many0(item_inner_braces))),
+                opt(substitution)
+            )),
+            char('}')
+        ),
+        |(default, transform)| {
+            Node::Variable(NodeVariable {
+                name: "VISUAL".into(),
+                transform,
+                children: merge_texts(default.unwrap_or_default())
+            })
+        }
+    )(s)
+}
+
+/// `!p python` || `!v vimscript` || `shell`; the code may start on the next line
+fn interpolation(s: &str) -> IResult<&str, Node> {
+    let language = alt((
+        value(
+            "python",
+            terminated(alt((tag("!python"), tag("!p"))), multispace1)
+        ),
+        value(
+            "vim",
+            terminated(alt((tag("!vim"), tag("!v"))), multispace1)
+        ),
+        value("shell", tag(""))
+    ));
+    map(
+        delimited(
+            char('`'),
+            tuple((
+                language,
+                many0(alt((preceded(char('\\'), one_of("`")), none_of("`"))))
+            )),
+            char('`')
+        ),
+        |(language, code)| {
+            Node::Interpolation(NodeInterpolation {
+                language: language.to_owned(),
+                code: code.into_iter().collect()
+            })
+        }
+    )(s)
+}
+
+fn text(s: &str) -> IResult<&str, Node> {
+    map(
+        escaped_transform(none_of("\\$`"), '\\', one_of("\\$`{}")),
+        text_node
+    )(s)
+}
+
+fn text_inner_braces(s: &str) -> IResult<&str, Node> {
+    map(
+        escaped_transform(none_of("\\$`}"), '\\', one_of("\\$`{}")),
+        text_node
+    )(s)
+}
+
+/// A character that starts nothing
+fn literal(s: &str) -> IResult<&str, Node> { map(anychar, |c| text_node(c.to_string()))(s) }
+
+fn text_node(value: String) -> Node { Node::Text(NodeText { value }) }
+
+fn merge_texts(nodes: Vec<Node>) -> Vec<Node> {
+    let mut merged: Vec<Node> = Vec::with_capacity(nodes.len());
+    for n in nodes {
+        match (merged.last_mut(), n) {
+            (Some(Node::Text(a)), Node::Text(b)) => a.value.push_str(&b.value),
+            (_, Node::Text(t)) if t.value.is_empty() => (),
+            (_, n) => merged.push(n)
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(s: &str) -> Node { text_node(s.into()) }
+
+    fn p(index: usize, children: Vec<Node>) -> Node {
+        Node::Placeholder(NodePlaceholder {
+            index: Some(index),
+            children,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn can_header() {
+        let h = |s: &str| header(s).unwrap();
+        let own = |a: &str, b: &str, c: &str| (a.to_owned(), b.to_owned(), c.to_owned());
+        assert_eq!(h("if"), own("if", "", ""));
+        assert_eq!(h(r#"if "If statement""#), own("if", "If statement", ""));
+        assert_eq!(
+            h(r#"if "If statement" bA"#),
+            own("if", "If statement", "bA")
+        );
+        assert_eq!(h(r#"!if else! "desc""#), own("if else", "desc", ""));
+        assert_eq!(h(r#""^\s*if" "regex" r"#), own(r#"^\s*if"#, "regex", "r"));
+        assert!(header("if else").is_err());
+    }
+
+    #[test]
+    fn can_parse() {
+        let file = parse(
+            "# comment\nextends c, cpp\n\npriority -50\nsnippet if \"If\" b\nif (${1:cond}) {\n\t$0\n}\nendsnippet\n\nglobal !p\ndef f(): pass\nendglobal\nsnippet el\nelse\nendsnippet\n"
+        )
+        .unwrap();
+        assert_eq!(file.extends, vec!["c", "cpp"]);
+        assert_eq!(
+            file.snippets,
+            vec![
+                Snippet {
+                    trigger: "if".into(),
+                    description: "If".into(),
+                    options: "b".into(),
+                    priority: -50,
+                    body: vec!["if (${1:cond}) {".into(), "\t$0".into(), "}".into()]
+                },
+                Snippet {
+                    trigger: "el".into(),
+                    description: "".into(),
+                    options: "".into(),
+                    priority: -50,
+                    body: vec!["else".into()]
+                }
+            ]
+        );
+        assert_eq!(
+            parse("snippet a\nb"),
+            Err(Error {
+                line: 1,
+                message: "missing endsnippet".into()
+            })
+        );
+    }
+
+    #[test]
+    fn skips_unsupported_lines() {
+        let file = parse(
+            "global !p\ndef f(): pass\nendglobal\npost_jump \"f(snip)\"\nfoo\nsnippet a\nb\nendsnippet\n"
+        )
+        .unwrap();
+        assert_eq!(file.snippets.len(), 1);
+        assert_eq!(
+            file.warnings,
+            vec![
+                Error {
+                    line: 1,
+                    message: "global !p is not supported".into()
+                },
+                Error {
+                    line: 4,
+                    message: "post_jump is not supported".into()
+                },
+                Error {
+                    line: 5,
+                    message: "unexpected foo".into()
+                }
+            ]
+        );
+        assert!(parse("global !p\n").is_err());
+    }
+
+    #[test]
+    fn can_parse_body() {
+        assert_eq!(
+            parse_body("if (${1:cond}) {\n\t${2:${VISUAL}}$0\n}"),
+            vec![
+                t("if ("),
+                p(1, vec![t("cond")]),
+                t(") {\n\t"),
+                p(
+                    2,
+                    vec![Node::Variable(NodeVariable {
+                        name: "VISUAL".into(),
+                        ..Default::default()
+                    })]
+                ),
+                p(0, vec![]),
+                t("\n}")
+            ]
+        );
+        assert_eq!(
+            parse_body("${1:name} $1 ${1/(\\w+)/\\u$1/g}"),
+            vec![
+                p(1, vec![t("name")]),
+                t(" "),
+                p(1, vec![t("name")]),
+                t(" "),
+                Node::Placeholder(NodePlaceholder {
+                    index: Some(1),
+                    transform: Some(NodeTransform {
+                        regex: "(\\w+)".into(),
+                        format: "\\u$1".into(),
+                        options: "g".into()
+                    }),
+                    children: vec![t("name")],
+                    ..Default::default()
+                })
+            ]
+        );
+        assert_eq!(
+            parse_body("`!p snip.rv = \"\\`\"` `!v expand('%')` `date`"),
+            vec![
+                Node::Interpolation(NodeInterpolation {
+                    language: "python".into(),
+                    code: "snip.rv = \"`\"".into()
+                }),
+                t(" "),
+                Node::Interpolation(NodeInterpolation {
+                    language: "vim".into(),
+                    code: "expand('%')".into()
+                }),
+                t(" "),
+                Node::Interpolation(NodeInterpolation {
+                    language: "shell".into(),
+                    code: "date".into()
+                })
+            ]
+        );
+        assert_eq!(
+            parse_body("`!p\nsnip.rv = fn\n`"),
+            vec![Node::Interpolation(NodeInterpolation {
+                language: "python".into(),
+                code: "snip.rv = fn\n".into()
+            })]
+        );
+        assert_eq!(
+            parse_body("\\$1 costs $ 5 \\n ${x} {}"),
+            vec![t("$1 costs $ 5 \\n ${x} {}")]
+        );
+        assert_eq!(parse_body("${1:a}b}"), vec![p(1, vec![t("a")]), t("b}")]);
+        assert_eq!(parse_body("${1:unclosed"), vec![t("${1:unclosed")]);
+    }
+}
@@ -0,0 +1,210 @@
+//! How often and how recently snippets were expanded, kept in a local file.
+//!
+//! Snippets are identified by their prefix within a filetype. The file has one
+//! tab-separated line per snippet, escaped as [`crate::tsv`] does: filetype,
+//! prefix, count and the Unix time of the last expansion. Nothing leaves the
+//! machine.
+//!
+//! The counts only break ties in matching: of the snippets that fit the lines
+//! equally well, the one expanded most, weighted towards recent use, is shown.
+use crate::tsv;
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+/// Uses this many days old count half
+const HALF_LIFE_DAYS: f64 = 30.;
+
+/// A snippet was expanded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub snippet: String,
+    pub filetype: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stat {
+    count: u64,
+    last: u64
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Usage {
+    /// (filetype, prefix)
+    stats: HashMap<(String, String), Stat>
+}
+
+impl Event {
+    /// An expansion happening now
+    pub fn now(snippet: impl Into<String>, filetype: impl Into<String>) -> Self {
+        Self {
+            snippet: snippet.into(),
+            filetype: filetype.into(),
+            timestamp: now()
+        }
+    }
+}
+
+impl Usage {
+    /// A missing file is no usage yet and malformed lines are skipped
+    pub fn load(path: &Path) -> io::Result<Self> { Ok(Self::from_rows(tsv::load(path)?)) }
+
+    /// Replaces the file at once so that readers never see half of it
+    pub fn save(&self, path: &Path) -> io::Result<()> { tsv::save(path, &self.rows()) }
+
+    pub fn record(&mut self, event: &Event) {
+        let stat = self
+            .stats
+            .entry((event.filetype.clone(), event.snippet.clone()))
+            .or_insert(Stat { count: 0, last: 0 });
+        stat.count += 1;
+        stat.last = stat.last.max(event.timestamp);
+    }
+
+    /// Frequency decayed by recency, summed over the prefixes of a snippet in
+    /// the filetypes of the buffer; 0 for snippets never expanded
+    pub fn score<P: AsRef<str>>(&self, filetypes: &[String], prefixes: &[P], now: u64) -> f64 {
+        let mut score = 0.;
+        if self.stats.is_empty() {
+            return score;
+        }
+        for ft in filetypes {
+            for p in prefixes {
+                let key = (ft.clone(), p.as_ref().to_owned());
+                if let Some(s) = self.stats.get(&key) {
+                    let days = now.saturating_sub(s.last) as f64 / 86400.;
+                    score += s.count as f64 * 0.5f64.powf(days / HALF_LIFE_DAYS);
+                }
+            }
+        }
+        score
+    }
+
+    fn from_rows(rows: Vec<Vec<String>>) -> Self {
+        let stats = rows
+            .into_iter()
+            .filter_map(|row| match <[String; 4]>::try_from(row).ok()? {
+                [ft, prefix, count, last] => {
+                    let stat = Stat {
+                        count: count.parse().ok()?,
+                        last: last.parse().ok()?
+                    };
+                    Some(((ft, prefix), stat))
+                }
+            })
+            .collect();
+        Self { stats }
+    }
+
+    /// Sorted so that the file diffs well
+    fn rows(&self) -> Vec<Vec<String>> {
+        let mut keys: Vec<_> = self.stats.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|k| {
+                let s = self.stats[k];
+                vec![
+                    k.0.clone(),
+                    k.1.clone(),
+                    s.count.to_string(),
+                    s.last.to_string(),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&tsv::format(&self.rows()))
+    }
+}
+
+/// Adds `event` to the file at `path`
+pub fn record(path: &Path, event: &Event) -> io::Result<()> {
+    tsv::update(path, |rows| {
+        let mut usage = Usage::from_rows(rows);
+        usage.record(event);
+        usage.rows()
+    })
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+    use std::fs;
+
+    #[test]
+    fn can_score() {
+        let mut usage = Usage::default();
+        let day = 86400;
+        let event = |snippet: &str, timestamp| Event {
+            snippet: snippet.into(),
+            filetype: "lua".into(),
+            timestamp
+        };
+        usage.record(&event("if", 0));
+        usage.record(&event("if", 0));
+        usage.record(&event("for", 60 * day));
+        let lua = ["lua".to_owned()];
+        let now = 60 * day;
+        assert_eq!(usage.score(&lua, &["if"], now), 0.5);
+        assert_eq!(usage.score(&lua, &["for"], now), 1.);
+        assert_eq!(usage.score(&lua, &["while"], now), 0.);
+        assert_eq!(usage.score(&["c".to_owned()], &["if"], now), 0.);
+        assert_eq!(usage.score(&lua, &["if", "for"], now), 1.5);
+    }
+
+    #[test]
+    fn can_save() {
+        let tmp = TempDir::new("usage");
+        let dir = tmp.path();
+        let path = dir.join("state").join("usage.tsv");
+        assert_eq!(Usage::load(&path).unwrap(), Usage::default());
+        record(&path, &Event::now("if", "lua")).unwrap();
+        record(&path, &Event::now("if", "lua")).unwrap();
+        let mut s = fs::read_to_string(&path).unwrap();
+        s.push_str("broken line\n");
+        fs::write(&path, s).unwrap();
+        let usage = Usage::load(&path).unwrap();
+        let stat = usage.stats[&("lua".to_owned(), "if".to_owned())];
+        assert_eq!(stat.count, 2);
+        assert_eq!(usage.stats.len(), 1);
+    }
+
+    #[test]
+    fn keeps_concurrent_records() {
+        let tmp = TempDir::new("usage-concurrent");
+        let path = tmp.path().join("usage.tsv");
+        let writers: Vec<_> = ["lua", "vim", "rust", "c"]
+            .into_iter()
+            .map(|ft| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        record(&path, &Event::now("if", ft)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        let usage = Usage::load(&path).unwrap();
+        assert_eq!(usage.stats.len(), 4);
+        assert!(usage.stats.values().all(|s| s.count == 10));
+    }
+}
@@ -3,11 +3,12 @@
 // NOTE: The implementation also has `${int/regex/format/options}`, but it is not documented.
 // any         ::= tabstop | placeholder | choice | variable | text
 // tabstop     ::= '$' int | '${' int '}'
-// placeholder ::= '${' int ':' any '}'
+//                | '${' int '/' regex '/' (format | text)* '/' options '}'
+// placeholder ::= '${' int ':' any* '}'
 // choice      ::= '${' int '|' text (',' text)* '|}'
 // variable    ::= '$' var | '${' var }'
-//                | '${' var ':' any '}'
-//                | '${' var '/' regex '/' (format | text)+ '/' options '}'
+//                | '${' var ':' any* '}'
+//                | '${' var '/' regex '/' (format | text)* '/' options '}'
 // format      ::= '$' int | '${' int '}'
 //                | '${' int ':' '/upcase' | '/downcase' | '/capitalize' '}'
 //                | '${' int ':+' if '}'
@@ -18,17 +19,25 @@
 // var         ::= [_a-zA-Z] [_a-zA-Z0-9]*
 // int         ::= [0-9]+
 // text        ::= .*
+// As in VS Code, `\` escapes only `$`, `}` and `\` (and `,` and `|` in a choice); any other
+// backslash is literal. So is a `$` that starts none of the above, and a `}` outside braces.
 
 use nom::{
-    branch::{alt, permutation},
-    bytes::complete::{escaped, escaped_transform, tag, take_while1},
-    character::complete::{char, digit1, none_of, one_of},
-    combinator::{map, map_res, value},
-    error::ErrorKind,
+    branch::alt,
+    bytes::complete::{escaped, take_while1},
+    character::complete::{anychar, char, digit1, none_of, one_of, satisfy},
+    combinator::{map, map_res, not, recognize, value},
+    error::{context, ContextError, ErrorKind, FromExternalError, ParseError as _},
     multi::{many0, many1, separated_list1},
-    sequence::delimited,
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult
 };
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fmt::{self, Write},
+    ops::Range
+};
 
 #[derive(Debug, PartialEq)]
 pub struct Ast<'a>(pub Vec<Any<'a>>);
@@ -36,6 +45,8 @@ pub struct Ast<'a>(pub Vec<Any<'a>>);
 #[derive(Debug, PartialEq)]
 pub enum Any<'a> {
     TabStop(TabStop),
+    /// Mirror of a tab stop, transformed
+    TabStopTransform(TabStop, Regex<'a>, Vec<Format<'a>>, Options<'a>),
     Placeholder(usize, Vec<Any<'a>>),
     Choice(usize, Vec<String>),
     Variable(&'a str, V<'a>),
@@ -49,12 +60,12 @@ pub enum V<'a> {
     Transform(Regex<'a>, Vec<Format<'a>>, Options<'a>)
 }
 
-/// escaped / and \self
+/// escapes kept as they are
 #[derive(Debug, PartialEq)]
-pub struct Regex<'a>(Escaped<'a>);
+pub struct Regex<'a>(pub(crate) Escaped<'a>);
 /// valid options has no } ?
 #[derive(Debug, PartialEq)]
-pub struct Options<'a>(&'a str);
+pub struct Options<'a>(pub(crate) &'a str);
 #[derive(Debug, PartialEq, Clone)]
 pub enum Format<'a> {
     Matched(usize),
@@ -71,41 +82,230 @@ pub enum Format<'a> {
 pub type Escaped<'a> = &'a str;
 pub type TabStop = usize;
 
-pub fn parse(s: &str) -> Option<Ast<'_>> {
+/// Why a body could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset of the furthest point the parser reached
+    pub offset: usize,
+    /// 1-based
+    pub line: usize,
+    /// 1-based, in characters
+    pub column: usize,
+    /// Tokens or constructs that would have been accepted, like `` `}` `` or `a number`
+    pub expected: Vec<String>,
+    /// The line with the error
+    pub context: String
+}
+
+impl ParseError {
+    /// What was expected, without the position
+    pub fn message(&self) -> String {
+        match self.expected.split_last() {
+            None => "unexpected input".into(),
+            Some((last, [])) => format!("expected {}", last),
+            Some((last, init)) => format!("expected {} or {}", init.join(", "), last)
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line,
+            self.column,
+            self.message()
+        )?;
+        write!(
+            f,
+            "\n{}\n{:>width$}",
+            self.context,
+            "^",
+            width = self.column
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Keeps the furthest failure and what was expected there
+#[derive(Debug, PartialEq)]
+struct Error<'a> {
+    input: &'a str,
+    expected: Vec<String>
+}
+
+type Res<'a, O> = IResult<&'a str, O, Error<'a>>;
+
+impl<'a> nom::error::ParseError<&'a str> for Error<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        let expected = match kind {
+            ErrorKind::Digit | ErrorKind::MapRes => vec!["a number".into()],
+            _ => Vec::new()
+        };
+        Error { input, expected }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self { other }
+
+    fn from_char(input: &'a str, c: char) -> Self {
+        Error {
+            input,
+            expected: vec![format!("`{}`", c)]
+        }
+    }
+
+    fn or(mut self, other: Self) -> Self {
+        match self.input.len().cmp(&other.input.len()) {
+            Ordering::Less => self,
+            Ordering::Greater => other,
+            Ordering::Equal => {
+                for e in other.expected {
+                    if !self.expected.contains(&e) {
+                        self.expected.push(e);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for Error<'a> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _: E) -> Self {
+        Error::from_error_kind(input, kind)
+    }
+}
+
+impl<'a> ContextError<&'a str> for Error<'a> {
+    fn add_context(input: &'a str, ctx: &'static str, mut other: Self) -> Self {
+        if other.input.len() == input.len() && other.expected.is_empty() {
+            other.expected.push(ctx.into());
+        }
+        other
+    }
+}
+
+pub fn parse(s: &str) -> Option<Ast<'_>> { parse_with_diagnostics(s).ok() }
+
+pub fn parse_with_diagnostics(s: &str) -> Result<Ast<'_>, ParseError> {
     // NOTE: if the parser passed to many0 accepts empty inputs (like alpha0 or digit0), many0 will return an error, to prevent going into an infinite loop
-    let result = map(many0(any), Ast)(s);
-    match result {
-        Err(e) => {
-            eprintln!("{}", e);
-            None
+    let rest = match many0(any)(s) {
+        Ok(("", xs)) => return Ok(Ast(xs)),
+        Ok((rest, _)) => rest,
+        Err(_) => s
+    };
+    // many0 drops the error that stopped it
+    let error = match any(rest) {
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => e,
+        _ => Error::from_error_kind(rest, ErrorKind::Many0)
+    };
+    let offset = s.len() - error.input.len();
+    let line_start = s[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = s[offset..].find('\n').map_or(s.len(), |i| offset + i);
+    Err(ParseError {
+        offset,
+        line: s[..offset].matches('\n').count() + 1,
+        column: s[line_start..offset].chars().count() + 1,
+        expected: error.expected,
+        context: s[line_start..line_end].to_owned()
+    })
+}
+
+/// Takes what does not parse as text, one character at a time, so that a malformed
+/// body still shows up. Returns the byte ranges taken so.
+pub fn parse_recovering(s: &str) -> (Ast<'_>, Vec<Range<usize>>) {
+    let recovered = Recovered::default();
+    let (_, xs) =
+        many0(|s| item(s, false, Some(&recovered)))(s).expect("every input is text at worst");
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (left, len) in recovered.into_inner() {
+        let start = s.len() - left;
+        match ranges.last_mut() {
+            Some(r) if r.end == start => r.end += len,
+            _ => ranges.push(start..start + len)
         }
-        Ok((rest, _)) if !rest.is_empty() => None,
-        Ok((_, x)) => Some(x)
     }
+    (Ast(merge_texts(xs)), ranges)
 }
 
-fn any(s: &str) -> IResult<&str, Any<'_>> {
-    if s.is_empty() {
-        return Err(nom::Err::Error(nom::error::Error::new(s, ErrorKind::Eof)));
+fn merge_texts(xs: Vec<Any<'_>>) -> Vec<Any<'_>> {
+    let mut merged: Vec<Any<'_>> = Vec::with_capacity(xs.len());
+    for x in xs {
+        let x = match x {
+            Any::Placeholder(n, children) => Any::Placeholder(n, merge_texts(children)),
+            Any::Variable(name, V::Any(children)) => {
+                Any::Variable(name, V::Any(merge_texts(children)))
+            }
+            x => x
+        };
+        match (merged.last_mut(), x) {
+            (Some(Any::Text(a)), Any::Text(b)) => a.push_str(&b),
+            (_, x) => merged.push(x)
+        }
+    }
+    merged
+}
+
+/// nom's tag telling what was expected
+fn tag<'a>(t: &'static str) -> impl Fn(&'a str) -> Res<'a, &'a str> {
+    move |s| {
+        nom::bytes::complete::tag(t)(s).map_err(|e| {
+            e.map(|e: Error<'a>| Error {
+                expected: vec![format!("`{}`", t)],
+                ..e
+            })
+        })
     }
-    alt((tab_stop_or_var_name, choice, placeholder, variable, text))(s)
 }
 
-fn any_inner_braces(s: &str) -> IResult<&str, Any<'_>> {
+fn any(s: &str) -> Res<'_, Any<'_>> { item(s, false, None) }
+
+fn any_inner_braces(s: &str) -> Res<'_, Any<'_>> { item(s, true, None) }
+
+/// Where [`parse_recovering`] took a character as text: the length of the input
+/// left and of the character
+type Recovered = RefCell<Vec<(usize, usize)>>;
+
+fn item<'a>(s: &'a str, in_braces: bool, recovered: Option<&Recovered>) -> Res<'a, Any<'a>> {
     if s.is_empty() {
-        return Err(nom::Err::Error(nom::error::Error::new(s, ErrorKind::Eof)));
+        return Err(nom::Err::Error(Error::from_error_kind(s, ErrorKind::Eof)));
     }
-    alt((
+    let result = alt((
         tab_stop_or_var_name,
         choice,
-        placeholder,
-        variable,
-        text_inner_braces
-    ))(s)
+        tab_stop_transform,
+        |s| undo_on_error(recovered, |s| placeholder(s, recovered), s),
+        |s| undo_on_error(recovered, |s| variable(s, recovered), s),
+        if in_braces { text_inner_braces } else { text }
+    ))(s);
+    match (result, recovered) {
+        (Err(nom::Err::Error(_)), Some(recovered)) if !(in_braces && s.starts_with('}')) => {
+            let c = s.chars().next().expect("not empty");
+            recovered.borrow_mut().push((s.len(), c.len_utf8()));
+            Ok((&s[c.len_utf8()..], Any::Text(c.to_string())))
+        }
+        (result, _) => result
+    }
+}
+
+/// Forgets what a failed attempt recovered
+fn undo_on_error<'a, O>(
+    recovered: Option<&Recovered>,
+    f: impl FnOnce(&'a str) -> Res<'a, O>,
+    s: &'a str
+) -> Res<'a, O> {
+    let mark = recovered.map(|r| r.borrow().len());
+    let result = f(s);
+    if let (Err(_), Some(r), Some(mark)) = (&result, recovered, mark) {
+        r.borrow_mut().truncate(mark);
+    }
+    result
 }
 
 /// $0 || ${0} || $var || ${var}
-fn tab_stop_or_var_name(s: &str) -> IResult<&str, Any<'_>> {
+fn tab_stop_or_var_name(s: &str) -> Res<'_, Any<'_>> {
     alt((
         map(number, Any::TabStop),
         map(var, |s| Any::Variable(s, V::None))
@@ -113,10 +313,10 @@ fn tab_stop_or_var_name(s: &str) -> IResult<&str, Any<'_>> {
 }
 
 /// $0 || ${0}
-fn number(s: &str) -> IResult<&str, usize> {
+fn number(s: &str) -> Res<'_, usize> {
     map_res(
         alt((
-            map(permutation((char('$'), digit1)), |(_, b): (char, &str)| b),
+            map(tuple((char('$'), digit1)), |(_, b): (char, &str)| b),
             delimited(tag("${"), digit1, char('}'))
         )),
         |s| s.parse::<usize>()
@@ -124,28 +324,31 @@ fn number(s: &str) -> IResult<&str, usize> {
 }
 
 /// $var || ${var}
-fn var(s: &str) -> IResult<&str, &str> {
+fn var(s: &str) -> Res<'_, &str> {
     alt((
-        map(permutation((char('$'), var_name)), |(_, s): (char, &str)| s),
+        map(tuple((char('$'), var_name)), |(_, s): (char, &str)| s),
         map(
-            permutation((tag("${"), var_name, char('}'))),
+            tuple((tag("${"), var_name, char('}'))),
             |(_, s, _): (&str, &str, char)| s
         )
     ))(s)
 }
 
-fn var_name(s: &str) -> IResult<&str, &str> {
-    let alphanum = take_while1(|c: char| c == '_' || c.is_ascii_alphanumeric())(s)?;
-    // for first char
-    let _ = take_while1(|c: char| c == '_' || c.is_ascii_alphabetic())(alphanum.1)?;
-    Ok(alphanum)
+fn var_name(s: &str) -> Res<'_, &str> {
+    fn name(s: &str) -> Res<'_, &str> {
+        if s.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(nom::Err::Error(Error::from_error_kind(s, ErrorKind::Alpha)));
+        }
+        take_while1(|c: char| c == '_' || c.is_ascii_alphanumeric())(s)
+    }
+    context("a variable name", name)(s)
 }
 
 /// ${0|text(,text)*|}
-fn choice(s: &str) -> IResult<&str, Any<'_>> {
+fn choice(s: &str) -> Res<'_, Any<'_>> {
     // choice      ::= '${' int '|' text (',' text)* '|}'
     map(
-        permutation((
+        tuple((
             tag("${"),
             digit1,
             delimited(char('|'), choice_elements, char('|')),
@@ -158,51 +361,72 @@ fn choice(s: &str) -> IResult<&str, Any<'_>> {
     )(s)
 }
 
-fn choice_elements(s: &str) -> IResult<&str, Vec<String>> {
+fn choice_elements(s: &str) -> Res<'_, Vec<String>> {
     separated_list1(
         char(','),
-        escaped_transform(
-            none_of(r#"\|,"#),
-            '\\',
-            alt((
-                value('\\', char('\\')),
-                value('|', char('|')),
-                value(',', char(','))
-            ))
+        map(
+            many1(alt((
+                preceded(char('\\'), one_of(r#"\|,"#)),
+                char('\\'),
+                none_of(r#"\|,"#)
+            ))),
+            |cs| cs.into_iter().collect()
         )
     )(s)
 }
 
+/// ${0/regex/format/options}
+fn tab_stop_transform(s: &str) -> Res<'_, Any<'_>> {
+    map(
+        tuple((
+            tag("${"),
+            map_res(digit1, |n: &str| n.parse::<usize>()),
+            transform
+        )),
+        |(_, n, (r, f, o))| Any::TabStopTransform(n, r, f, o)
+    )(s)
+}
+
 /// ${0:ast}
-fn placeholder(s: &str) -> IResult<&str, Any<'_>> {
-    let (rest, number) = map(permutation((tag("${"), digit1)), |(_, n): (&str, &str)| {
+fn placeholder<'a>(s: &'a str, recovered: Option<&Recovered>) -> Res<'a, Any<'a>> {
+    let (rest, number) = map(tuple((tag("${"), digit1)), |(_, n): (&str, &str)| {
         n.parse::<usize>().unwrap()
     })(s)?;
     map(
-        permutation((char(':'), many1(any_inner_braces), char('}'))),
-        move |(_, children, _): (char, Vec<Any<'_>>, char)| Any::Placeholder(number, children)
+        preceded(char(':'), |s| children(s, recovered)),
+        move |children| Any::Placeholder(number, children)
     )(rest)
 }
 
+/// any* '}'; if '}' is missing, the error tells why the last child failed as well
+fn children<'a>(s: &'a str, recovered: Option<&Recovered>) -> Res<'a, Vec<Any<'a>>> {
+    let (rest, xs) = many0(|s| item(s, true, recovered))(s)?;
+    match char::<_, Error<'_>>('}')(rest) {
+        Ok((rest, _)) => Ok((rest, xs)),
+        Err(nom::Err::Error(e)) => match any_inner_braces(rest) {
+            Err(nom::Err::Error(stopped)) => Err(nom::Err::Error(e.or(stopped))),
+            _ => Err(nom::Err::Error(e))
+        },
+        Err(e) => Err(e)
+    }
+}
+
 ///  ${' var ':' any '}' || '${' var '/' regex '/' (format | text)+ '/' options '}'
-fn variable(s: &str) -> IResult<&str, Any<'_>> {
-    let (rest, name) = map(
-        permutation((tag("${"), var_name)),
-        |(_, name): (&str, &str)| name
-    )(s)?;
+fn variable<'a>(s: &'a str, recovered: Option<&Recovered>) -> Res<'a, Any<'a>> {
+    let (rest, name) = map(tuple((tag("${"), var_name)), |(_, name): (&str, &str)| name)(s)?;
     let a = map(
-        permutation((char(':'), many1(any_inner_braces), char('}'))),
-        move |(_, children, _): (char, Vec<Any<'_>>, char)| {
-            Any::Variable(<&str>::clone(&name), V::Any(children))
-        }
+        preceded(char(':'), |s| children(s, recovered)),
+        move |children| Any::Variable(<&str>::clone(&name), V::Any(children))
     );
-    let t = map(transform, move |v| Any::Variable(name, v));
+    let t = map(transform, move |(r, f, o)| {
+        Any::Variable(name, V::Transform(r, f, o))
+    });
     alt((a, t))(rest)
 }
 
-fn transform(s: &str) -> IResult<&str, V> {
+fn transform(s: &str) -> Res<'_, (Regex<'_>, Vec<Format<'_>>, Options<'_>)> {
     map(
-        permutation((
+        tuple((
             char('/'),
             regex,
             char('/'),
@@ -211,15 +435,18 @@ fn transform(s: &str) -> IResult<&str, V> {
             options,
             char('}')
         )),
-        |(_, r, _, f, _, o, _)| V::Transform(r, f, o)
+        |(_, r, _, f, _, o, _)| (r, f, o)
     )(s)
 }
 
-fn regex(s: &str) -> IResult<&str, Regex<'_>> {
-    map(escaped(none_of("\\/"), '\\', one_of(r#"/\"#)), Regex)(s)
+fn regex(s: &str) -> Res<'_, Regex<'_>> {
+    if s.starts_with('/') {
+        return Ok((s, Regex("")));
+    }
+    map(escaped(none_of("\\/"), '\\', anychar), Regex)(s)
 }
 
-fn options(s: &str) -> IResult<&str, Options<'_>> {
+fn options(s: &str) -> Res<'_, Options<'_>> {
     match s.chars().next() {
         None => return Ok(("", Options(""))),
         Some('}') => return Ok((s, Options(""))),
@@ -228,7 +455,7 @@ fn options(s: &str) -> IResult<&str, Options<'_>> {
     map(take_while1(|c| c != '}'), Options)(s)
 }
 
-fn formats(s: &str) -> IResult<&str, Vec<Format<'_>>> {
+fn formats(s: &str) -> Res<'_, Vec<Format<'_>>> {
     // NOTE: if the parser passed to many0 accepts empty inputs (like alpha0 or digit0), many0 will return an error, to prevent going into an infinite loop
     many0(format)(s)
 }
@@ -238,77 +465,278 @@ fn formats(s: &str) -> IResult<&str, Vec<Format<'_>>> {
 ///                | '${' int ':+' if '}'
 ///                | '${' int ':?' if ':' else '}'
 ///                | '${' int ':-' else '}' | '${' int ':' else '}'
-fn format(s: &str) -> IResult<&str, Format<'_>> {
-    if let Ok(t) = map(number, Format::Matched)(s) {
-        return Ok(t);
-    }
-    fn matched_with_transform(s: &str) -> IResult<&str, Format<'_>> {
-        let (s, n) = map(
-            permutation((tag("${"), digit1, char(':'))),
-            |(_, n, _): (&str, &str, char)| n.parse::<usize>().unwrap()
-        )(s)?;
-        let case = alt((
-            value(Format::Upcase(n), tag("/upcase")),
-            value(Format::Downcase(n), tag("/downcase")),
-            value(Format::Capitalize(n), tag("/capitalize"))
-        ));
-        let i = map(
-            permutation((char('+'), take_while1(|c| c != '}'))),
-            move |(_, i): (_, &str)| Format::If(n, i)
-        );
-        let e = map(
-            permutation((char('-'), take_while1(|c| c != '}'))),
-            move |(_, i): (_, &str)| Format::Else(n, i)
-        );
-        let ie = map(
-            permutation((
-                char('?'),
-                take_while1(|c| c != ':'),
-                char(':'),
-                take_while1(|c| c != '}')
-            )),
-            move |(_, i, _, e): (_, &str, _, &str)| Format::IfElse(n, i, e)
-        );
-        let e2 = map(take_while1(|c| c != '}'), move |e| Format::Else(n, e));
-        map(
-            permutation((alt((case, i, e, ie, e2)), char('}'))),
-            |(f, _)| f
-        )(s)
-    }
-    let t = map(take_while1(|c| c != '/'), Format::Text);
-    alt((matched_with_transform, t))(s)
+fn format(s: &str) -> Res<'_, Format<'_>> {
+    alt((
+        map(number, Format::Matched),
+        matched_with_transform,
+        map(recognize(many1(format_char)), Format::Text)
+    ))(s)
 }
 
-fn text(s: &str) -> IResult<&str, Any<'_>> {
-    map(
-        escaped_transform(
-            none_of(r#"\$"#),
-            '\\',
-            alt((value('\\', char('\\')), value('$', char('$'))))
-        ),
-        Any::Text
-    )(s)
+fn matched_with_transform(s: &str) -> Res<'_, Format<'_>> {
+    let (s, n) = map(
+        tuple((tag("${"), digit1, char(':'))),
+        |(_, n, _): (&str, &str, char)| n.parse::<usize>().unwrap()
+    )(s)?;
+    let case = alt((
+        value(Format::Upcase(n), tag("/upcase")),
+        value(Format::Downcase(n), tag("/downcase")),
+        value(Format::Capitalize(n), tag("/capitalize"))
+    ));
+    let i = map(preceded(char('+'), until("}")), move |i| Format::If(n, i));
+    let e = map(preceded(char('-'), until("}")), move |e| Format::Else(n, e));
+    let ie = map(
+        tuple((char('?'), until(":"), char(':'), until("}"))),
+        move |(_, i, _, e)| Format::IfElse(n, i, e)
+    );
+    let e2 = map(until("}"), move |e| Format::Else(n, e));
+    terminated(alt((case, i, e, ie, e2)), char('}'))(s)
 }
 
-fn text_inner_braces(s: &str) -> IResult<&str, Any<'_>> {
+/// A character of the text between formats; a `$` starting no format is literal
+fn format_char(s: &str) -> Res<'_, &str> {
+    alt((
+        recognize(pair(char('\\'), anychar)),
+        recognize(none_of("\\/$")),
+        recognize(preceded(
+            not(alt((value((), number), value((), matched_with_transform)))),
+            char('$')
+        ))
+    ))(s)
+}
+
+/// Up to one of `stop`, keeping escapes as they are
+fn until<'a>(stop: &'static str) -> impl FnMut(&'a str) -> Res<'a, Escaped<'a>> {
+    recognize(many1(alt((
+        recognize(pair(char('\\'), anychar)),
+        recognize(satisfy(move |c| c != '\\' && !stop.contains(c)))
+    ))))
+}
+
+fn text(s: &str) -> Res<'_, Any<'_>> { text_until(s, "\\$") }
+
+fn text_inner_braces(s: &str) -> Res<'_, Any<'_>> { text_until(s, "\\$}") }
+
+fn text_until<'a>(s: &'a str, stop: &'static str) -> Res<'a, Any<'a>> {
+    if s.is_empty() {
+        return Ok((s, Any::Text(String::new())));
+    }
     map(
-        escaped_transform(
-            none_of(r#"\$}"#),
-            '\\',
-            alt((
-                value('\\', char('\\')),
-                value('$', char('$')),
-                value('}', char('}'))
-            ))
-        ),
-        Any::Text
+        many1(alt((
+            preceded(char('\\'), one_of("\\$}")),
+            char('\\'),
+            terminated(
+                char('$'),
+                not(satisfy(|c| {
+                    c == '{' || c == '_' || c.is_ascii_alphanumeric()
+                }))
+            ),
+            none_of(stop)
+        ))),
+        |cs| Any::Text(cs.into_iter().collect())
     )(s)
 }
 
-// TODO: test with https://github.com/microsoft/vscode/blob/main/src/vs/editor/contrib/snippet/test/snippetParser.test.ts
+/// What a transform makes of `value`, like VS Code's `Transform.resolve`. `None` if the
+/// regex is beyond what the regex crate supports, such as lookarounds.
+pub fn resolve_transform(
+    Regex(r): &Regex<'_>,
+    formats: &[Format<'_>],
+    Options(o): &Options<'_>,
+    value: &str
+) -> Option<String> {
+    let re = regex::RegexBuilder::new(&r.replace("\\/", "/"))
+        .case_insensitive(o.contains('i'))
+        .multi_line(o.contains('m'))
+        .dot_matches_new_line(o.contains('s'))
+        .build()
+        .ok()?;
+    let limit = if o.contains('g') { 0 } else { 1 };
+    let replaced = re.replacen(value, limit, |caps: &regex::Captures<'_>| {
+        let group = |n: &usize| caps.get(*n).map_or("", |m| m.as_str());
+        let mut out = String::new();
+        for x in formats {
+            match x {
+                Format::Matched(n) => out.push_str(group(n)),
+                Format::Upcase(n) => out.push_str(&group(n).to_uppercase()),
+                Format::Downcase(n) => out.push_str(&group(n).to_lowercase()),
+                Format::Capitalize(n) => {
+                    let mut cs = group(n).chars();
+                    out.extend(cs.next().into_iter().flat_map(char::to_uppercase));
+                    out.push_str(cs.as_str());
+                }
+                Format::If(n, i) if !group(n).is_empty() => out.push_str(&unescape(i)),
+                Format::IfElse(n, i, _) if !group(n).is_empty() => out.push_str(&unescape(i)),
+                Format::Else(n, _) if !group(n).is_empty() => out.push_str(group(n)),
+                Format::IfElse(_, _, e) | Format::Else(_, e) => out.push_str(&unescape(e)),
+                Format::If(..) => (),
+                Format::Text(t) => out.push_str(&unescape(t))
+            }
+        }
+        out
+    });
+    Some(replaced.into_owned())
+}
+
+/// [`resolve_transform`] of a transform kept as text, like a [`crate::NodeTransform`]
+pub(crate) fn resolve_transform_str(
+    regex: &str,
+    format: &str,
+    options: &str,
+    value: &str
+) -> Option<String> {
+    let formats = parse_formats(format)?;
+    resolve_transform(&Regex(regex), &formats, &Options(options), value)
+}
+
+/// The format part of a transform kept as text; `None` unless all of it parses
+pub(crate) fn parse_formats(s: &str) -> Option<Vec<Format<'_>>> {
+    match formats(s) {
+        Ok(("", formats)) => Some(formats),
+        _ => None
+    }
+}
+
+/// `\$`, `\}`, `\\` and `\/` in formats; other backslashes are literal
+pub(crate) fn unescape(s: Escaped<'_>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut cs = s.chars().peekable();
+    while let Some(c) = cs.next() {
+        match (c, cs.peek()) {
+            ('\\', Some(&e)) if "$}\\/:".contains(e) => {
+                out.push(e);
+                cs.next();
+            }
+            _ => out.push(c)
+        }
+    }
+    out
+}
+
+impl fmt::Display for Ast<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write_anys(f, &self.0, false) }
+}
+
+impl fmt::Display for Any<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_anys(f, std::slice::from_ref(self), false)
+    }
+}
+
+/// What follows the variable name
+impl fmt::Display for V<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            V::None => Ok(()),
+            V::Any(children) => {
+                f.write_char(':')?;
+                write_anys(f, children, true)
+            }
+            V::Transform(regex, formats, options) => write_transform(f, regex, formats, options)
+        }
+    }
+}
+
+fn write_transform(
+    f: &mut fmt::Formatter<'_>,
+    Regex(r): &Regex<'_>,
+    formats: &[Format<'_>],
+    Options(o): &Options<'_>
+) -> fmt::Result {
+    write!(f, "/{}/{}/{}", r, Formats(formats), o)
+}
+
+/// The format part of a transform as written in a snippet
+pub struct Formats<'a, 'b>(pub &'b [Format<'a>]);
+
+impl fmt::Display for Formats<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let formats = self.0;
+        for (i, x) in formats.iter().enumerate() {
+            match (x, formats.get(i + 1)) {
+                (Format::Matched(n), Some(Format::Text(t)))
+                    if t.starts_with(|c: char| c.is_ascii_digit()) =>
+                {
+                    write!(f, "${{{}}}", n)?
+                }
+                _ => write!(f, "{}", x)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Format<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Matched(n) => write!(f, "${}", n),
+            Format::Upcase(n) => write!(f, "${{{}:/upcase}}", n),
+            Format::Downcase(n) => write!(f, "${{{}:/downcase}}", n),
+            Format::Capitalize(n) => write!(f, "${{{}:/capitalize}}", n),
+            Format::If(n, i) => write!(f, "${{{}:+{}}}", n, i),
+            Format::IfElse(n, i, e) => write!(f, "${{{}:?{}:{}}}", n, i, e),
+            Format::Else(n, e) => write!(f, "${{{}:-{}}}", n, e),
+            Format::Text(t) => f.write_str(t)
+        }
+    }
+}
+
+/// `$1` and `$name` need braces when a word continues right after them.
+fn write_anys(f: &mut fmt::Formatter<'_>, xs: &[Any<'_>], in_braces: bool) -> fmt::Result {
+    for (i, x) in xs.iter().enumerate() {
+        let glued = |word: fn(char) -> bool| match xs.get(i + 1) {
+            Some(Any::Text(t)) => t.starts_with(word),
+            _ => false
+        };
+        match x {
+            Any::TabStop(n) if glued(|c| c.is_ascii_digit()) => write!(f, "${{{}}}", n)?,
+            Any::TabStop(n) => write!(f, "${}", n)?,
+            Any::TabStopTransform(n, regex, formats, options) => {
+                write!(f, "${{{}", n)?;
+                write_transform(f, regex, formats, options)?;
+                f.write_char('}')?
+            }
+            Any::Placeholder(n, children) => {
+                write!(f, "${{{}:", n)?;
+                write_anys(f, children, true)?;
+                f.write_char('}')?
+            }
+            Any::Choice(n, xs) => {
+                write!(f, "${{{}|", n)?;
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_escaped(f, x, &['\\', '|', ','])?;
+                }
+                f.write_str("|}")?
+            }
+            Any::Variable(name, V::None) if glued(|c| c == '_' || c.is_ascii_alphanumeric()) => {
+                write!(f, "${{{}}}", name)?
+            }
+            Any::Variable(name, V::None) => write!(f, "${}", name)?,
+            Any::Variable(name, v) => write!(f, "${{{}{}}}", name, v)?,
+            Any::Text(t) if in_braces => write_escaped(f, t, &['\\', '$', '}'])?,
+            Any::Text(t) => write_escaped(f, t, &['\\', '$'])?
+        }
+    }
+    Ok(())
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, s: &str, special: &[char]) -> fmt::Result {
+    for c in s.chars() {
+        if special.contains(&c) {
+            f.write_char('\\')?;
+        }
+        f.write_char(c)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn can_number() {
@@ -331,7 +759,7 @@ mod tests {
 
     #[test]
     fn can_choice() {
-        let e = |r: IResult<&str, Any<'_>>| match r.unwrap().1 {
+        let e = |r: Res<'_, Any<'_>>| match r.unwrap().1 {
             Any::Choice(_, xs) => xs,
             _ => unreachable!()
         };
@@ -344,14 +772,14 @@ mod tests {
     #[test]
     fn can_placeholder() {
         assert_eq!(
-            placeholder("${30:${3:${2}}}").unwrap(),
+            placeholder("${30:${3:${2}}}", None).unwrap(),
             (
                 "",
                 Any::Placeholder(30, vec![Any::Placeholder(3, vec![Any::TabStop(2)])])
             )
         );
         assert_eq!(
-            placeholder("${1:true}").unwrap(),
+            placeholder("${1:true}", None).unwrap(),
             ("", Any::Placeholder(1, vec![Any::Text("true".into())]))
         );
     }
@@ -359,7 +787,7 @@ mod tests {
     #[test]
     fn can_variable() {
         assert_eq!(
-            variable("${as:${wer:${2}}}").unwrap(),
+            variable("${as:${wer:${2}}}", None).unwrap(),
             (
                 "",
                 Any::Variable(
@@ -369,7 +797,7 @@ mod tests {
             )
         );
         assert_eq!(
-            variable("${_3/.*/a/g}"),
+            variable("${_3/.*/a/g}", None),
             Ok((
                 "",
                 Any::Variable(
@@ -439,7 +867,7 @@ mod tests {
                 Any::Text("\nend".into())
             ]))
         );
-        // XXX: Should be parsed as text without the need for escaping
+        // As in VS Code, the first `}` closes the placeholder
         let a = parse("${1:className} = {}\n\n$1.${2:new} = function($3)\n\tlocal ${4:varName} = ${5:{}}\n\n\t${6: --code}\n\n\treturn $4\nend");
         let b = Some(Ast(vec![
             Any::Placeholder(1, vec![Any::Text("className".into())]),
@@ -471,6 +899,373 @@ mod tests {
             ]))
         );
         // escaping $?
-        dbg!(parse("case \"$${0:VAR}\" in\n\t${1:1}) echo 1\n\t;;\n\t${2:2|3}) echo 2 or 3\n\t;;\n\t*) echo default\n\t;;\nesac\n"));
+        assert_eq!(
+            parse("case \"$${0:VAR}\" in\n\t${1:1}) echo 1\n\t;;\n\t${2:2|3}) echo 2 or 3\n\t;;\n\t*) echo default\n\t;;\nesac\n"),
+            Some(Ast(vec![
+                Any::Text("case \"$".into()),
+                Any::Placeholder(0, vec![Any::Text("VAR".into())]),
+                Any::Text("\" in\n\t".into()),
+                Any::Placeholder(1, vec![Any::Text("1".into())]),
+                Any::Text(") echo 1\n\t;;\n\t".into()),
+                Any::Placeholder(2, vec![Any::Text("2|3".into())]),
+                Any::Text(") echo 2 or 3\n\t;;\n\t*) echo default\n\t;;\nesac\n".into())
+            ]))
+        );
+    }
+
+    #[test]
+    fn can_parse_with_diagnostics() {
+        let e = parse_with_diagnostics("if a\n\t${1:x ${2|a,b}").unwrap_err();
+        assert_eq!(
+            (e.offset, e.line, e.column, e.context.as_str()),
+            (20, 2, 16, "\t${1:x ${2|a,b}")
+        );
+        assert_eq!(e.expected, vec!["`|`"]);
+        assert_eq!(
+            e.to_string(),
+            "line 2, column 16: expected `|`\n\t${1:x ${2|a,b}\n               ^"
+        );
+        let e = parse_with_diagnostics("a ${").unwrap_err();
+        assert_eq!(
+            (e.column, e.message()),
+            (5, "expected a number or a variable name".into())
+        );
+        assert_eq!(
+            parse_with_diagnostics("${a:\\x}"),
+            Ok(Ast(vec![Any::Variable(
+                "a",
+                V::Any(vec![Any::Text("\\x".into())])
+            )]))
+        );
+    }
+
+    #[test]
+    fn can_parse_recovering() {
+        let (ast, recovered) = parse_recovering("case \"$${0:VAR}\" in");
+        assert_eq!(
+            ast,
+            Ast(vec![
+                Any::Text("case \"$".into()),
+                Any::Placeholder(0, vec![Any::Text("VAR".into())]),
+                Any::Text("\" in".into())
+            ])
+        );
+        assert!(recovered.is_empty());
+        let (ast, recovered) = parse_recovering("${1:a ${ b} $2 ${3:c");
+        assert_eq!(
+            ast,
+            Ast(vec![
+                Any::Placeholder(1, vec![Any::Text("a ${ b".into())]),
+                Any::Text(" ".into()),
+                Any::TabStop(2),
+                Any::Text(" ${3:c".into())
+            ])
+        );
+        assert_eq!(recovered, vec![6..7, 15..16]);
+        let s = "if ${1:true} then\n\t$0\nend";
+        assert_eq!(parse_recovering(s), (parse(s).unwrap(), vec![]));
+    }
+
+    /// What VS Code inserts for a marker whose variables resolve to nothing
+    fn inserted(xs: &[Any<'_>]) -> String {
+        xs.iter()
+            .map(|x| match x {
+                Any::Placeholder(_, children) | Any::Variable(_, V::Any(children)) => {
+                    inserted(children)
+                }
+                Any::Choice(_, xs) => xs[0].clone(),
+                Any::Text(t) => t.clone(),
+                Any::TabStop(_) | Any::TabStopTransform(..) | Any::Variable(..) => String::new()
+            })
+            .collect()
+    }
+
+    /// `assertTextAndMarker` of VS Code's snippetParser.test.ts. VS Code's parser never
+    /// fails, so it is compared with [`parse_recovering`].
+    fn assert_text_and_marker(s: &str, text: &str, markers: &[&str]) {
+        let (Ast(xs), _) = parse_recovering(s);
+        let kinds: Vec<_> = xs
+            .iter()
+            .map(|x| match x {
+                Any::TabStop(_)
+                | Any::TabStopTransform(..)
+                | Any::Placeholder(..)
+                | Any::Choice(..) => "Placeholder",
+                Any::Variable(..) => "Variable",
+                Any::Text(_) => "Text"
+            })
+            .collect();
+        assert_eq!(
+            (inserted(&xs).as_str(), kinds.as_slice()),
+            (text, markers),
+            "{}",
+            s
+        );
+    }
+
+    /// Ported from https://github.com/microsoft/vscode/blob/main/src/vs/editor/contrib/snippet/test/browser/snippetParser.test.ts
+    #[test]
+    fn conforms_to_vscode() {
+        let text = |s: &str, t: &str| assert_text_and_marker(s, t, &["Text"]);
+        text("$", "$");
+        text("\\\\$", "\\$");
+        text("{", "{");
+        text("\\}", "}");
+        text("\\abc", "\\abc");
+        text("\\{", "\\{");
+        text("I need \\\\\\$", "I need \\$");
+        text("\\", "\\");
+        text("\\{{", "\\{{");
+        text("{{", "{{");
+        text("{{dd", "{{dd");
+        text("}}", "}}");
+        text("ff}}", "ff}}");
+        text("far{{}}boo", "far{{}}boo");
+        text("far{{123}}boo", "far{{123}}boo");
+        text("far\\{{123}}boo", "far\\{{123}}boo");
+        text("far{{id:bern {{basel}}}}boo", "far{{id:bern {{basel}}}}boo");
+        text("far`123`boo", "far`123`boo");
+        text("far\\`123\\`boo", "far\\`123\\`boo");
+        text("\\$far-boo", "$far-boo");
+        assert_text_and_marker("foo${f:\\}}bar", "foo}bar", &["Text", "Variable", "Text"]);
+
+        // TM text
+        assert_text_and_marker("foo${1:bar}}", "foobar}", &["Text", "Placeholder", "Text"]);
+        assert_text_and_marker(
+            "foo${1:bar}${2:foo}}",
+            "foobarfoo}",
+            &["Text", "Placeholder", "Placeholder", "Text"]
+        );
+        assert_text_and_marker(
+            "foo${1:bar\\}${2:foo}}",
+            "foobar}foo",
+            &["Text", "Placeholder"]
+        );
+
+        // variables and tab stops
+        assert_text_and_marker("$far-boo", "-boo", &["Variable", "Text"]);
+        assert_text_and_marker("far$farboo", "far", &["Text", "Variable"]);
+        assert_text_and_marker("far${farboo}", "far", &["Text", "Variable"]);
+        assert_text_and_marker("$123", "", &["Placeholder"]);
+        assert_text_and_marker("$farboo", "", &["Variable"]);
+        assert_text_and_marker("$far12boo", "", &["Variable"]);
+        assert_text_and_marker("000_${far}_000", "000__000", &["Text", "Variable", "Text"]);
+        assert_text_and_marker(
+            "FFF_${TM_SELECTED_TEXT}_FFF$0",
+            "FFF__FFF",
+            &["Text", "Variable", "Text", "Placeholder"]
+        );
+
+        // placeholders with defaults
+        assert_text_and_marker("${name:value}", "value", &["Variable"]);
+        assert_text_and_marker("${1:value}", "value", &["Placeholder"]);
+        assert_text_and_marker("${1:bar${2:foo}bar}", "barfoobar", &["Placeholder"]);
+        assert_text_and_marker("${name:value", "${name:value", &["Text"]);
+        assert_text_and_marker(
+            "${1:bar${2:foobar}",
+            "${1:barfoobar",
+            &["Text", "Placeholder"]
+        );
+        assert_text_and_marker("${1:}", "", &["Placeholder"]);
+        assert_text_and_marker("${TM_SELECTED_TEXT:foo:bar}", "foo:bar", &["Variable"]);
+        assert_text_and_marker("${1:foo:bar}", "foo:bar", &["Placeholder"]);
+        assert_text_and_marker(
+            "FOO${1:/bin/bash}",
+            "FOO/bin/bash",
+            &["Text", "Placeholder"]
+        );
+        assert_text_and_marker(
+            "Foo \\\\${abc}bar",
+            "Foo \\bar",
+            &["Text", "Variable", "Text"]
+        );
+
+        // variable transforms
+        for s in [
+            "${foo///}",
+            "${foo/regex/format/gmi}",
+            "${foo/([A-Z][a-z])/format/}",
+            "${foo/m\\/atch/$1/i}",
+            "${foo/.*/${0:fooo}/i}",
+            "${foo/.*/${1}/i}",
+            "${foo/.*/$1/i}",
+            "${foo/.*/This-$1-encloses/i}",
+            "${foo/.*/complex${1:else}/i}",
+            "${foo/.*/complex${1:-else}/i}",
+            "${foo/.*/complex${1:+if}/i}",
+            "${foo/.*/complex${1:?if:else}/i}",
+            "${foo/.*/complex${1:/upcase}/i}",
+            "${TM_DIRECTORY/src\\//$1/}",
+            "${TM_SELECTED_TEXT/a/\\/$1/g}",
+            "${TM_SELECTED_TEXT/a/in\\/$1ner/g}",
+            "${TM_SELECTED_TEXT/a/end\\//g}",
+            "${TM_DIRECTORY/.*src[\\/](.*)/$1/}",
+            "${CURRENT_YEAR/(.*)/${1:+\\}}/}"
+        ] {
+            assert_text_and_marker(s, "", &["Variable"]);
+            assert!(parse(s).is_some(), "{}", s);
+        }
+        assert_text_and_marker(
+            "${foo/([A-Z][a-z]/format/",
+            "${foo/([A-Z][a-z]/format/",
+            &["Text"]
+        );
+        assert_text_and_marker("${foo///", "${foo///", &["Text"]);
+        assert_text_and_marker(
+            "${foo/regex/format/options",
+            "${foo/regex/format/options",
+            &["Text"]
+        );
+        assert_eq!(
+            parse("${foo/.*/This-$1-encloses/i}"),
+            Some(Ast(vec![Any::Variable(
+                "foo",
+                V::Transform(
+                    Regex(".*"),
+                    vec![
+                        Format::Text("This-"),
+                        Format::Matched(1),
+                        Format::Text("-encloses")
+                    ],
+                    Options("i")
+                )
+            )]))
+        );
+        assert_eq!(formats("${1:+\\}}"), Ok(("", vec![Format::If(1, "\\}")])));
+
+        // placeholder transforms
+        for s in [
+            "${1///}",
+            "${1/regex/format/gmi}",
+            "${1/(void$)|(.+)/${1:?-\treturn nil;}/}"
+        ] {
+            assert_text_and_marker(s, "", &["Placeholder"]);
+            assert!(parse(s).is_some(), "{}", s);
+        }
+        assert!(parse(
+            "class ${1:${TM_FILENAME/(?:\\A|_)([A-Za-z0-9]+)(?:\\.rb)?/(?2::\\u$1)/g}} < ${2:Application}Controller\n  $3\nend"
+        )
+        .is_some());
+        assert!(parse("${1:${foo:${1}}}").is_some());
+        assert!(parse("${1:${FOO:abc$1def}}").is_some());
+
+        // choices
+        assert_text_and_marker("${1|one,two,three|}", "one", &["Placeholder"]);
+        assert_text_and_marker("${1|one|}", "one", &["Placeholder"]);
+        assert_text_and_marker("${1|one1,two2|}", "one1", &["Placeholder"]);
+        assert_text_and_marker("${1|one1\\,two2|}", "one1,two2", &["Placeholder"]);
+        assert_text_and_marker("${1|one1\\|two2|}", "one1|two2", &["Placeholder"]);
+        assert_text_and_marker("${1|one1\\atwo2|}", "one1\\atwo2", &["Placeholder"]);
+        assert_text_and_marker("${1|one,two,three,|}", "${1|one,two,three,|}", &["Text"]);
+        assert_text_and_marker("${1|one,", "${1|one,", &["Text"]);
+        assert_text_and_marker("${1|aaa$aaa|}", "aaa$aaa", &["Placeholder"]);
+        assert_text_and_marker(
+            "console.log(${1|not\\, not, five, 5, 1   23|});",
+            "console.log(not, not);",
+            &["Text", "Placeholder", "Text"]
+        );
+        assert_eq!(
+            parse("${1|\\,,},$,\\|,\\\\|}"),
+            Some(Ast(vec![Any::Choice(
+                1,
+                vec![",".into(), "}".into(), "$".into(), "|".into(), "\\".into()]
+            )]))
+        );
+
+        // real world
+        let (Ast(xs), _) = parse_recovering("console.warn(${1: $TM_SELECTED_TEXT })");
+        assert_eq!(
+            xs,
+            vec![
+                Any::Text("console.warn(".into()),
+                Any::Placeholder(
+                    1,
+                    vec![
+                        Any::Text(" ".into()),
+                        Any::Variable("TM_SELECTED_TEXT", V::None),
+                        Any::Text(" ".into())
+                    ]
+                ),
+                Any::Text(")".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn can_resolve_transform() {
+        let resolve = |s: &str, value: &str| match parse(s).expect(s).0.pop() {
+            Some(Any::TabStopTransform(_, r, f, o)) => resolve_transform(&r, &f, &o, value),
+            _ => unreachable!()
+        };
+        assert_eq!(
+            resolve("${1/(.*)/get${1:/capitalize}/}", "name"),
+            Some("getName".into())
+        );
+        assert_eq!(
+            resolve("${1/^(.)|-(.)/${1:/upcase}${2:/upcase}/g}", "my-file-name"),
+            Some("MyFileName".into())
+        );
+        assert_eq!(resolve("${1/a/b/}", "aAa"), Some("bAa".into()));
+        assert_eq!(resolve("${1/a/b/gi}", "aAa"), Some("bbb".into()));
+        assert_eq!(resolve("${1/(x)?/${1:?y:n\\}}/}", ""), Some("n}".into()));
+        assert_eq!(
+            resolve("${1/(x)?/${1:-else}\\/$1/}", "x"),
+            Some("x/x".into())
+        );
+        assert_eq!(resolve("${1/(?<=a)b/c/}", "ab"), None);
+    }
+
+    #[test]
+    fn can_print() {
+        for s in [
+            "if ${1:true} then\n\t$0\nend",
+            "${1}0 $a ${a}b ${2|x\\,y,\\|,\\\\|} ${3:\\$\\}{}",
+            "${TM_FILENAME/(.*)\\/(a)/${2:/upcase}${1:+x}${1:?y:z}${2:-w}$1x${1}0/gi}",
+            "${x:${1:a}}} \\\\",
+            "${1:a} ${1/(.)(.*)/${1:/upcase}$2/g}"
+        ] {
+            assert_eq!(parse(s).expect(s).to_string(), s);
+        }
+    }
+
+    fn snippet_like() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            3 => Just("$"),
+            3 => Just("\\"),
+            3 => Just("{"),
+            3 => Just("}"),
+            3 => Just(":"),
+            3 => Just("|"),
+            3 => Just(","),
+            3 => Just("/"),
+            3 => Just("\\$"),
+            3 => Just("\\}"),
+            3 => Just("${1:"),
+            3 => Just("${2|a,"),
+            3 => Just("|}"),
+            3 => Just("${TM_FILENAME:"),
+            3 => Just("${a/(.)/"),
+            3 => Just("${1/(.)/"),
+            3 => Just("/g}"),
+            3 => Just("$1"),
+            3 => Just("$x"),
+            3 => Just("${1:/upcase}"),
+            3 => Just("${1:?a:b}"),
+            3 => Just("0"),
+            6 => Just("a"),
+            6 => Just(" "),
+            3 => Just("\n")
+        ];
+        prop::collection::vec(piece, 0..16).prop_map(|ps| ps.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn parse_print_roundtrips(s in snippet_like()) {
+            if let Some(ast) = parse(&s) {
+                let printed = ast.to_string();
+                prop_assert_eq!(parse(&printed), Some(ast), "printed {:?}", printed);
+            }
+        }
     }
 }
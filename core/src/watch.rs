@@ -0,0 +1,85 @@
+//! Notifications of changes in snippet directories.
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf}
+};
+
+pub struct Watcher {
+    inner: RecommendedWatcher,
+    dirs: HashSet<PathBuf>,
+    failed: HashSet<PathBuf>
+}
+
+impl Watcher {
+    /// `on_change` is called on another thread with the changed paths.
+    pub fn new<F>(on_change: F) -> notify::Result<Self>
+    where
+        F: Fn(Vec<PathBuf>) + Send + 'static
+    {
+        let inner =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+                Ok(ev) if is_change(&ev.kind) => on_change(ev.paths),
+                Ok(_) => (),
+                Err(e) => eprintln!("{}", e)
+            })?;
+        Ok(Watcher {
+            inner,
+            dirs: HashSet::new(),
+            failed: HashSet::new()
+        })
+    }
+
+    /// Starts watching `dir` unless it is watched already. A directory that cannot
+    /// be watched, for example because it does not exist yet, is tried again next
+    /// time and reported only once.
+    pub fn watch(&mut self, dir: &Path) -> notify::Result<()> {
+        if self.dirs.contains(dir) {
+            return Ok(());
+        }
+        match self.inner.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                self.failed.remove(dir);
+                self.dirs.insert(dir.to_owned());
+                Ok(())
+            }
+            Err(e) => {
+                if self.failed.insert(dir.to_owned()) {
+                    eprintln!("{}: {}", dir.display(), e);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+fn is_change(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) | EventKind::Any
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+    use std::{fs, sync::mpsc, time::Duration};
+
+    #[test]
+    fn notifies_changes() {
+        let tmp = TempDir::new("watch");
+        let dir = tmp.path();
+        let (tx, rx) = mpsc::channel();
+        let mut w = Watcher::new(move |paths| {
+            let _ = tx.send(paths);
+        })
+        .unwrap();
+        w.watch(dir).unwrap();
+        assert!(w.watch(&dir.join("missing")).is_err());
+        let file = dir.join("lua.json");
+        fs::write(&file, "{}").unwrap();
+        let paths = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(paths, vec![file]);
+    }
+}